@@ -0,0 +1,670 @@
+//! An append-only record of seals, unseal attempts, revocations and admin actions, so the sender of an
+//! envelope can see who accessed it and when, and an attacker can't quietly erase having done
+//! so. See [`FileAuditLog`].
+
+use crate::listener;
+use anyhow::{Context as _, Result, anyhow};
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{HeaderMap, header, request::Parts},
+};
+use base64::prelude::*;
+use ed25519_dalek::{Signature, Signer as _, SigningKey, Verifier as _, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    convert::Infallible,
+    env, fmt,
+    fs::{self, OpenOptions},
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+use zeroize::Zeroizing;
+
+/// Appends and reads back audit events. Calls block, so the server runs them on the blocking
+/// thread pool.
+pub trait AuditLog: Send + Sync {
+    fn append(&self, event: &AuditEvent) -> Result<()>;
+    /// The events for envelope `id`, oldest first.
+    fn for_envelope(&self, id: &str) -> Result<Vec<AuditEvent>>;
+    /// Signs the events appended so far, for logs that sign.
+    fn checkpoint(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// One request to seal, unseal or administer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub time: String,
+    #[serde(default)]
+    pub action: Action,
+    /// Unset when the envelope couldn't be opened, or was sealed before envelopes had ids.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub envelope: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    /// The signed-in caller. Always set for unseals.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(flatten)]
+    pub requester: Requester,
+    pub outcome: Outcome,
+}
+
+impl AuditEvent {
+    /// An event happening now, with only what every request has.
+    pub fn now(action: Action, requester: Requester, outcome: Outcome) -> AuditEvent {
+        AuditEvent {
+            time: chrono::Utc::now().to_rfc3339(),
+            action,
+            envelope: None,
+            kid: None,
+            email: None,
+            requester,
+            outcome,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Seal,
+    #[default]
+    Unseal,
+    /// A share of the master key was sent to `/api/admin/unseal`.
+    AddShare,
+    /// The sender asked to revoke the envelope.
+    Revoke,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Sealed,
+    Unsealed,
+    ShareAccepted,
+    /// The envelope couldn't be decrypted, e.g. it was tampered with or its KEK is gone, or the
+    /// request was otherwise refused.
+    Invalid,
+    NotRecipient,
+    Revoked,
+    /// The envelope had been unsealed its maximum number of times.
+    UsedUp,
+    /// The server failed while checking the envelope.
+    Error,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Outcome::Sealed => "sealed",
+            Outcome::Unsealed => "unsealed",
+            Outcome::ShareAccepted => "share_accepted",
+            Outcome::Invalid => "invalid",
+            Outcome::NotRecipient => "not_recipient",
+            Outcome::Revoked => "revoked",
+            Outcome::UsedUp => "used_up",
+            Outcome::Error => "error",
+        })
+    }
+}
+
+/// Where a request came from. `ip` is the peer's address, which is a proxy's when the server
+/// runs behind one; `forwarded_for` is the `X-Forwarded-For` header as sent, and is only as
+/// trustworthy as that proxy.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Requester {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forwarded_for: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Requester {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let header = |headers: &HeaderMap, name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(Into::into)
+        };
+        // Only there when served by `run_server`, not when the router is nested in another app.
+        let peer = parts.extensions.get::<ConnectInfo<listener::Addr>>();
+        Ok(Requester {
+            ip: peer.map(|ConnectInfo(addr)| addr.to_string()),
+            forwarded_for: header(&parts.headers, "x-forwarded-for"),
+            user_agent: header(&parts.headers, header::USER_AGENT.as_str()),
+        })
+    }
+}
+
+/// Reads `AUDIT_LOG`: `memory` or `file:<path>`, which needs [`key_from_env`]. Auditing is
+/// disabled when it's unset.
+pub fn from_env() -> Result<Option<Arc<dyn AuditLog>>> {
+    let Ok(spec) = env::var("AUDIT_LOG") else {
+        return Ok(None);
+    };
+    let log: Arc<dyn AuditLog> = match spec.split_once(':') {
+        None if spec == "memory" => Arc::new(MemoryAuditLog::default()),
+        Some(("file", path)) => {
+            let key = key_from_env()?;
+            tracing::info!(
+                public_key = encode_public_key(&key.verifying_key()),
+                "Signing audit log checkpoints"
+            );
+            Arc::new(FileAuditLog::open(path, key)?)
+        }
+        _ => return Err(anyhow!("AUDIT_LOG should be memory or file:<path>")),
+    };
+    tracing::info!("Auditing to {spec}");
+    Ok(Some(log))
+}
+
+/// Keeps events until the server exits. For development and tests.
+#[derive(Default)]
+pub struct MemoryAuditLog {
+    events: Mutex<Vec<AuditEvent>>,
+}
+
+impl AuditLog for MemoryAuditLog {
+    fn append(&self, event: &AuditEvent) -> Result<()> {
+        self.events
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .push(event.clone());
+        Ok(())
+    }
+
+    fn for_envelope(&self, id: &str) -> Result<Vec<AuditEvent>> {
+        Ok(self
+            .events
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .iter()
+            .filter(|event| event.envelope.as_deref() == Some(id))
+            .cloned()
+            .collect())
+    }
+}
+
+/// Appends events to a file as JSON lines, each holding the SHA-256 of the line before it, so no
+/// line can be changed or removed without breaking the chain. Every [`CHECKPOINT_EVERY`] events,
+/// and whenever [`AuditLog::checkpoint`] is called with events unsigned, a checkpoint line signs
+/// the chain so far with an Ed25519 key. See [`verify`], which only needs the public key.
+///
+/// The server holds the private key, so whoever takes over the server could still rewrite the
+/// log and sign it again. Only the heads it logged before then, kept somewhere off the server,
+/// show that it was rewritten.
+pub struct FileAuditLog {
+    path: PathBuf,
+    key: SigningKey,
+    /// Also keeps concurrent appends from interleaving.
+    chain: Mutex<Verified>,
+}
+
+/// How many events a [`FileAuditLog`] appends before signing a checkpoint.
+pub const CHECKPOINT_EVERY: u64 = 100;
+
+/// One line of a [`FileAuditLog`].
+#[derive(Debug, Serialize, Deserialize)]
+struct Link {
+    /// The hash of the line before, or empty for the first line.
+    prev: String,
+    #[serde(flatten)]
+    entry: Entry,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Entry {
+    Event(AuditEvent),
+    Checkpoint(Checkpoint),
+}
+
+/// Signs every line before it, by way of `prev`.
+#[derive(Debug, Serialize, Deserialize)]
+struct Checkpoint {
+    time: String,
+    /// The number of events before this checkpoint.
+    events: u64,
+    signature: String,
+}
+
+impl FileAuditLog {
+    /// Opens the log at `path`, creating it on the first append. Refuses a log that doesn't
+    /// [`verify`] with the public half of `key`, rather than extend a chain that has been
+    /// tampered with. A partial last line, left by a crash mid-append, is cut off first, as its
+    /// append never succeeded.
+    pub fn open(path: impl Into<PathBuf>, key: SigningKey) -> Result<FileAuditLog> {
+        let path = path.into();
+        repair_torn_line(&path)?;
+        let chain = verify(&path, &key.verifying_key(), None)
+            .with_context(|| format!("Audit log {} failed verification", path.display()))?;
+        Ok(FileAuditLog {
+            path,
+            key,
+            chain: Mutex::new(chain),
+        })
+    }
+
+    fn write(&self, chain: &mut Verified, entry: Entry) -> Result<()> {
+        let link = Link {
+            prev: chain.head.clone(),
+            entry,
+        };
+        let line = serde_json::to_string(&link)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open audit log {}", self.path.display()))?;
+        let len = file.metadata()?.len();
+        if let Err(err) = file
+            .write_all(format!("{line}\n").as_bytes())
+            .and_then(|_| file.sync_data())
+        {
+            // Don't leave part of a line for the next append to run on from.
+            let _ = file.set_len(len);
+            return Err(err)
+                .with_context(|| format!("Failed to append to audit log {}", self.path.display()));
+        }
+        chain.head = hash(&line);
+        match link.entry {
+            Entry::Event(_) => {
+                chain.events += 1;
+                chain.unsigned += 1;
+            }
+            Entry::Checkpoint(checkpoint) => {
+                chain.checkpoints += 1;
+                chain.unsigned = 0;
+                tracing::info!(
+                    head = chain.head,
+                    events = checkpoint.events,
+                    "Signed audit log checkpoint"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn sign(&self, chain: &mut Verified) -> Result<()> {
+        let time = chrono::Utc::now().to_rfc3339();
+        let signature = self
+            .key
+            .sign(&checkpoint_message(&chain.head, &time, chain.events));
+        let checkpoint = Checkpoint {
+            time,
+            events: chain.events,
+            signature: BASE64_URL_SAFE_NO_PAD.encode(signature.to_bytes()),
+        };
+        self.write(chain, Entry::Checkpoint(checkpoint))
+    }
+}
+
+impl AuditLog for FileAuditLog {
+    fn append(&self, event: &AuditEvent) -> Result<()> {
+        let mut chain = self.chain.lock().unwrap_or_else(|err| err.into_inner());
+        self.write(&mut chain, Entry::Event(event.clone()))?;
+        if chain.unsigned >= CHECKPOINT_EVERY {
+            self.sign(&mut chain)?;
+        }
+        Ok(())
+    }
+
+    fn for_envelope(&self, id: &str) -> Result<Vec<AuditEvent>> {
+        let mut events = Vec::new();
+        for (i, line) in read_lines(&self.path)?.iter().enumerate() {
+            let link: Link = serde_json::from_str(line)
+                .with_context(|| format!("Audit log line {} is malformed", i + 1))?;
+            let Entry::Event(event) = link.entry else {
+                continue;
+            };
+            if event.envelope.as_deref() == Some(id) {
+                events.push(event);
+            }
+        }
+        Ok(events)
+    }
+
+    fn checkpoint(&self) -> Result<()> {
+        let mut chain = self.chain.lock().unwrap_or_else(|err| err.into_inner());
+        if chain.unsigned == 0 {
+            return Ok(());
+        }
+        self.sign(&mut chain)
+    }
+}
+
+/// What [`verify`] found in a sound log.
+#[derive(Debug, Default, PartialEq)]
+pub struct Verified {
+    pub events: u64,
+    pub checkpoints: u64,
+    /// Events after the last checkpoint. These could have been removed without a trace.
+    pub unsigned: u64,
+    /// The hash of the last line, which the server logs with each checkpoint.
+    pub head: String,
+}
+
+/// Checks that every line of the log at `path` follows from the one before, and that every
+/// checkpoint is signed by the private half of `public_key`. A missing log is empty. With `head`,
+/// also checks that the log still has the line with that hash, e.g. one the server logged
+/// elsewhere, so it hasn't been rewritten or cut short since.
+pub fn verify(path: &Path, public_key: &VerifyingKey, head: Option<&str>) -> Result<Verified> {
+    let mut verified = Verified::default();
+    let mut found_head = false;
+    for (i, text) in read_lines(path)?.iter().enumerate() {
+        let line = i + 1;
+        let link: Link =
+            serde_json::from_str(text).with_context(|| format!("Line {line} is malformed"))?;
+        if link.prev != verified.head {
+            return Err(anyhow!(
+                "Line {line} doesn't follow from the line before it"
+            ));
+        }
+        match link.entry {
+            Entry::Event(_) => {
+                verified.events += 1;
+                verified.unsigned += 1;
+            }
+            Entry::Checkpoint(checkpoint) => {
+                if checkpoint.events != verified.events {
+                    return Err(anyhow!(
+                        "Checkpoint on line {line} counts {} events, not {}",
+                        checkpoint.events,
+                        verified.events
+                    ));
+                }
+                let message = checkpoint_message(&link.prev, &checkpoint.time, checkpoint.events);
+                let signature = BASE64_URL_SAFE_NO_PAD
+                    .decode(&checkpoint.signature)
+                    .ok()
+                    .and_then(|signature| Signature::from_slice(&signature).ok())
+                    .filter(|signature| public_key.verify(&message, signature).is_ok());
+                if signature.is_none() {
+                    return Err(anyhow!("Checkpoint on line {line} has a bad signature"));
+                }
+                verified.checkpoints += 1;
+                verified.unsigned = 0;
+            }
+        }
+        verified.head = hash(text);
+        found_head |= head == Some(verified.head.as_str());
+    }
+    if let Some(head) = head
+        && !found_head
+    {
+        return Err(anyhow!("No line has hash {head}"));
+    }
+    Ok(verified)
+}
+
+/// Cuts off the last line of the log at `path` if a crash left it partly written, or ends it if
+/// only its newline is missing. Every append ends with a newline, so nothing else leaves a log
+/// without one.
+fn repair_torn_line(path: &Path) -> Result<()> {
+    let log = match fs::read(path) {
+        Ok(log) => log,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to read {}", path.display()));
+        }
+    };
+    if log.last().is_none_or(|&byte| byte == b'\n') {
+        return Ok(());
+    }
+    let start = log
+        .iter()
+        .rposition(|&byte| byte == b'\n')
+        .map_or(0, |i| i + 1);
+    let line = log[..start].iter().filter(|&&byte| byte == b'\n').count() + 1;
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open audit log {}", path.display()))?;
+    if serde_json::from_slice::<Link>(&log[start..]).is_ok() {
+        file.write_all(b"\n")?;
+    } else {
+        tracing::error!(
+            "Audit log {} ends with a partly written line {line}, {} bytes, left by a crash \
+            mid-append. Cutting it off",
+            path.display(),
+            log.len() - start
+        );
+        file.set_len(start as u64)?;
+    }
+    file.sync_data()
+        .with_context(|| format!("Failed to repair audit log {}", path.display()))
+}
+
+/// The lines of the log at `path`.
+fn read_lines(path: &Path) -> Result<Vec<String>> {
+    let log = match fs::read_to_string(path) {
+        Ok(log) => log,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to read {}", path.display()));
+        }
+    };
+    Ok(log.lines().map(Into::into).collect())
+}
+
+fn hash(line: &str) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(line.as_bytes()))
+}
+
+fn checkpoint_message(prev: &str, time: &str, events: u64) -> Vec<u8> {
+    format!("cipherly audit checkpoint\n{prev}\n{time}\n{events}").into_bytes()
+}
+
+/// Reads `AUDIT_KEY`, the private key that signs checkpoints of a file audit log. See
+/// [`decode_key`].
+pub fn key_from_env() -> Result<SigningKey> {
+    decode_key(&Zeroizing::new(
+        env::var("AUDIT_KEY").context("AUDIT_KEY is not set")?,
+    ))
+}
+
+/// Decodes an audit log key: an Ed25519 private key, the 32 bytes of its seed base64url encoded.
+/// `gen-kek` prints a suitable one.
+pub fn decode_key(key: &str) -> Result<SigningKey> {
+    let key = Zeroizing::new(
+        BASE64_URL_SAFE_NO_PAD
+            .decode(key)
+            .context("The audit log key should be base64url")?,
+    );
+    let seed: &[u8; 32] = key
+        .as_slice()
+        .try_into()
+        .map_err(|_| anyhow!("The audit log key should be 32 bytes"))?;
+    Ok(SigningKey::from_bytes(seed))
+}
+
+/// Decodes the public key that checks an audit log's checkpoints, as [`encode_public_key`]
+/// prints it.
+pub fn decode_public_key(public_key: &str) -> Result<VerifyingKey> {
+    let public_key = BASE64_URL_SAFE_NO_PAD
+        .decode(public_key)
+        .context("The audit log public key should be base64url")?;
+    let public_key: &[u8; 32] = public_key
+        .as_slice()
+        .try_into()
+        .map_err(|_| anyhow!("The audit log public key should be 32 bytes"))?;
+    VerifyingKey::from_bytes(public_key).context("The audit log public key is invalid")
+}
+
+/// A public key to check an audit log with, base64url encoded.
+pub fn encode_public_key(public_key: &VerifyingKey) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(public_key.as_bytes())
+}
+
+/// How often the server signs a checkpoint of a log with events unsigned.
+pub const CHECKPOINT_PERIOD: Duration = Duration::from_secs(60);
+
+/// Calls [`AuditLog::checkpoint`] every `period` until `shutdown` is cancelled.
+pub async fn checkpoint_periodically(
+    log: Arc<dyn AuditLog>,
+    period: Duration,
+    shutdown: CancellationToken,
+) {
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.cancelled() => return,
+        }
+        let log = log.clone();
+        match tokio::task::spawn_blocking(move || log.checkpoint()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => tracing::error!("Failed to checkpoint audit log: {err:#}"),
+            Err(err) => tracing::error!("Failed to checkpoint audit log: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Action, AuditEvent, AuditLog, CHECKPOINT_EVERY, FileAuditLog, MemoryAuditLog, Outcome,
+        Requester, verify,
+    };
+    use ed25519_dalek::{SigningKey, VerifyingKey};
+    use std::fs;
+
+    fn key() -> SigningKey {
+        SigningKey::from_bytes(b"0123456789abcdef0123456789abcdef")
+    }
+
+    fn public_key() -> VerifyingKey {
+        key().verifying_key()
+    }
+
+    fn event(envelope: &str, outcome: Outcome) -> AuditEvent {
+        AuditEvent {
+            time: "2026-10-19T12:00:00+00:00".into(),
+            action: Action::Unseal,
+            envelope: Some(envelope.into()),
+            kid: Some("v1".into()),
+            email: Some("alice@email.com".into()),
+            requester: Requester {
+                ip: Some("127.0.0.1:1234".into()),
+                forwarded_for: None,
+                user_agent: Some("curl/8.0".into()),
+            },
+            outcome,
+        }
+    }
+
+    fn finds_events_by_envelope(log: &dyn AuditLog) {
+        log.append(&event("a", Outcome::NotRecipient)).unwrap();
+        log.append(&event("b", Outcome::Unsealed)).unwrap();
+        log.append(&event("a", Outcome::Unsealed)).unwrap();
+        assert_eq!(
+            log.for_envelope("a").unwrap(),
+            vec![
+                event("a", Outcome::NotRecipient),
+                event("a", Outcome::Unsealed)
+            ]
+        );
+        assert_eq!(log.for_envelope("c").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn memory_log_finds_events_by_envelope() {
+        finds_events_by_envelope(&MemoryAuditLog::default());
+    }
+
+    #[test]
+    fn file_log_finds_events_by_envelope() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = FileAuditLog::open(dir.path().join("audit.jsonl"), key()).unwrap();
+        assert_eq!(log.for_envelope("a").unwrap(), vec![]);
+        finds_events_by_envelope(&log);
+    }
+
+    #[test]
+    fn file_log_verifies_until_tampered_with() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let log = FileAuditLog::open(&path, key()).unwrap();
+        for _ in 0..CHECKPOINT_EVERY + 1 {
+            log.append(&event("a", Outcome::Unsealed)).unwrap();
+        }
+        let verified = verify(&path, &public_key(), None).unwrap();
+        assert_eq!(
+            (verified.events, verified.checkpoints, verified.unsigned),
+            (CHECKPOINT_EVERY + 1, 1, 1)
+        );
+        log.checkpoint().unwrap();
+        log.checkpoint().unwrap();
+        let head = verify(&path, &public_key(), None).unwrap().head;
+        assert_eq!(
+            verify(&path, &public_key(), Some(&head)).unwrap().unsigned,
+            0
+        );
+
+        // Reopening continues the chain.
+        drop(log);
+        let log = FileAuditLog::open(&path, key()).unwrap();
+        log.append(&event("b", Outcome::Unsealed)).unwrap();
+        let verified = verify(&path, &public_key(), Some(&head)).unwrap();
+        assert_eq!(
+            (verified.events, verified.checkpoints),
+            (CHECKPOINT_EVERY + 2, 2)
+        );
+
+        let another_key = SigningKey::from_bytes(&[7; 32]).verifying_key();
+        assert!(verify(&path, &another_key, None).is_err());
+        let log = fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = log.lines().collect();
+        let removed = [&lines[..3], &lines[4..]].concat().join("\n");
+        let changed = log.replacen("alice@email.com", "mallory@email.com", 1);
+        for log in [removed, changed] {
+            fs::write(&path, log).unwrap();
+            assert!(verify(&path, &public_key(), None).is_err());
+            assert!(FileAuditLog::open(&path, key()).is_err());
+        }
+        // Cutting the log short leaves a sound chain, but loses the head.
+        fs::write(&path, lines[..CHECKPOINT_EVERY as usize].join("\n")).unwrap();
+        assert!(verify(&path, &public_key(), None).is_ok());
+        assert!(verify(&path, &public_key(), Some(&head)).is_err());
+    }
+
+    #[test]
+    fn file_log_recovers_from_a_torn_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let log = FileAuditLog::open(&path, key()).unwrap();
+        log.append(&event("a", Outcome::Unsealed)).unwrap();
+        log.checkpoint().unwrap();
+        let head = verify(&path, &public_key(), None).unwrap().head;
+        drop(log);
+
+        // A crash mid-append leaves part of a line.
+        let sound = fs::read_to_string(&path).unwrap();
+        fs::write(&path, format!("{sound}{{\"prev\":\"{head}\",\"ev")).unwrap();
+        let log = FileAuditLog::open(&path, key()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), sound);
+        log.append(&event("b", Outcome::Unsealed)).unwrap();
+        let verified = verify(&path, &public_key(), Some(&head)).unwrap();
+        assert_eq!((verified.events, verified.unsigned), (2, 1));
+
+        // One that got as far as the newline keeps its line.
+        let sound = fs::read_to_string(&path).unwrap();
+        fs::write(&path, sound.trim_end()).unwrap();
+        drop(log);
+        FileAuditLog::open(&path, key()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), sound);
+    }
+}
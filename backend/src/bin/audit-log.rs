@@ -0,0 +1,66 @@
+//! Checks the audit log the server writes with `AUDIT_LOG=file:<path>`. Checking only needs the
+//! public key, which `public-key` prints, so it can be done away from the server.
+
+use anyhow::{Context as _, Result};
+use cipherly::audit;
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+use zeroize::Zeroizing;
+
+#[derive(Parser)]
+#[command(about = "Check cipherly audit logs")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check that no line has been changed or removed, and the signature of every checkpoint.
+    Verify {
+        /// The public key of the server's `AUDIT_KEY`.
+        #[arg(long, env = "AUDIT_PUBLIC_KEY")]
+        public_key: String,
+        /// The log file.
+        audit_log: PathBuf,
+        /// A head the server logged with a checkpoint, which the log must still have, so it
+        /// hasn't been cut short or rewritten since.
+        #[arg(long)]
+        head: Option<String>,
+    },
+    /// Print the public key to verify with for the private key the server signs with.
+    PublicKey {
+        #[arg(long, env = "AUDIT_KEY", hide_env_values = true)]
+        key: String,
+    },
+}
+
+fn main() -> Result<()> {
+    match Cli::parse().command {
+        Command::Verify {
+            public_key,
+            audit_log,
+            head,
+        } => {
+            let public_key = audit::decode_public_key(&public_key)?;
+            let verified = audit::verify(&audit_log, &public_key, head.as_deref())
+                .with_context(|| format!("{} failed verification", audit_log.display()))?;
+            println!(
+                "ok: {} events, {} checkpoints, head {}",
+                verified.events, verified.checkpoints, verified.head
+            );
+            if verified.unsigned > 0 {
+                eprintln!(
+                    "warning: the last {} events aren't signed by a checkpoint yet",
+                    verified.unsigned
+                );
+            }
+            Ok(())
+        }
+        Command::PublicKey { key } => {
+            let key = audit::decode_key(&Zeroizing::new(key))?;
+            println!("{}", audit::encode_public_key(&key.verifying_key()));
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,413 @@
+//! Recovers a secret with the KEKs directly, for when sign in is unavailable. Every recovery
+//! appends a signed record, chained to the one before it, to an audit log before anything is
+//! revealed.
+
+use anyhow::{Context as _, Result, anyhow};
+use base64::prelude::*;
+use cipherly::{
+    envelope::SealedEnvelope,
+    kek,
+    payload::{self, Payload},
+    shamir::Share,
+};
+use clap::{Parser, Subcommand};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{self, Read as _, Write as _},
+    path::{Path, PathBuf},
+};
+use zeroize::Zeroizing;
+
+#[derive(Parser)]
+#[command(about = "Unseal cipherly envelopes without signing in")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Unseal a sealed envelope JSON, a `/decrypt#...` link, or a `.cly` file.
+    Unseal {
+        /// The envelope, link or path to a file. Read from stdin if omitted.
+        input: Option<String>,
+        /// Why the normal sign in path can't be used. Recorded in the audit log.
+        #[arg(long)]
+        reason: String,
+        /// Who is doing the recovery. Recorded in the audit log.
+        #[arg(long, env = "USER")]
+        operator: String,
+        #[command(flatten)]
+        keks: KeksArgs,
+        #[arg(long, env = "BREAK_GLASS_AUDIT_LOG")]
+        audit_log: PathBuf,
+        /// Where to write the plaintext of a link or file. Defaults to stdout.
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Check the signature of every record in an audit log, and that none are missing.
+    Verify {
+        #[command(flatten)]
+        keks: KeksArgs,
+        #[arg(long, env = "BREAK_GLASS_AUDIT_LOG")]
+        audit_log: PathBuf,
+        /// The head printed by the last unseal, to also catch records removed from the end.
+        #[arg(long)]
+        head: Option<String>,
+    },
+}
+
+/// Where the KEKs come from: the server's `KEKS`, or its `KEKS_FILE`, which takes precedence as
+/// it does for the server.
+#[derive(clap::Args)]
+struct KeksArgs {
+    #[arg(long, env = "KEKS", hide_env_values = true)]
+    keks: Option<String>,
+    /// An encrypted key ring file. Its passphrase is read from `KEKS_PASSPHRASE_FILE`,
+    /// `KEKS_PASSPHRASE` or the terminal; for a file split into shares, the shares are prompted for.
+    #[arg(long, env = "KEKS_FILE")]
+    keks_file: Option<String>,
+}
+
+impl KeksArgs {
+    /// The key ring as `KEKS` JSON.
+    fn read(&self) -> Result<Zeroizing<String>> {
+        let Some(path) = &self.keks_file else {
+            let keks = self.keks.as_deref().context("Set KEKS or KEKS_FILE")?;
+            return Ok(Zeroizing::new(keks.into()));
+        };
+        let file = fs::read_to_string(path).with_context(|| format!("Failed to read {path}"))?;
+        let json = match kek::split_threshold(&file)? {
+            Some(threshold) => kek::combine_key_ring(&file, &read_shares(threshold)?)?,
+            None => kek::decrypt_key_ring(&file, &kek::passphrase_from_env(path)?)?,
+        };
+        Ok(json)
+    }
+}
+
+fn read_shares(threshold: u8) -> Result<Vec<Share>> {
+    (1..=threshold)
+        .map(|i| {
+            let share = Zeroizing::new(rpassword::prompt_password(format!(
+                "Share {i} of {threshold}: "
+            ))?);
+            Share::decode(&share)
+        })
+        .collect()
+}
+
+/// One break-glass unseal. Signed with a key derived from the KEK that opened the envelope, so
+/// nobody without the key ring can forge one. The operator holds the key ring, though, so the
+/// signatures alone don't stop them rewriting the log: each record also carries the hash of the
+/// line before it, and `unseal` prints the new head to record somewhere the operator can't
+/// change. `verify --head` then catches records edited, removed or re-signed after the fact.
+#[derive(Debug, Serialize, Deserialize)]
+struct AuditRecord {
+    /// Base64url SHA-256 of the previous line of the log, or empty for the first record.
+    prev: String,
+    time: String,
+    operator: String,
+    reason: String,
+    kid: String,
+    /// SHA-256 of the sealed envelope's data, to identify it without storing it.
+    envelope: String,
+    emails: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SignedAuditRecord {
+    #[serde(flatten)]
+    record: AuditRecord,
+    signature: String,
+}
+
+fn signing_key(kek: &[u8]) -> Zeroizing<Vec<u8>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(kek).expect("HMAC accepts any key length");
+    mac.update(b"cipherly break-glass audit");
+    Zeroizing::new(mac.finalize().into_bytes().to_vec())
+}
+
+fn mac(
+    raw_keks: &HashMap<String, Zeroizing<Vec<u8>>>,
+    record: &AuditRecord,
+) -> Result<Hmac<Sha256>> {
+    let kek = raw_keks
+        .get(&record.kid)
+        .with_context(|| format!("No KEK {}", record.kid))?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(&signing_key(kek))?;
+    mac.update(&serde_json::to_vec(record)?);
+    Ok(mac)
+}
+
+impl AuditRecord {
+    fn sign(self, raw_keks: &HashMap<String, Zeroizing<Vec<u8>>>) -> Result<SignedAuditRecord> {
+        let signature =
+            BASE64_URL_SAFE_NO_PAD.encode(mac(raw_keks, &self)?.finalize().into_bytes());
+        Ok(SignedAuditRecord {
+            record: self,
+            signature,
+        })
+    }
+}
+
+impl SignedAuditRecord {
+    fn verify(&self, raw_keks: &HashMap<String, Zeroizing<Vec<u8>>>) -> Result<()> {
+        let signature = BASE64_URL_SAFE_NO_PAD.decode(&self.signature)?;
+        mac(raw_keks, &self.record)?
+            .verify_slice(&signature)
+            .map_err(|_| anyhow!("Signature does not match"))
+    }
+}
+
+/// What was handed to the tool: a bare sealed envelope, or a whole secret.
+enum Input {
+    Sealed(SealedEnvelope),
+    Secret(Payload),
+}
+
+fn read_input(input: Option<String>) -> Result<Input> {
+    let (data, is_file) = match input {
+        Some(input) if Path::new(&input).is_file() => (
+            fs::read(&input).with_context(|| format!("Failed to read {input}"))?,
+            true,
+        ),
+        Some(input) => (input.into_bytes(), false),
+        None => {
+            let mut data = Vec::new();
+            io::stdin().read_to_end(&mut data)?;
+            (data, false)
+        }
+    };
+    if data.trim_ascii_start().starts_with(b"{") {
+        let sealed = serde_json::from_slice(&data).context("Input is not a sealed envelope")?;
+        return Ok(Input::Sealed(sealed));
+    }
+    let (_, payload) = if is_file {
+        payload::decode_file(&data)?
+    } else {
+        payload::decode_link(std::str::from_utf8(&data).context("Link is not valid UTF-8")?)?
+    };
+    Ok(Input::Secret(payload))
+}
+
+fn main() -> Result<()> {
+    match Cli::parse().command {
+        Command::Unseal {
+            input,
+            reason,
+            operator,
+            keks,
+            audit_log,
+            out,
+        } => unseal(input, reason, operator, &keks.read()?, &audit_log, out),
+        Command::Verify {
+            keks,
+            audit_log,
+            head,
+        } => verify(&keks.read()?, &audit_log, head.as_deref()),
+    }
+}
+
+fn unseal(
+    input: Option<String>,
+    reason: String,
+    operator: String,
+    keks: &str,
+    audit_log: &Path,
+    out: Option<PathBuf>,
+) -> Result<()> {
+    if reason.trim().is_empty() {
+        return Err(anyhow!("--reason must not be empty"));
+    }
+    let raw_keks = kek::decode(keks).context("Failed to parse KEKs")?;
+    let parsed_keks = kek::parse(keks).context("Failed to parse KEKs")?;
+
+    let (sealed, secret) = match read_input(input)? {
+        Input::Sealed(sealed) => (sealed, None),
+        Input::Secret(Payload::Auth(payload)) => (payload.sealed_envelope(), Some(payload)),
+        Input::Secret(Payload::Password(_)) => {
+            return Err(anyhow!(
+                "This secret is password protected; the KEKs can't recover it"
+            ));
+        }
+    };
+    let envelope = kek::unseal(&parsed_keks, &sealed)?;
+
+    let record = AuditRecord {
+        prev: head(audit_log)?,
+        time: chrono::Utc::now().to_rfc3339(),
+        operator,
+        reason,
+        kid: sealed.kid.clone(),
+        envelope: BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(sealed.data.as_bytes())),
+        emails: envelope.emails.clone(),
+    }
+    .sign(&raw_keks)?;
+    let head = append_record(audit_log, &record)?;
+    eprintln!(
+        "Recorded break-glass unseal in {}. Note its head, {head}, outside this host, e.g. in the \
+         incident ticket, so it can be checked with `verify --head`.",
+        audit_log.display()
+    );
+
+    // A bare envelope can only be recovered as far as its DEK.
+    let output = match secret {
+        Some(payload) => payload.decrypt(&envelope)?,
+        None => serde_json::to_vec_pretty(&envelope)?,
+    };
+    match out {
+        Some(out) => OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&out)
+            .and_then(|mut file| file.write_all(&output))
+            .with_context(|| format!("Failed to write {}", out.display())),
+        None => Ok(io::stdout().write_all(&output)?),
+    }
+}
+
+fn hash(line: &str) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(line.as_bytes()))
+}
+
+/// The hash of the last line of the log, or empty if there's none yet.
+fn head(audit_log: &Path) -> Result<String> {
+    match fs::read_to_string(audit_log) {
+        Ok(log) => Ok(log
+            .lines()
+            .rfind(|line| !line.is_empty())
+            .map(hash)
+            .unwrap_or_default()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        Err(err) => {
+            Err(err).with_context(|| format!("Failed to read audit log {}", audit_log.display()))
+        }
+    }
+}
+
+/// Appends and syncs the record, so nothing is revealed unless it's on disk. Returns the new head.
+fn append_record(audit_log: &Path, record: &SignedAuditRecord) -> Result<String> {
+    let line = serde_json::to_string(record)?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(audit_log)
+        .with_context(|| format!("Failed to open audit log {}", audit_log.display()))?;
+    file.write_all(format!("{line}\n").as_bytes())?;
+    file.sync_all()?;
+    Ok(hash(&line))
+}
+
+fn verify(keks: &str, audit_log: &Path, head: Option<&str>) -> Result<()> {
+    let raw_keks = kek::decode(keks).context("Failed to parse KEKs")?;
+    let log = fs::read_to_string(audit_log)
+        .with_context(|| format!("Failed to read {}", audit_log.display()))?;
+    let mut failures = 0;
+    let mut prev = String::new();
+    for (i, line) in log.lines().enumerate().filter(|(_, line)| !line.is_empty()) {
+        let result = serde_json::from_str::<SignedAuditRecord>(line)
+            .map_err(Into::into)
+            .and_then(|record| record.verify(&raw_keks).map(|_| record))
+            .and_then(|record| {
+                if record.record.prev == prev {
+                    Ok(record)
+                } else {
+                    Err(anyhow!("The record before it was removed or changed"))
+                }
+            });
+        prev = hash(line);
+        match result {
+            Ok(record) => println!(
+                "line {}: ok: {} by {}: {}",
+                i + 1,
+                record.record.time,
+                record.record.operator,
+                record.record.reason
+            ),
+            Err(err) => {
+                failures += 1;
+                println!("line {}: FAILED: {err}", i + 1);
+            }
+        }
+    }
+    println!("head: {prev}");
+    if failures > 0 {
+        return Err(anyhow!("{failures} audit records failed verification"));
+    }
+    if head.is_some_and(|head| head != prev) {
+        return Err(anyhow!(
+            "The log doesn't end at {}; records were removed or rewritten",
+            head.unwrap_or_default()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuditRecord, append_record, head, verify};
+    use cipherly::kek;
+    use std::fs;
+
+    const TEST_KEKS: &str = r#"{"v1":"jRg36ErQ6FLcc7nZgngOpjJnJLGwA3xaMy0yx1pxJrI"}"#;
+
+    fn record() -> AuditRecord {
+        AuditRecord {
+            prev: String::new(),
+            time: "2026-01-01T00:00:00+00:00".into(),
+            operator: "alice".into(),
+            reason: "Google sign in outage".into(),
+            kid: "v1".into(),
+            envelope: "hash".into(),
+            emails: vec!["bob@email.com".into()],
+        }
+    }
+
+    #[test]
+    fn signed_record_verifies() {
+        let raw_keks = kek::decode(TEST_KEKS).unwrap();
+        let signed = record().sign(&raw_keks).unwrap();
+        let line = serde_json::to_string(&signed).unwrap();
+        let parsed: super::SignedAuditRecord = serde_json::from_str(&line).unwrap();
+        parsed.verify(&raw_keks).unwrap();
+    }
+
+    #[test]
+    fn tampered_record_fails_verification() {
+        let raw_keks = kek::decode(TEST_KEKS).unwrap();
+        let mut signed = record().sign(&raw_keks).unwrap();
+        signed.record.reason = "routine".into();
+        assert!(signed.verify(&raw_keks).is_err());
+    }
+
+    #[test]
+    fn removed_records_fail_verification() {
+        let raw_keks = kek::decode(TEST_KEKS).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("break-glass.jsonl");
+        let mut heads = Vec::new();
+        for reason in ["first", "second", "third"] {
+            let record = AuditRecord {
+                prev: head(&path).unwrap(),
+                reason: reason.into(),
+                ..record()
+            };
+            heads.push(append_record(&path, &record.sign(&raw_keks).unwrap()).unwrap());
+        }
+        verify(TEST_KEKS, &path, Some(&heads[2])).unwrap();
+
+        let log = fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = log.lines().collect();
+        // Dropping a record from the middle breaks the chain.
+        fs::write(&path, format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+        assert!(verify(TEST_KEKS, &path, None).is_err());
+        // Dropping the last one is only caught with the head.
+        fs::write(&path, format!("{}\n{}\n", lines[0], lines[1])).unwrap();
+        verify(TEST_KEKS, &path, None).unwrap();
+        assert!(verify(TEST_KEKS, &path, Some(&heads[2])).is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use aes_gcm::{
+    AeadCore, Aes256Gcm, KeyInit,
+    aead::{Aead, OsRng, rand_core::RngCore},
+};
+use anyhow::{Context as _, Result, anyhow};
+use base64::prelude::*;
+use cipherly::payload::{AUTH_SCHEME, AuthPayload};
+use cipherly::{
+    client::{Client, Error},
+    envelope::Envelope,
+};
+use zeroize::Zeroizing;
+
+fn decode_base64(data: &str) -> Result<Vec<u8>> {
+    Ok(BASE64_URL_SAFE_NO_PAD.decode(data.trim_end_matches('='))?)
+}
+
+/// Encrypts `plaintext` under a fresh DEK and has the server seal the DEK for `emails`. With
+/// `sender_token`, seals as its holder and prints how to revoke the envelope.
+pub async fn encrypt(
+    client: &Client,
+    plaintext: &[u8],
+    emails: Vec<String>,
+    max_unseals: Option<u32>,
+    sender_token: Option<&str>,
+    filename: Option<String>,
+) -> Result<AuthPayload> {
+    let mut dek = Zeroizing::new([0; 32]);
+    OsRng.fill_bytes(dek.as_mut_slice());
+    let iv = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ct = Aes256Gcm::new(dek.as_slice().into())
+        .encrypt(&iv, plaintext)
+        .map_err(|_| anyhow!("Encryption failed"))?;
+
+    let envelope = Envelope {
+        dek: BASE64_URL_SAFE_NO_PAD.encode(dek.as_slice()),
+        emails,
+        max_unseals,
+        id: None,
+    };
+    let sealed = match sender_token {
+        Some(token) => client.seal_as(&envelope, token).await,
+        None => client.seal(&envelope).await,
+    }
+    .context("Failed to seal the key")?;
+    if let (Some(_), Some(id)) = (sender_token, &sealed.id) {
+        eprintln!("Revoke with: cipherly-cli revoke {id}");
+    }
+
+    Ok(AuthPayload {
+        es: AUTH_SCHEME,
+        filename,
+        k: sealed.kid,
+        n: decode_base64(&sealed.nonce)?,
+        se: decode_base64(&sealed.data)?,
+        iv: iv.to_vec(),
+        ct,
+    })
+}
+
+/// Has the server unseal the DEK for the holder of `token`, then decrypts the payload.
+pub async fn decrypt(client: &Client, payload: &AuthPayload, token: &str) -> Result<Vec<u8>> {
+    let envelope = match client.unseal(&payload.sealed_envelope(), token).await {
+        Ok(envelope) => envelope,
+        Err(Error::Unauthorized { .. }) => {
+            return Err(anyhow!(
+                "Not authorized to decrypt this secret. Was it sent to your email address?"
+            ));
+        }
+        Err(err) => return Err(err).context("Failed to unseal the key"),
+    };
+    payload.decrypt(&envelope)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_base64, decrypt, encrypt};
+    use axum::{Json, Router, http::StatusCode, routing::post};
+    use base64::prelude::*;
+    use cipherly::{
+        client::Client,
+        envelope::{Envelope, SealedEnvelope},
+    };
+    use tokio::net::TcpListener;
+
+    /// Stands in for the server by "sealing" envelopes as plain JSON.
+    async fn start_fake_server() -> String {
+        let app = Router::new()
+            .route(
+                "/api/seal",
+                post(|Json(envelope): Json<Envelope>| async move {
+                    Json(SealedEnvelope {
+                        kid: "v1".into(),
+                        nonce: "AAAAAAAAAAAAAAAA".into(),
+                        data: BASE64_URL_SAFE_NO_PAD.encode(serde_json::to_vec(&envelope).unwrap()),
+                        id: None,
+                    })
+                }),
+            )
+            .route(
+                "/api/unseal",
+                post(|Json(sealed): Json<SealedEnvelope>| async move {
+                    let envelope: Envelope =
+                        serde_json::from_slice(&decode_base64(&sealed.data).unwrap()).unwrap();
+                    if envelope.emails.contains(&"alice@email.com".to_string()) {
+                        Ok(Json(envelope))
+                    } else {
+                        Err(StatusCode::UNAUTHORIZED)
+                    }
+                }),
+            );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{addr}")
+    }
+
+    #[tokio::test]
+    async fn encrypt_and_decrypt_succeeds() {
+        let client = Client::new(start_fake_server().await);
+
+        let payload = encrypt(
+            &client,
+            b"hello",
+            vec!["alice@email.com".into()],
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(payload.k, "v1");
+        let plaintext = decrypt(&client, &payload, "token").await.unwrap();
+        assert_eq!(plaintext, b"hello");
+    }
+
+    #[tokio::test]
+    async fn decrypt_for_other_recipient_fails() {
+        let client = Client::new(start_fake_server().await);
+
+        let payload = encrypt(
+            &client,
+            b"hello",
+            vec!["bob@email.com".into()],
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(decrypt(&client, &payload, "token").await.is_err());
+    }
+}
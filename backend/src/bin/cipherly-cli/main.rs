@@ -0,0 +1,458 @@
+//! Encrypts and decrypts cipherly secrets from the terminal. Links and `.cly` files are
+//! interchangeable with the ones produced by the web app.
+
+use anyhow::{Context as _, Result, anyhow};
+use cipherly::{
+    client::Client,
+    google,
+    payload::{self, Payload, ShortLink},
+    stream::{self, Decryptor, Encryptor, StreamLink},
+};
+use clap::{Parser, Subcommand};
+use oidc::DeviceFlow;
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{self, IsTerminal as _, Read as _, Write as _},
+    path::{Path, PathBuf},
+};
+use tokio::io::AsyncReadExt as _;
+use zeroize::Zeroizing;
+
+mod auth;
+mod oidc;
+mod password;
+
+#[derive(Parser)]
+#[command(about = "Share secrets with cipherly from the terminal")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Encrypt text from stdin, or a file, with a password or for a set of recipients.
+    Encrypt {
+        /// The cipherly instance that will decrypt the secret.
+        #[arg(long, env = "CIPHERLY_URL")]
+        url: String,
+        /// Encrypt this file into `<FILE>.cly` instead of reading text from stdin.
+        #[arg(long)]
+        file: Option<PathBuf>,
+        /// Where to write the `.cly` file. Defaults to `<FILE>.cly`.
+        #[arg(long, requires = "file", conflicts_with = "short")]
+        out: Option<PathBuf>,
+        /// Store the encrypted secret on the server and print a short `/s/...` link to it,
+        /// for files and text too long for a link. The server must have a blob store.
+        #[arg(long)]
+        short: bool,
+        /// Sign in and seal as yourself, so you can later revoke the secret with `revoke`. Needs
+        /// `--to`, and a server with a blob store.
+        #[arg(long, requires = "to")]
+        revocable: bool,
+        /// Let the secret be opened at most this many times. Needs `--to` or `--short`.
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+        max_views: Option<u32>,
+        /// Let this email address decrypt after signing in, instead of using a password. May be
+        /// repeated.
+        #[arg(long, value_name = "EMAIL", conflicts_with = "password_env")]
+        to: Vec<String>,
+        #[command(flatten)]
+        password: PasswordArgs,
+        #[command(flatten)]
+        login: LoginArgs,
+    },
+    /// Decrypt a link or a `.cly` file.
+    Decrypt {
+        /// A `/decrypt#...` or `/s/...` link, or a path to a `.cly` file. Reads a link from stdin
+        /// if omitted.
+        input: Option<String>,
+        /// Where to write the plaintext. Defaults to stdout for text and the original filename
+        /// for files.
+        #[arg(long)]
+        out: Option<PathBuf>,
+        #[command(flatten)]
+        password: PasswordArgs,
+        #[command(flatten)]
+        login: LoginArgs,
+    },
+    /// Revoke a secret encrypted with `--revocable`, so no one can decrypt it anymore.
+    Revoke {
+        /// The cipherly instance the secret was encrypted with.
+        #[arg(long, env = "CIPHERLY_URL")]
+        url: String,
+        /// The envelope id printed when encrypting.
+        id: String,
+        #[command(flatten)]
+        login: LoginArgs,
+    },
+    /// Encrypt a file of any size a piece at a time while uploading it, and print a link to it.
+    /// Anyone with the link can download it. The server must have a stream store.
+    Upload {
+        /// The cipherly instance to store the file on.
+        #[arg(long, env = "CIPHERLY_URL")]
+        url: String,
+        file: PathBuf,
+    },
+    /// Download and decrypt a file uploaded with `upload`, a piece at a time.
+    Download {
+        /// The `/api/streams/...` link printed by `upload`.
+        link: String,
+        /// Where to write the file.
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// List who tried to decrypt a secret encrypted with `--revocable`, and when.
+    Access {
+        /// The cipherly instance the secret was encrypted with.
+        #[arg(long, env = "CIPHERLY_URL")]
+        url: String,
+        /// The envelope id printed when encrypting.
+        id: String,
+        #[command(flatten)]
+        login: LoginArgs,
+    },
+}
+
+#[derive(clap::Args)]
+struct PasswordArgs {
+    /// Read the password from this environment variable instead of prompting.
+    #[arg(long, value_name = "VAR")]
+    password_env: Option<String>,
+}
+
+impl PasswordArgs {
+    fn read(&self, confirm: bool) -> Result<String> {
+        if let Some(var) = &self.password_env {
+            return env::var(var).with_context(|| format!("{var} is not set"));
+        }
+        let password = rpassword::prompt_password("Password: ")?;
+        if confirm && rpassword::prompt_password("Confirm password: ")? != password {
+            return Err(anyhow!("Passwords do not match"));
+        }
+        if password.is_empty() {
+            return Err(anyhow!("Password must not be empty"));
+        }
+        Ok(password)
+    }
+}
+
+/// How to get an ID token for secrets encrypted for recipients.
+#[derive(clap::Args)]
+struct LoginArgs {
+    /// Read an ID token from this environment variable instead of signing in.
+    #[arg(long, value_name = "VAR")]
+    token_env: Option<String>,
+    /// A Google OAuth client that supports the device flow, the only issuer the server accepts.
+    /// The server must accept it in `EXTRA_CLIENT_IDS`.
+    #[arg(long, env = "CIPHERLY_OIDC_CLIENT_ID")]
+    client_id: Option<String>,
+    #[arg(long, env = "CIPHERLY_OIDC_CLIENT_SECRET", hide_env_values = true)]
+    client_secret: Option<String>,
+}
+
+impl LoginArgs {
+    async fn token(&self, client: &reqwest::Client) -> Result<String> {
+        if let Some(var) = &self.token_env {
+            return env::var(var).with_context(|| format!("{var} is not set"));
+        }
+        let client_id = self
+            .client_id
+            .clone()
+            .context("Set --client-id or CIPHERLY_OIDC_CLIENT_ID to sign in")?;
+        DeviceFlow {
+            issuer: google::ISSUER.into(),
+            client_id,
+            client_secret: self.client_secret.clone(),
+        }
+        .login(client)
+        .await
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    match Cli::parse().command {
+        Command::Encrypt {
+            url,
+            file,
+            out,
+            short,
+            revocable,
+            max_views,
+            to,
+            password,
+            login,
+        } => {
+            let limits = Limits { short, max_views };
+            let sender_token = if revocable {
+                Some(login.token(&reqwest::Client::new()).await?)
+            } else {
+                None
+            };
+            encrypt(&url, file, out, &limits, to, sender_token, &password).await
+        }
+        Command::Decrypt {
+            input,
+            out,
+            password,
+            login,
+        } => decrypt(input, out, &password, &login).await,
+        Command::Revoke { url, id, login } => {
+            let token = login.token(&reqwest::Client::new()).await?;
+            Client::new(url).revoke(&id, &token).await?;
+            eprintln!("Revoked {id}");
+            Ok(())
+        }
+        Command::Upload { url, file } => upload_stream(&url, &file).await,
+        Command::Download { link, out } => download_stream(&link, &out).await,
+        Command::Access { url, id, login } => {
+            let token = login.token(&reqwest::Client::new()).await?;
+            for event in Client::new(url).access_history(&id, &token).await? {
+                let requester = &event.requester;
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    event.time,
+                    event.email.as_deref().unwrap_or("-"),
+                    event.outcome,
+                    requester
+                        .forwarded_for
+                        .as_ref()
+                        .or(requester.ip.as_ref())
+                        .map_or("-", String::as_str),
+                    requester.user_agent.as_deref().unwrap_or("-"),
+                );
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Whether to store the secret on the server, and how many times it may be opened.
+struct Limits {
+    short: bool,
+    max_views: Option<u32>,
+}
+
+async fn seal(
+    url: &str,
+    plaintext: &[u8],
+    to: Vec<String>,
+    max_unseals: Option<u32>,
+    sender_token: Option<&str>,
+    password: &PasswordArgs,
+    filename: Option<String>,
+) -> Result<Payload> {
+    if to.is_empty() {
+        let payload = password::encrypt(plaintext, &password.read(true)?, filename)?;
+        Ok(Payload::Password(payload))
+    } else {
+        let client = Client::new(url);
+        let payload =
+            auth::encrypt(&client, plaintext, to, max_unseals, sender_token, filename).await?;
+        Ok(Payload::Auth(payload))
+    }
+}
+
+async fn encrypt(
+    url: &str,
+    file: Option<PathBuf>,
+    out: Option<PathBuf>,
+    limits: &Limits,
+    to: Vec<String>,
+    sender_token: Option<String>,
+    password: &PasswordArgs,
+) -> Result<()> {
+    let sender_token = sender_token.as_deref();
+    if limits.max_views.is_some() && to.is_empty() && !limits.short {
+        return Err(anyhow!(
+            "--max-views needs --to or --short; the server can't count views of a link alone"
+        ));
+    }
+    // A short link to an envelope is limited by the envelope's unseals, so a failed sign-in
+    // doesn't use up a view.
+    let (max_unseals, max_views) = if to.is_empty() {
+        (None, limits.max_views)
+    } else {
+        (limits.max_views, None)
+    };
+    match file {
+        Some(file) => {
+            let plaintext =
+                fs::read(&file).with_context(|| format!("Failed to read {}", file.display()))?;
+            let filename = file
+                .file_name()
+                .and_then(|name| name.to_str())
+                .context("File name is not valid UTF-8")?
+                .to_string();
+            let payload = seal(
+                url,
+                &plaintext,
+                to,
+                max_unseals,
+                sender_token,
+                password,
+                Some(filename),
+            )
+            .await?;
+            if limits.short {
+                println!("{}", upload(url, &payload, max_views).await?);
+            } else {
+                let out = out.unwrap_or_else(|| PathBuf::from(format!("{}.cly", file.display())));
+                write_new(&out, &payload::encode_file(url, &payload)?)?;
+                eprintln!("Wrote {}", out.display());
+            }
+        }
+        None => {
+            let mut plaintext = Vec::new();
+            io::stdin().read_to_end(&mut plaintext)?;
+            let payload = seal(
+                url,
+                &plaintext,
+                to,
+                max_unseals,
+                sender_token,
+                password,
+                None,
+            )
+            .await?;
+            if limits.short {
+                println!("{}", upload(url, &payload, max_views).await?);
+            } else {
+                println!("{}", payload::encode_link(url, &payload)?);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Stores the payload on the server as an encrypted blob and returns a short link to it.
+async fn upload(url: &str, payload: &Payload, max_views: Option<u32>) -> Result<ShortLink> {
+    let (blob, key) = payload::encrypt_blob(url, payload)?;
+    let id = Client::new(url).upload_blob(&blob, max_views).await?;
+    Ok(ShortLink {
+        base_url: url.trim_end_matches('/').into(),
+        id,
+        key,
+    })
+}
+
+/// Decodes a `/decrypt#...` link, or fetches and decrypts the blob of a short link.
+async fn decode_link(link: &str) -> Result<(String, Payload)> {
+    match ShortLink::parse(link) {
+        Some(link) => {
+            let blob = Client::new(&link.base_url).fetch_blob(&link.id).await?;
+            link.decrypt_blob(&blob)
+        }
+        None => payload::decode_link(link),
+    }
+}
+
+async fn decrypt(
+    input: Option<String>,
+    out: Option<PathBuf>,
+    password: &PasswordArgs,
+    login: &LoginArgs,
+) -> Result<()> {
+    let (base_url, payload) = match input {
+        Some(input) if Path::new(&input).is_file() => payload::decode_file(&fs::read(&input)?)?,
+        Some(link) => decode_link(&link).await?,
+        None => {
+            let mut link = String::new();
+            io::stdin().read_line(&mut link)?;
+            decode_link(&link).await?
+        }
+    };
+    let plaintext = match &payload {
+        Payload::Password(payload) => password::decrypt(payload, &password.read(false)?)?,
+        Payload::Auth(payload) => {
+            let token = login.token(&reqwest::Client::new()).await?;
+            auth::decrypt(&Client::new(base_url), payload, &token).await?
+        }
+    };
+
+    // Only trust the final path component of the embedded filename.
+    let filename = payload
+        .filename()
+        .and_then(|name| Path::new(name).file_name())
+        .map(PathBuf::from);
+    match out.or(filename) {
+        Some(out) => {
+            write_new(&out, &plaintext)?;
+            eprintln!("Wrote {}", out.display());
+        }
+        None => {
+            let mut stdout = io::stdout();
+            stdout.write_all(&plaintext)?;
+            if stdout.is_terminal() && !plaintext.ends_with(b"\n") {
+                writeln!(stdout)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Encrypts a file under a new key as it's read, uploads it as a stream and prints its link.
+async fn upload_stream(url: &str, path: &Path) -> Result<()> {
+    let file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let key = stream::new_key();
+    let encryptor = Encryptor::new(&key);
+    let body = futures_util::stream::try_unfold(Some((file, encryptor)), |state| async move {
+        let Some((mut file, mut encryptor)) = state else {
+            return anyhow::Ok(None);
+        };
+        let mut data = Zeroizing::new(vec![0; stream::CHUNK_SIZE]);
+        let len = file.read(&mut data).await?;
+        if len == 0 {
+            return Ok(Some((encryptor.finish()?, None)));
+        }
+        Ok(Some((
+            encryptor.update(&data[..len])?,
+            Some((file, encryptor)),
+        )))
+    });
+    let id = Client::new(url)
+        .upload_stream(reqwest::Body::wrap_stream(body))
+        .await?;
+    println!("{}", StreamLink::new(url, id, &key));
+    Ok(())
+}
+
+async fn download_stream(link: &str, out: &Path) -> Result<()> {
+    let link = StreamLink::parse(link).context("Not an /api/streams/... link")?;
+    let mut decryptor = Decryptor::new(&*link.key()?);
+    let mut response = Client::new(&link.base_url)
+        .download_stream(&link.id)
+        .await?;
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(out)
+        .with_context(|| format!("Failed to write {}", out.display()))?;
+    let result = async {
+        while let Some(data) = response.chunk().await? {
+            file.write_all(&decryptor.update(&data)?)?;
+        }
+        file.write_all(&decryptor.finish()?)?;
+        anyhow::Ok(())
+    }
+    .await;
+    // Don't leave behind a file that may have been cut short.
+    if result.is_err() {
+        let _ = fs::remove_file(out);
+    }
+    result
+}
+
+/// Writes a file, refusing to replace one that already exists.
+fn write_new(path: &Path, data: &[u8]) -> Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(data))
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
@@ -0,0 +1,192 @@
+//! Gets an OpenID Connect ID token with the device authorization grant (RFC 8628), so users can
+//! sign in from a terminal by entering a code in any browser.
+
+use anyhow::{Context as _, Result, anyhow};
+use reqwest::Client;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+#[derive(Deserialize)]
+struct Discovery {
+    device_authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+#[derive(Deserialize)]
+struct DeviceAuthorization {
+    device_code: String,
+    user_code: String,
+    /// Google still uses the draft spec's `verification_url`.
+    #[serde(alias = "verification_url")]
+    verification_uri: String,
+    expires_in: u64,
+    #[serde(default = "default_interval")]
+    interval: u64,
+}
+
+fn default_interval() -> u64 {
+    5
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+pub struct DeviceFlow {
+    pub issuer: String,
+    pub client_id: String,
+    /// Some providers, including Google, require the secret even for device clients.
+    pub client_secret: Option<String>,
+}
+
+impl DeviceFlow {
+    /// Runs the device flow and returns the ID token once the user approves the sign in.
+    pub async fn login(&self, client: &Client) -> Result<String> {
+        let discovery: Discovery = client
+            .get(format!(
+                "{}/.well-known/openid-configuration",
+                self.issuer.trim_end_matches('/')
+            ))
+            .send()
+            .await?
+            .error_for_status()
+            .context("Failed to fetch OpenID configuration")?
+            .json()
+            .await?;
+
+        let authorization: DeviceAuthorization = client
+            .post(&discovery.device_authorization_endpoint)
+            .form(&[
+                ("client_id", self.client_id.as_str()),
+                ("scope", "openid email profile"),
+            ])
+            .send()
+            .await?
+            .error_for_status()
+            .context("Failed to start device authorization")?
+            .json()
+            .await?;
+        eprintln!(
+            "To sign in, visit {} and enter the code {}",
+            authorization.verification_uri, authorization.user_code
+        );
+
+        let deadline = Instant::now() + Duration::from_secs(authorization.expires_in);
+        let mut interval = Duration::from_secs(authorization.interval);
+        let mut form = vec![
+            ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+            ("device_code", authorization.device_code.as_str()),
+            ("client_id", self.client_id.as_str()),
+        ];
+        if let Some(client_secret) = &self.client_secret {
+            form.push(("client_secret", client_secret));
+        }
+        while Instant::now() < deadline {
+            tokio::time::sleep(interval).await;
+            let token: TokenResponse = client
+                .post(&discovery.token_endpoint)
+                .form(&form)
+                .send()
+                .await?
+                .json()
+                .await?;
+            match (token.id_token, token.error.as_deref()) {
+                (Some(id_token), _) => return Ok(id_token),
+                (None, Some("authorization_pending")) => {}
+                (None, Some("slow_down")) => interval += Duration::from_secs(5),
+                (None, error) => {
+                    return Err(anyhow!(
+                        "Sign in failed: {}",
+                        token
+                            .error_description
+                            .as_deref()
+                            .or(error)
+                            .unwrap_or("no ID token returned")
+                    ));
+                }
+            }
+        }
+        Err(anyhow!("Sign in timed out"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DeviceFlow;
+    use axum::{Form, Json, Router, extract::State, routing::get, routing::post};
+    use reqwest::Client;
+    use serde_json::{Value, json};
+    use std::{
+        collections::HashMap,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+    };
+    use tokio::net::TcpListener;
+
+    /// Fake issuer that reports the authorization as pending on the first poll.
+    async fn start_fake_issuer() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let polls = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route(
+                "/.well-known/openid-configuration",
+                get({
+                    let issuer = issuer.clone();
+                    || async move {
+                        Json(json!({
+                            "device_authorization_endpoint": format!("{issuer}/device"),
+                            "token_endpoint": format!("{issuer}/token"),
+                        }))
+                    }
+                }),
+            )
+            .route(
+                "/device",
+                post(|| async {
+                    Json(json!({
+                        "device_code": "device-code",
+                        "user_code": "ABCD-EFGH",
+                        "verification_url": "https://example.com/device",
+                        "expires_in": 60,
+                        "interval": 0,
+                    }))
+                }),
+            )
+            .route(
+                "/token",
+                post(
+                    |State(polls): State<Arc<AtomicUsize>>,
+                     Form(form): Form<HashMap<String, String>>| async move {
+                        assert_eq!(form["device_code"], "device-code");
+                        assert_eq!(form["client_secret"], "secret");
+                        let body: Value = if polls.fetch_add(1, Ordering::SeqCst) == 0 {
+                            json!({"error": "authorization_pending"})
+                        } else {
+                            json!({"id_token": "id-token"})
+                        };
+                        Json(body)
+                    },
+                ),
+            )
+            .with_state(polls);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        issuer
+    }
+
+    #[tokio::test]
+    async fn login_returns_id_token_after_approval() {
+        let issuer = start_fake_issuer().await;
+        let flow = DeviceFlow {
+            issuer,
+            client_id: "client".into(),
+            client_secret: Some("secret".into()),
+        };
+        assert_eq!(flow.login(&Client::new()).await.unwrap(), "id-token");
+    }
+}
@@ -0,0 +1,64 @@
+use aes_gcm::{
+    AeadCore, Aes256Gcm, Key, KeyInit,
+    aead::{Aead, OsRng, rand_core::RngCore},
+};
+use anyhow::{Result, anyhow};
+use cipherly::payload::{PASSWORD_SCHEME, PasswordPayload};
+use sha2::Sha256;
+
+/// Must match `deriveKey` in `cipherly.ts`.
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+fn derive_key(password: &str, salt: &[u8]) -> Key<Aes256Gcm> {
+    let mut key = Key::<Aes256Gcm>::default();
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, PBKDF2_ITERATIONS, &mut key);
+    key
+}
+
+pub fn encrypt(
+    plaintext: &[u8],
+    password: &str,
+    filename: Option<String>,
+) -> Result<PasswordPayload> {
+    let mut salt = vec![0; 16];
+    OsRng.fill_bytes(&mut salt);
+    let key = derive_key(password, &salt);
+    let iv = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ct = Aes256Gcm::new(&key)
+        .encrypt(&iv, plaintext)
+        .map_err(|_| anyhow!("Encryption failed"))?;
+    Ok(PasswordPayload {
+        es: PASSWORD_SCHEME,
+        filename,
+        s: salt,
+        iv: iv.to_vec(),
+        ct,
+    })
+}
+
+pub fn decrypt(payload: &PasswordPayload, password: &str) -> Result<Vec<u8>> {
+    if payload.iv.len() != 12 {
+        return Err(anyhow!("Payload has an invalid IV"));
+    }
+    let key = derive_key(password, &payload.s);
+    Aes256Gcm::new(&key)
+        .decrypt(payload.iv.as_slice().into(), payload.ct.as_slice())
+        .map_err(|_| anyhow!("Decryption failed. Is the password correct?"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decrypt, encrypt};
+
+    #[test]
+    fn encrypt_and_decrypt_succeeds() {
+        let payload = encrypt(b"hello", "password", None).unwrap();
+        assert_eq!(decrypt(&payload, "password").unwrap(), b"hello");
+    }
+
+    #[test]
+    fn decrypt_with_wrong_password_fails() {
+        let payload = encrypt(b"hello", "password", None).unwrap();
+        assert!(decrypt(&payload, "wrong").is_err());
+    }
+}
@@ -1,10 +1,424 @@
+//! Generates KEKs and manages the `KEKS` key ring. Commands that change the key ring print the
+//! new JSON on stdout and a summary with fingerprints, never key bytes, on stderr. Key rings can
+//! also be encrypted for `KEKS_FILE`, under a passphrase or a master key split into shares.
+
 use aes_gcm::{
     Aes256Gcm,
     aead::{KeyInit, OsRng},
 };
+use anyhow::{Context as _, Result, anyhow};
 use base64::prelude::*;
+use cipherly::{
+    kek::{self, KeyEntry, KeyRing, KeyState},
+    shamir::Share,
+};
+use clap::{Parser, Subcommand};
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{self, Read as _, Write as _},
+    os::unix::fs::OpenOptionsExt as _,
+    path::{Path, PathBuf},
+};
+use zeroize::Zeroizing;
+
+#[derive(Parser)]
+#[command(about = "Generate KEKs and manage the KEKS key ring")]
+struct Cli {
+    /// Print a single new KEK if omitted.
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Create a key ring with one new primary KEK.
+    New {
+        #[arg(long, default_value = "v1")]
+        kid: String,
+    },
+    /// Add a new KEK with the next free `vN` kid.
+    Add {
+        /// Also make the new KEK the primary.
+        #[arg(long)]
+        primary: bool,
+        #[command(flatten)]
+        keks: KeksArgs,
+    },
+    /// Make an active KEK the primary that new envelopes are sealed with.
+    Primary {
+        kid: String,
+        #[command(flatten)]
+        keks: KeksArgs,
+    },
+    /// Stop sealing with a KEK, but keep unsealing with it.
+    Retire {
+        kid: String,
+        #[command(flatten)]
+        keks: KeksArgs,
+    },
+    /// Stop unsealing with a KEK. Envelopes sealed with it can no longer be opened.
+    Disable {
+        kid: String,
+        #[command(flatten)]
+        keks: KeksArgs,
+    },
+    /// Check key lengths, duplicate keys and the primary.
+    Validate {
+        #[command(flatten)]
+        keks: KeksArgs,
+    },
+    /// List kids, states and fingerprints.
+    List {
+        #[command(flatten)]
+        keks: KeksArgs,
+    },
+    /// Encrypt the key ring under a passphrase, for `KEKS_FILE`.
+    Encrypt {
+        /// Where to write the encrypted key ring.
+        #[arg(long)]
+        out: PathBuf,
+        #[command(flatten)]
+        passphrase: PassphraseArgs,
+        #[command(flatten)]
+        keks: KeksArgs,
+    },
+    /// Encrypt the key ring under a new master key split into shares, for `KEKS_FILE`.
+    Split {
+        /// How many shares it takes to unseal the key ring.
+        #[arg(long)]
+        threshold: u8,
+        /// How many shares to print, one for each operator.
+        #[arg(long)]
+        shares: u8,
+        /// Where to write the encrypted key ring.
+        #[arg(long)]
+        out: PathBuf,
+        #[command(flatten)]
+        keks: KeksArgs,
+    },
+    /// Print the key ring in an encrypted file as `KEKS` JSON. Prompts for shares if the file is
+    /// split.
+    Decrypt {
+        file: PathBuf,
+        #[command(flatten)]
+        passphrase: PassphraseArgs,
+    },
+    /// Encrypt a key ring file under a new passphrase.
+    Rekey {
+        file: PathBuf,
+        /// Where to write the re-encrypted key ring.
+        #[arg(long)]
+        out: PathBuf,
+        /// Read the current passphrase from this environment variable instead of prompting.
+        #[arg(long, value_name = "VAR")]
+        old_passphrase_env: Option<String>,
+        #[command(flatten)]
+        passphrase: PassphraseArgs,
+    },
+}
+
+#[derive(clap::Args)]
+struct PassphraseArgs {
+    /// Read the passphrase from this environment variable instead of prompting.
+    #[arg(long, value_name = "VAR")]
+    passphrase_env: Option<String>,
+}
+
+impl PassphraseArgs {
+    fn read(&self, confirm: bool) -> Result<Zeroizing<String>> {
+        read_passphrase(self.passphrase_env.as_deref(), "Passphrase: ", confirm)
+    }
+}
+
+fn read_passphrase(var: Option<&str>, prompt: &str, confirm: bool) -> Result<Zeroizing<String>> {
+    if let Some(var) = var {
+        return Ok(Zeroizing::new(
+            env::var(var).with_context(|| format!("{var} is not set"))?,
+        ));
+    }
+    let passphrase = Zeroizing::new(rpassword::prompt_password(prompt)?);
+    if confirm {
+        let confirmation = Zeroizing::new(rpassword::prompt_password("Confirm passphrase: ")?);
+        if confirmation != passphrase {
+            return Err(anyhow!("Passphrases do not match"));
+        }
+    }
+    if passphrase.is_empty() {
+        return Err(anyhow!("Passphrase must not be empty"));
+    }
+    Ok(passphrase)
+}
+
+fn read_shares(threshold: u8) -> Result<Vec<Share>> {
+    (1..=threshold)
+        .map(|i| {
+            let share = Zeroizing::new(rpassword::prompt_password(format!(
+                "Share {i} of {threshold}: "
+            ))?);
+            Share::decode(&share)
+        })
+        .collect()
+}
+
+fn read_file(path: &Path) -> Result<String> {
+    fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))
+}
+
+/// Writes a file only the owner can read, refusing to replace one that already exists.
+fn write_new(path: &Path, data: &str) -> Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(data.as_bytes()))
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[derive(clap::Args)]
+struct KeksArgs {
+    /// The current key ring. Read from stdin if not set.
+    #[arg(long, env = "KEKS", hide_env_values = true)]
+    keks: Option<String>,
+}
+
+impl KeksArgs {
+    fn read(&self) -> Result<KeyRing> {
+        let json = match &self.keks {
+            Some(json) => json.clone(),
+            None => {
+                let mut json = String::new();
+                io::stdin().read_to_string(&mut json)?;
+                json
+            }
+        };
+        KeyRing::from_json(&json).context("Failed to parse KEKs")
+    }
+}
+
+fn generate_kek() -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(Aes256Gcm::generate_key(OsRng))
+}
+
+/// The kid after the highest `vN` in the key ring.
+fn next_kid(key_ring: &KeyRing) -> String {
+    let last = key_ring
+        .keys
+        .keys()
+        .filter_map(|kid| kid.strip_prefix('v')?.parse::<u32>().ok())
+        .max()
+        .unwrap_or(0);
+    format!("v{}", last + 1)
+}
+
+fn set_state(key_ring: &mut KeyRing, kid: &str, state: KeyState) -> Result<()> {
+    if key_ring.primary.as_deref() == Some(kid) {
+        return Err(anyhow!(
+            "{kid} is the primary; make another KEK primary first"
+        ));
+    }
+    key_ring
+        .keys
+        .get_mut(kid)
+        .with_context(|| format!("No KEK {kid}"))?
+        .state = state;
+    Ok(())
+}
+
+fn set_primary(key_ring: &mut KeyRing, kid: &str) -> Result<()> {
+    match key_ring.keys.get(kid) {
+        None => Err(anyhow!("No KEK {kid}")),
+        Some(entry) if entry.state != KeyState::Active => Err(anyhow!(
+            "{kid} is {:?} and can't be the primary",
+            entry.state
+        )),
+        Some(_) => {
+            key_ring.primary = Some(kid.into());
+            Ok(())
+        }
+    }
+}
+
+fn list(key_ring: &KeyRing) -> Result<String> {
+    let raw_keys = key_ring.raw_keys()?;
+    Ok(key_ring
+        .keys
+        .iter()
+        .map(|(kid, entry)| {
+            let primary = if key_ring.primary.as_deref() == Some(kid) {
+                " primary"
+            } else {
+                ""
+            };
+            format!(
+                "{kid}\t{:?}{primary}\t{}\n",
+                entry.state,
+                kek::fingerprint(&raw_keys[kid])
+            )
+        })
+        .collect())
+}
+
+fn main() -> Result<()> {
+    let Some(command) = Cli::parse().command else {
+        println!("New KEK: {}", generate_kek());
+        return Ok(());
+    };
+    let key_ring = match command {
+        Command::New { kid } => KeyRing {
+            primary: Some(kid.clone()),
+            keys: [(
+                kid,
+                KeyEntry {
+                    key: generate_kek(),
+                    state: KeyState::Active,
+                },
+            )]
+            .into(),
+        },
+        Command::Add { primary, keks } => {
+            let mut key_ring = keks.read()?;
+            let kid = next_kid(&key_ring);
+            key_ring.keys.insert(
+                kid.clone(),
+                KeyEntry {
+                    key: generate_kek(),
+                    state: KeyState::Active,
+                },
+            );
+            if primary {
+                set_primary(&mut key_ring, &kid)?;
+            }
+            eprintln!("Added {kid}");
+            key_ring
+        }
+        Command::Primary { kid, keks } => {
+            let mut key_ring = keks.read()?;
+            set_primary(&mut key_ring, &kid)?;
+            key_ring
+        }
+        Command::Retire { kid, keks } => {
+            let mut key_ring = keks.read()?;
+            set_state(&mut key_ring, &kid, KeyState::Retired)?;
+            key_ring
+        }
+        Command::Disable { kid, keks } => {
+            let mut key_ring = keks.read()?;
+            set_state(&mut key_ring, &kid, KeyState::Disabled)?;
+            key_ring
+        }
+        Command::Validate { keks } => {
+            let problems = keks.read()?.validate();
+            for problem in &problems {
+                eprintln!("{problem}");
+            }
+            if !problems.is_empty() {
+                return Err(anyhow!("Key ring has {} problems", problems.len()));
+            }
+            eprintln!("Key ring is valid");
+            return Ok(());
+        }
+        Command::List { keks } => {
+            print!("{}", list(&keks.read()?)?);
+            return Ok(());
+        }
+        Command::Encrypt {
+            out,
+            passphrase,
+            keks,
+        } => {
+            let key_ring = keks.read()?;
+            for problem in key_ring.validate() {
+                eprintln!("Warning: {problem}");
+            }
+            let file = kek::encrypt_key_ring(&key_ring.to_json()?, &passphrase.read(true)?)?;
+            write_new(&out, &file)?;
+            eprint!("{}", list(&key_ring)?);
+            eprintln!("Wrote {}", out.display());
+            return Ok(());
+        }
+        Command::Split {
+            threshold,
+            shares,
+            out,
+            keks,
+        } => {
+            let key_ring = keks.read()?;
+            for problem in key_ring.validate() {
+                eprintln!("Warning: {problem}");
+            }
+            let (file, shares) = kek::split_key_ring(&key_ring.to_json()?, threshold, shares)?;
+            write_new(&out, &file)?;
+            eprint!("{}", list(&key_ring)?);
+            eprintln!(
+                "Wrote {}. Give each share to a different operator:",
+                out.display()
+            );
+            for share in shares {
+                println!("Share {}: {}", share.x, *share.encode());
+            }
+            return Ok(());
+        }
+        Command::Decrypt { file, passphrase } => {
+            let file = read_file(&file)?;
+            let json = match kek::split_threshold(&file)? {
+                Some(threshold) => kek::combine_key_ring(&file, &read_shares(threshold)?)?,
+                None => kek::decrypt_key_ring(&file, &passphrase.read(false)?)?,
+            };
+            println!("{}", *json);
+            return Ok(());
+        }
+        Command::Rekey {
+            file,
+            out,
+            old_passphrase_env,
+            passphrase,
+        } => {
+            let old_passphrase =
+                read_passphrase(old_passphrase_env.as_deref(), "Current passphrase: ", false)?;
+            let json = kek::decrypt_key_ring(&read_file(&file)?, &old_passphrase)?;
+            write_new(
+                &out,
+                &kek::encrypt_key_ring(&json, &passphrase.read(true)?)?,
+            )?;
+            eprintln!("Wrote {}", out.display());
+            return Ok(());
+        }
+    };
+
+    for problem in key_ring.validate() {
+        eprintln!("Warning: {problem}");
+    }
+    eprint!("{}", list(&key_ring)?);
+    println!("{}", key_ring.to_json()?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{next_kid, set_primary, set_state};
+    use cipherly::kek::{KeyRing, KeyState};
+
+    const TEST_KEY_RING: &str = r#"{"primary":"v2","keys":{"v1":{"key":"jRg36ErQ6FLcc7nZgngOpjJnJLGwA3xaMy0yx1pxJrI","state":"retired"},"v2":{"key":"5wasFWpc1thRkR8Wkghn5hZwWF-vimSxIYYZuALL3i8"}}}"#;
+
+    #[test]
+    fn next_kid_follows_highest_version() {
+        let key_ring = KeyRing::from_json(TEST_KEY_RING).unwrap();
+        assert_eq!(next_kid(&key_ring), "v3");
+        assert_eq!(next_kid(&KeyRing::default()), "v1");
+    }
+
+    #[test]
+    fn primary_cannot_be_retired() {
+        let mut key_ring = KeyRing::from_json(TEST_KEY_RING).unwrap();
+        assert!(set_state(&mut key_ring, "v2", KeyState::Retired).is_err());
+    }
 
-fn main() {
-    let key = Aes256Gcm::generate_key(OsRng);
-    println!("New KEK: {}", BASE64_URL_SAFE_NO_PAD.encode(key));
+    #[test]
+    fn retired_key_cannot_be_primary() {
+        let mut key_ring = KeyRing::from_json(TEST_KEY_RING).unwrap();
+        assert!(set_primary(&mut key_ring, "v1").is_err());
+        assert_eq!(key_ring.primary.as_deref(), Some("v2"));
+    }
 }
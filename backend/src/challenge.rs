@@ -0,0 +1,407 @@
+//! An optional proof-of-work gate on anonymous `/api/seal`, so it can't be used as a free
+//! encryption oracle or to exhaust the server.
+//!
+//! `GET /api/challenge` issues a [`Challenge`]: a token that carries its own expiry and
+//! difficulty, under an HMAC so it needs no server state. A client solves it by finding a number
+//! such that the SHA-256 of `<token>:<number>` starts with `difficulty` zero bits, see [`solve`],
+//! and sends `X-Cipherly-Challenge: <token>:<number>` with the seal. Each solution is accepted
+//! once by each server: the solutions used are only kept in its memory, so behind a load balancer
+//! one can be used once on every instance. The difficulty rises by a bit for each doubling of the rate of anonymous seals over a
+//! target, up to [`MAX_EXTRA_DIFFICULTY`] bits more.
+
+use crate::{google, server::ApiError};
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use anyhow::{Context as _, Result, anyhow};
+use axum::{
+    Extension, Json,
+    extract::Request,
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse as _, Response},
+};
+use base64::prelude::*;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    env, fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use zeroize::Zeroizing;
+
+pub const CHALLENGE_HEADER: &str = "x-cipherly-challenge";
+
+/// How long a challenge may be solved and used for.
+const TTL: Duration = Duration::from_secs(120);
+/// How often the rate of anonymous seals is measured.
+const WINDOW: Duration = Duration::from_secs(10);
+/// The most the difficulty rises above the base under load.
+pub const MAX_EXTRA_DIFFICULTY: u8 = 8;
+/// Anonymous seals per [`WINDOW`] before the difficulty rises, by default.
+const DEFAULT_LOAD_TARGET: u32 = 20;
+
+/// The body of `GET /api/challenge`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Challenge {
+    pub token: String,
+    /// The number of leading zero bits the solution's hash needs.
+    pub difficulty: u8,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ChallengeError {
+    /// No `X-Cipherly-Challenge` header.
+    Missing,
+    Malformed,
+    /// The token wasn't issued by this server, or with this key.
+    Forged,
+    Expired,
+    /// The solution was already used.
+    Reused,
+    /// The hash doesn't have enough leading zero bits.
+    Unsolved,
+}
+
+impl fmt::Display for ChallengeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ChallengeError::Missing => "no challenge solution",
+            ChallengeError::Malformed => "challenge solution is malformed",
+            ChallengeError::Forged => "challenge was not issued by this server",
+            ChallengeError::Expired => "challenge has expired",
+            ChallengeError::Reused => "challenge solution was already used",
+            ChallengeError::Unsolved => "challenge is not solved",
+        })
+    }
+}
+
+impl std::error::Error for ChallengeError {}
+
+/// Issues and checks challenges.
+pub struct Challenger {
+    key: Zeroizing<Vec<u8>>,
+    base_difficulty: u8,
+    load_target: u32,
+    load: Mutex<Load>,
+    /// Tokens of accepted solutions, with when they expire. Not shared with other servers.
+    used: Mutex<HashMap<String, u64>>,
+}
+
+/// Anonymous seals in the current and the last complete [`WINDOW`].
+struct Load {
+    start: Instant,
+    current: u32,
+    last: u32,
+}
+
+const EXPIRES_LEN: usize = 8;
+const NONCE_LEN: usize = 16;
+/// Expiry, difficulty and a random nonce.
+const PAYLOAD_LEN: usize = EXPIRES_LEN + 1 + NONCE_LEN;
+const MAC_LEN: usize = 32;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The number of leading zero bits of `hash`.
+fn leading_zeros(hash: &[u8]) -> u32 {
+    let mut zeros = 0;
+    for byte in hash {
+        zeros += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    zeros
+}
+
+fn solution_hash(token: &str, solution: u64) -> [u8; 32] {
+    Sha256::digest(format!("{token}:{solution}")).into()
+}
+
+/// Finds a solution to `challenge`, taking about 2^difficulty hashes. Blocks, so run it on the
+/// blocking thread pool in async code.
+pub fn solve(challenge: &Challenge) -> u64 {
+    (0..)
+        .find(|&solution| {
+            leading_zeros(&solution_hash(&challenge.token, solution)) >= challenge.difficulty.into()
+        })
+        .expect("some u64 solves any challenge of up to 256 bits")
+}
+
+impl Challenger {
+    /// Issues challenges of at least `base_difficulty` bits, signed with `key`. Servers that
+    /// share a key accept each other's challenges.
+    pub fn new(key: &[u8], base_difficulty: u8) -> Challenger {
+        Challenger {
+            key: Zeroizing::new(key.into()),
+            base_difficulty,
+            load_target: DEFAULT_LOAD_TARGET,
+            load: Mutex::new(Load {
+                start: Instant::now(),
+                current: 0,
+                last: 0,
+            }),
+            used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Raises the difficulty once more than `seals` anonymous seals arrive within 10 seconds.
+    pub fn with_load_target(mut self, seals: u32) -> Challenger {
+        self.load_target = seals.max(1);
+        self
+    }
+
+    /// Reads `SEAL_CHALLENGE`, the base difficulty in bits, and `SEAL_CHALLENGE_KEY`, a base64url
+    /// HMAC key to share between servers. A random key is used if the latter is unset. Challenges
+    /// are disabled when `SEAL_CHALLENGE` is unset.
+    pub fn from_env() -> Result<Option<Challenger>> {
+        let Ok(difficulty) = env::var("SEAL_CHALLENGE") else {
+            return Ok(None);
+        };
+        let difficulty: u8 = difficulty
+            .parse()
+            .ok()
+            .filter(|difficulty| *difficulty <= 32)
+            .context("SEAL_CHALLENGE should be a number of bits up to 32")?;
+        let key = match env::var("SEAL_CHALLENGE_KEY") {
+            Ok(key) => Zeroizing::new(
+                BASE64_URL_SAFE_NO_PAD
+                    .decode(key)
+                    .context("SEAL_CHALLENGE_KEY should be base64url")?,
+            ),
+            Err(_) => {
+                let mut key = Zeroizing::new(vec![0; 32]);
+                OsRng.fill_bytes(&mut key);
+                key
+            }
+        };
+        if key.len() < 32 {
+            return Err(anyhow!("SEAL_CHALLENGE_KEY should be at least 32 bytes"));
+        }
+        tracing::info!("Requiring {difficulty}-bit challenges for anonymous seals");
+        Ok(Some(Challenger::new(&key, difficulty)))
+    }
+
+    /// The difficulty for the current load.
+    pub fn difficulty(&self) -> u8 {
+        let load = self.load.lock().unwrap_or_else(|err| err.into_inner());
+        let seals = load.current.max(load.last);
+        let mut extra = 0;
+        while extra < MAX_EXTRA_DIFFICULTY && seals > self.load_target.saturating_mul(1 << extra) {
+            extra += 1;
+        }
+        self.base_difficulty.saturating_add(extra).min(32)
+    }
+
+    pub fn issue(&self) -> Challenge {
+        self.issue_expiring(now() + TTL.as_secs())
+    }
+
+    fn issue_expiring(&self, expires: u64) -> Challenge {
+        let difficulty = self.difficulty();
+        let mut payload = [0; PAYLOAD_LEN];
+        payload[..EXPIRES_LEN].copy_from_slice(&expires.to_be_bytes());
+        payload[EXPIRES_LEN] = difficulty;
+        OsRng.fill_bytes(&mut payload[EXPIRES_LEN + 1..]);
+        let mac = self.mac(&payload).finalize().into_bytes();
+        Challenge {
+            token: BASE64_URL_SAFE_NO_PAD.encode([&payload[..], &mac].concat()),
+            difficulty,
+        }
+    }
+
+    /// Checks a `<token>:<solution>` header value, and uses the solution up.
+    pub fn verify(&self, header: &str) -> Result<(), ChallengeError> {
+        let (token, solution) = header.split_once(':').ok_or(ChallengeError::Malformed)?;
+        let solution: u64 = solution.parse().map_err(|_| ChallengeError::Malformed)?;
+        let data = BASE64_URL_SAFE_NO_PAD
+            .decode(token)
+            .ok()
+            .filter(|data| data.len() == PAYLOAD_LEN + MAC_LEN)
+            .ok_or(ChallengeError::Malformed)?;
+        let (payload, mac) = data.split_at(PAYLOAD_LEN);
+        self.mac(payload)
+            .verify_slice(mac)
+            .map_err(|_| ChallengeError::Forged)?;
+        let expires = u64::from_be_bytes(payload[..EXPIRES_LEN].try_into().unwrap());
+        let now = now();
+        if expires < now {
+            return Err(ChallengeError::Expired);
+        }
+        let difficulty = payload[EXPIRES_LEN];
+        if leading_zeros(&solution_hash(token, solution)) < difficulty.into() {
+            return Err(ChallengeError::Unsolved);
+        }
+        let mut used = self.used.lock().unwrap_or_else(|err| err.into_inner());
+        used.retain(|_, expires| *expires >= now);
+        if used.insert(token.into(), expires).is_some() {
+            return Err(ChallengeError::Reused);
+        }
+        Ok(())
+    }
+
+    /// Counts an anonymous seal with a solved challenge towards the load.
+    fn record_seal(&self) {
+        let mut load = self.load.lock().unwrap_or_else(|err| err.into_inner());
+        let elapsed = load.start.elapsed();
+        if elapsed >= WINDOW {
+            // A window with no seals in it leaves nothing to carry over.
+            load.last = if elapsed >= 2 * WINDOW {
+                0
+            } else {
+                load.current
+            };
+            load.current = 0;
+            load.start = Instant::now();
+        }
+        load.current += 1;
+    }
+
+    fn mac(&self, payload: &[u8]) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(b"cipherly seal challenge\n");
+        mac.update(payload);
+        mac
+    }
+}
+
+/// Lets anonymous requests through only with a solved challenge, responding with 428 if there's
+/// none and 403 if it's wrong, with a JSON error body saying which. Signed-in requests skip the challenge, so put
+/// [`google::authenticate_optional`] in front of it. Needs an `Arc<Challenger>` extension.
+pub async fn require_solution(
+    Extension(challenger): Extension<Arc<Challenger>>,
+    claims: Option<Extension<google::Claims>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if claims.is_some() {
+        return next.run(request).await;
+    }
+    let result = match request.headers().get(CHALLENGE_HEADER) {
+        None => Err(ChallengeError::Missing),
+        Some(header) => header
+            .to_str()
+            .map_err(|_| ChallengeError::Malformed)
+            .and_then(|header| challenger.verify(header)),
+    };
+    match result {
+        // Only solved challenges count towards the load, so requests that cost nothing to send
+        // can't make the challenge harder for everyone else.
+        Ok(()) => {
+            challenger.record_seal();
+            next.run(request).await
+        }
+        Err(err) => {
+            let forbidden = StatusCode::FORBIDDEN;
+            let (status, error) = match err {
+                ChallengeError::Missing => {
+                    (StatusCode::PRECONDITION_REQUIRED, "Challenge Required")
+                }
+                ChallengeError::Malformed => (forbidden, "Malformed Challenge"),
+                ChallengeError::Forged => (forbidden, "Forged Challenge"),
+                ChallengeError::Expired => (forbidden, "Expired Challenge"),
+                ChallengeError::Reused => (forbidden, "Reused Challenge"),
+                ChallengeError::Unsolved => (forbidden, "Unsolved Challenge"),
+            };
+            if status == forbidden {
+                tracing::info!("Rejected seal: {err}");
+            }
+            (status, Json(ApiError { error })).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Challenge, ChallengeError, Challenger, MAX_EXTRA_DIFFICULTY, leading_zeros, now,
+        solution_hash, solve,
+    };
+    use base64::prelude::*;
+
+    const KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn solved(challenge: &Challenge) -> String {
+        format!("{}:{}", challenge.token, solve(challenge))
+    }
+
+    #[test]
+    fn accepts_each_solution_once() {
+        let challenger = Challenger::new(KEY, 8);
+        let challenge = challenger.issue();
+        assert_eq!(challenge.difficulty, 8);
+        let solution = solved(&challenge);
+        assert_eq!(challenger.verify(&solution), Ok(()));
+        assert_eq!(challenger.verify(&solution), Err(ChallengeError::Reused));
+    }
+
+    #[test]
+    fn solve_matches_frontend() {
+        // Shared with solveChallenge in frontend/src/lib/cipherly.test.ts.
+        let challenge = Challenge {
+            token: "test".into(),
+            difficulty: 12,
+        };
+        assert_eq!(solve(&challenge), 1963);
+    }
+
+    #[test]
+    fn rejects_bad_solutions() {
+        let challenger = Challenger::new(KEY, 12);
+        let challenge = challenger.issue();
+        let unsolved = (0..)
+            .find(|&solution| leading_zeros(&solution_hash(&challenge.token, solution)) < 12)
+            .unwrap();
+        assert_eq!(
+            challenger.verify(&format!("{}:{unsolved}", challenge.token)),
+            Err(ChallengeError::Unsolved)
+        );
+
+        let expired = solved(&challenger.issue_expiring(now() - 1));
+        assert_eq!(challenger.verify(&expired), Err(ChallengeError::Expired));
+
+        let other = Challenger::new(&[1; 32], 12);
+        let forged = solved(&other.issue());
+        assert_eq!(challenger.verify(&forged), Err(ChallengeError::Forged));
+
+        // Lowering the difficulty in the token breaks its MAC.
+        let mut data = BASE64_URL_SAFE_NO_PAD.decode(&challenge.token).unwrap();
+        data[8] = 0;
+        let easier = Challenge {
+            token: BASE64_URL_SAFE_NO_PAD.encode(data),
+            difficulty: 0,
+        };
+        assert_eq!(
+            challenger.verify(&solved(&easier)),
+            Err(ChallengeError::Forged)
+        );
+
+        for header in ["", "token", "token:1", &format!("{}:x", challenge.token)] {
+            assert_eq!(challenger.verify(header), Err(ChallengeError::Malformed));
+        }
+    }
+
+    #[test]
+    fn difficulty_rises_with_load() {
+        let challenger = Challenger::new(KEY, 10).with_load_target(2);
+        let difficulties: Vec<_> = (0..10)
+            .map(|_| {
+                challenger.record_seal();
+                challenger.difficulty()
+            })
+            .collect();
+        assert_eq!(difficulties, [10, 10, 11, 11, 12, 12, 12, 12, 13, 13]);
+        for _ in 0..1000 {
+            challenger.record_seal();
+        }
+        assert_eq!(challenger.difficulty(), 10 + MAX_EXTRA_DIFFICULTY);
+    }
+}
@@ -0,0 +1,514 @@
+//! An async client for `/api/seal`, `/api/unseal`, `/api/envelopes`, `/api/blobs` and
+//! `/api/streams`.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), cipherly::client::Error> {
+//! use cipherly::{client::Client, envelope::Envelope};
+//!
+//! let client = Client::new("https://cipherly.example");
+//! let sealed = client
+//!     .seal(&Envelope {
+//!         dek: "...".into(),
+//!         emails: vec!["oncall@example.com".into()],
+//!         max_unseals: None,
+//!         id: None,
+//!     })
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    audit::AuditEvent,
+    challenge::{self, CHALLENGE_HEADER, Challenge},
+    envelope::{Envelope, InvalidEnvelope, SealedEnvelope, Violation},
+};
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, de::DeserializeOwned};
+use std::{fmt, time::Duration};
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+#[derive(Debug)]
+pub enum Error {
+    /// The ID token was rejected, or its holder is not a recipient of the envelope.
+    Unauthorized { request_id: Option<String> },
+    /// The server refused to seal the envelope, for each of `violations`.
+    InvalidEnvelope {
+        violations: Vec<Violation>,
+        request_id: Option<String>,
+    },
+    /// The server responded with an unexpected status.
+    Status {
+        status: StatusCode,
+        request_id: Option<String>,
+    },
+    /// The server couldn't be reached or sent a malformed response.
+    Transport(reqwest::Error),
+}
+
+impl Error {
+    /// The server's request ID, for finding the request in its logs.
+    pub fn request_id(&self) -> Option<&str> {
+        match self {
+            Error::Unauthorized { request_id }
+            | Error::InvalidEnvelope { request_id, .. }
+            | Error::Status { request_id, .. } => request_id.as_deref(),
+            Error::Transport(_) => None,
+        }
+    }
+
+    fn is_retryable(&self, retry: Retry) -> bool {
+        if let Retry::Unsent = retry {
+            return matches!(self, Error::Transport(err) if err.is_connect());
+        }
+        match self {
+            Error::Unauthorized { .. } | Error::InvalidEnvelope { .. } => false,
+            Error::Status { status, .. } => {
+                status.is_server_error()
+                    || *status == StatusCode::REQUEST_TIMEOUT
+                    || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            Error::Transport(err) => err.is_connect() || err.is_timeout(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Unauthorized { .. } => write!(f, "not authorized to unseal the envelope")?,
+            Error::InvalidEnvelope { violations, .. } => {
+                write!(f, "invalid envelope")?;
+                for (i, violation) in violations.iter().enumerate() {
+                    write!(f, "{} {violation}", if i == 0 { ":" } else { ";" })?;
+                }
+            }
+            Error::Status { status, .. } => write!(f, "server responded with {status}")?,
+            Error::Transport(err) => write!(f, "request failed: {err}")?,
+        }
+        match self.request_id() {
+            Some(request_id) => write!(f, " (request ID {request_id})"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Transport(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::Transport(err)
+    }
+}
+
+/// Which failures a request is retried after.
+#[derive(Clone, Copy)]
+enum Retry {
+    /// Any that may be transient. For requests that can safely be made twice.
+    Transient,
+    /// Only failures to connect, when the server never got the request. For requests that use up
+    /// a view, where a retry after a lost response would use up another.
+    Unsent,
+}
+
+/// Seals and unseals envelopes. Cloning is cheap and shares the connection pool.
+#[derive(Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    max_retries: u32,
+    retry_backoff: Duration,
+    request_id: Option<String>,
+}
+
+impl Client {
+    pub fn new(base_url: impl Into<String>) -> Client {
+        Client::with_http_client(reqwest::Client::new(), base_url)
+    }
+
+    /// Uses a preconfigured HTTP client, e.g. one with proxies or timeouts set.
+    pub fn with_http_client(http: reqwest::Client, base_url: impl Into<String>) -> Client {
+        Client {
+            http,
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            max_retries: 2,
+            retry_backoff: Duration::from_millis(200),
+            request_id: None,
+        }
+    }
+
+    /// Retries connection failures, timeouts and 5xx responses up to `max_retries` times,
+    /// doubling the wait from `backoff` each time. Defaults to 2 retries from 200ms. Unseals and
+    /// blob fetches, which use up views, are only retried when they couldn't connect.
+    pub fn with_retries(mut self, max_retries: u32, backoff: Duration) -> Client {
+        self.max_retries = max_retries;
+        self.retry_backoff = backoff;
+        self
+    }
+
+    /// Sends `request_id` as `x-request-id` so the server's logs line up with the caller's.
+    pub fn with_request_id(&self, request_id: impl Into<String>) -> Client {
+        Client {
+            request_id: Some(request_id.into()),
+            ..self.clone()
+        }
+    }
+
+    /// Has the server seal `envelope` for its recipients, solving a challenge first if the server
+    /// asks for one.
+    pub async fn seal(&self, envelope: &Envelope) -> Result<SealedEnvelope, Error> {
+        match self
+            .send(|| self.http.post(self.url("/api/seal")).json(envelope))
+            .await
+        {
+            Err(Error::Status {
+                status: StatusCode::PRECONDITION_REQUIRED,
+                ..
+            }) => {}
+            result => return result,
+        }
+        let challenge: Challenge = self
+            .send(|| self.http.get(self.url("/api/challenge")))
+            .await?;
+        let solution = tokio::task::spawn_blocking(move || {
+            let solution = challenge::solve(&challenge);
+            format!("{}:{solution}", challenge.token)
+        })
+        .await
+        .expect("solving a challenge doesn't panic");
+        self.send(|| {
+            self.http
+                .post(self.url("/api/seal"))
+                .header(CHALLENGE_HEADER, &solution)
+                .json(envelope)
+        })
+        .await
+    }
+
+    /// Like [`Client::seal`], but as the holder of `id_token`, who can then [`Client::revoke`]
+    /// the envelope by the returned `id`. Needs a server with a blob store.
+    pub async fn seal_as(
+        &self,
+        envelope: &Envelope,
+        id_token: &str,
+    ) -> Result<SealedEnvelope, Error> {
+        self.send(|| {
+            self.http
+                .post(self.url("/api/seal"))
+                .bearer_auth(id_token)
+                .json(envelope)
+        })
+        .await
+    }
+
+    /// Revokes an envelope sealed by the holder of `id_token` with [`Client::seal_as`], so it
+    /// can no longer be unsealed.
+    pub async fn revoke(&self, id: &str, id_token: &str) -> Result<(), Error> {
+        self.send_raw(|| {
+            self.http
+                .delete(self.url(&format!("/api/envelopes/{id}")))
+                .bearer_auth(id_token)
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Lists the attempts to unseal an envelope sealed by the holder of `id_token`, oldest first.
+    /// Needs a server with an audit log.
+    pub async fn access_history(&self, id: &str, id_token: &str) -> Result<Vec<AuditEvent>, Error> {
+        self.send(|| {
+            self.http
+                .get(self.url(&format!("/api/envelopes/{id}/access")))
+                .bearer_auth(id_token)
+        })
+        .await
+    }
+
+    /// Has the server unseal `sealed` for the holder of `id_token`.
+    pub async fn unseal(&self, sealed: &SealedEnvelope, id_token: &str) -> Result<Envelope, Error> {
+        self.send_with(Retry::Unsent, || {
+            self.http
+                .post(self.url("/api/unseal"))
+                .bearer_auth(id_token)
+                .json(sealed)
+        })
+        .await
+    }
+
+    /// Stores an already-encrypted blob, returning its id. The server deletes it after
+    /// `max_views` fetches, if set. Needs a server with a blob store.
+    pub async fn upload_blob(&self, blob: &[u8], max_views: Option<u32>) -> Result<String, Error> {
+        #[derive(Deserialize)]
+        struct BlobId {
+            id: String,
+        }
+        let BlobId { id } = self
+            .send(|| {
+                let path = match max_views {
+                    Some(max_views) => format!("/api/blobs?max_views={max_views}"),
+                    None => "/api/blobs".into(),
+                };
+                self.http.post(self.url(&path)).body(blob.to_vec())
+            })
+            .await?;
+        Ok(id)
+    }
+
+    /// Fetches a blob stored with [`Client::upload_blob`], counting as one of its views.
+    pub async fn fetch_blob(&self, id: &str) -> Result<Vec<u8>, Error> {
+        let response = self
+            .send_raw_with(Retry::Unsent, || {
+                self.http.get(self.url(&format!("/api/blobs/{id}")))
+            })
+            .await?;
+        Ok(response.bytes().await?.into())
+    }
+
+    /// Uploads a stream made by [`crate::stream::Encryptor`] as `body` produces it, returning its
+    /// id. It can't be replayed, so it isn't retried. Needs a server with a stream store.
+    pub async fn upload_stream(&self, body: reqwest::Body) -> Result<String, Error> {
+        #[derive(Deserialize)]
+        struct StreamId {
+            id: String,
+        }
+        let mut builder = self.http.post(self.url("/api/streams")).body(body);
+        if let Some(request_id) = &self.request_id {
+            builder = builder.header(REQUEST_ID_HEADER, request_id);
+        }
+        let StreamId { id } = Self::check(builder.send().await).await?.json().await?;
+        Ok(id)
+    }
+
+    /// Starts downloading a stream uploaded with [`Client::upload_stream`]. Read it a piece at a
+    /// time with [`Response::chunk`].
+    pub async fn download_stream(&self, id: &str) -> Result<Response, Error> {
+        self.send_raw(|| self.http.get(self.url(&format!("/api/streams/{id}"))))
+            .await
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.base_url)
+    }
+
+    async fn send<T: DeserializeOwned>(
+        &self,
+        request: impl Fn() -> RequestBuilder,
+    ) -> Result<T, Error> {
+        self.send_with(Retry::Transient, request).await
+    }
+
+    async fn send_with<T: DeserializeOwned>(
+        &self,
+        retry: Retry,
+        request: impl Fn() -> RequestBuilder,
+    ) -> Result<T, Error> {
+        Ok(self.send_raw_with(retry, request).await?.json().await?)
+    }
+
+    async fn send_raw(&self, request: impl Fn() -> RequestBuilder) -> Result<Response, Error> {
+        self.send_raw_with(Retry::Transient, request).await
+    }
+
+    async fn send_raw_with(
+        &self,
+        retry: Retry,
+        request: impl Fn() -> RequestBuilder,
+    ) -> Result<Response, Error> {
+        let mut backoff = self.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            let mut builder = request();
+            if let Some(request_id) = &self.request_id {
+                builder = builder.header(REQUEST_ID_HEADER, request_id);
+            }
+            match Self::check(builder.send().await).await {
+                Err(err) if attempt < self.max_retries && err.is_retryable(retry) => {
+                    tracing::debug!("Retrying after {err}");
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn check(response: reqwest::Result<Response>) -> Result<Response, Error> {
+        let response = response?;
+        let request_id = response
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(Into::into);
+        match response.status() {
+            status if status.is_success() => Ok(response),
+            StatusCode::UNAUTHORIZED => Err(Error::Unauthorized { request_id }),
+            // Other 400s, e.g. from a server without seal policies, have no violations.
+            StatusCode::BAD_REQUEST => match response.json::<InvalidEnvelope>().await {
+                Ok(InvalidEnvelope { violations, .. }) => Err(Error::InvalidEnvelope {
+                    violations,
+                    request_id,
+                }),
+                Err(_) => Err(Error::Status {
+                    status: StatusCode::BAD_REQUEST,
+                    request_id,
+                }),
+            },
+            status => Err(Error::Status { status, request_id }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Client, Error};
+    use crate::envelope::{Envelope, SealedEnvelope};
+    use axum::{
+        Json, Router,
+        extract::State,
+        http::{HeaderMap, StatusCode},
+        response::IntoResponse,
+        routing::post,
+    };
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
+    use tokio::net::TcpListener;
+
+    fn envelope() -> Envelope {
+        Envelope {
+            dek: "dek".into(),
+            emails: vec!["alice@email.com".into()],
+            max_unseals: None,
+            id: None,
+        }
+    }
+
+    /// Fails the first `failures` seals with a 503, echoing the request ID on every response.
+    async fn start_fake_server(failures: usize) -> (String, Arc<AtomicUsize>) {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route(
+                "/api/seal",
+                post(
+                    move |State(attempts): State<Arc<AtomicUsize>>,
+                          headers: HeaderMap,
+                          Json(envelope): Json<Envelope>| async move {
+                        let request_id = headers.get("x-request-id").cloned();
+                        let mut response = if attempts.fetch_add(1, Ordering::SeqCst) < failures {
+                            StatusCode::SERVICE_UNAVAILABLE.into_response()
+                        } else {
+                            Json(SealedEnvelope {
+                                kid: "v1".into(),
+                                nonce: "nonce".into(),
+                                data: envelope.dek.clone(),
+                                id: None,
+                            })
+                            .into_response()
+                        };
+                        if let Some(request_id) = request_id {
+                            response.headers_mut().insert("x-request-id", request_id);
+                        }
+                        response
+                    },
+                ),
+            )
+            .route(
+                "/api/unseal",
+                post(|| async { ([("x-request-id", "server-id")], StatusCode::UNAUTHORIZED) }),
+            )
+            .with_state(attempts.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (format!("http://{addr}/"), attempts)
+    }
+
+    #[tokio::test]
+    async fn seal_retries_server_errors() {
+        let (base_url, attempts) = start_fake_server(2).await;
+        let client = Client::new(base_url).with_retries(2, Duration::from_millis(1));
+
+        let sealed = client.seal(&envelope()).await.unwrap();
+        assert_eq!(sealed.data, "dek");
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn seal_fails_when_retries_run_out() {
+        let (base_url, attempts) = start_fake_server(2).await;
+        let client = Client::new(base_url)
+            .with_retries(1, Duration::from_millis(1))
+            .with_request_id("caller-id");
+
+        let err = client.seal(&envelope()).await.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Status {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                ..
+            }
+        ));
+        assert_eq!(err.request_id(), Some("caller-id"));
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn unseal_maps_unauthorized() {
+        let (base_url, _) = start_fake_server(0).await;
+        let client = Client::new(base_url);
+
+        let sealed = client.seal(&envelope()).await.unwrap();
+        let err = client.unseal(&sealed, "token").await.unwrap_err();
+        assert!(matches!(err, Error::Unauthorized { .. }));
+        assert_eq!(err.request_id(), Some("server-id"));
+    }
+
+    #[tokio::test]
+    async fn unseal_is_not_retried_after_server_errors() {
+        // The server may have used up a view of a limited envelope before failing.
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route(
+                "/api/unseal",
+                post(|State(attempts): State<Arc<AtomicUsize>>| async move {
+                    attempts.fetch_add(1, Ordering::SeqCst);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }),
+            )
+            .with_state(attempts.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let client =
+            Client::new(format!("http://{addr}")).with_retries(2, Duration::from_millis(1));
+
+        let sealed = SealedEnvelope {
+            kid: "v1".into(),
+            nonce: "nonce".into(),
+            data: "data".into(),
+            id: Some("AAAAAAAAAAAAAAAA".into()),
+        };
+        let err = client.unseal(&sealed, "token").await.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Status {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                ..
+            }
+        ));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,106 @@
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{HeaderValue, Method, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use base64::prelude::*;
+use rust_embed::{EmbeddedFile, RustEmbed};
+
+/// The built frontend, embedded at compile time. Run `pnpm build` in `frontend` first. A missing
+/// build embeds nothing, so `cargo clippy --all-features` works without one.
+#[derive(RustEmbed)]
+#[folder = "../frontend/build"]
+#[allow_missing = true]
+struct Assets;
+
+/// Whether a frontend build was embedded, rather than nothing for lack of one.
+pub fn has_frontend() -> bool {
+    Assets::get("index.html").is_some()
+}
+
+/// Serves the embedded frontend the same way `ServeDir` serves `static`: precompressed
+/// variants when the client accepts them, and `index.html` for unknown paths.
+pub async fn serve(request: Request) -> Response {
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+        return StatusCode::METHOD_NOT_ALLOWED.into_response();
+    }
+
+    let path = request.uri().path().trim_start_matches('/');
+    let path = if path.is_empty() || path.ends_with('/') {
+        format!("{path}index.html")
+    } else {
+        path.to_string()
+    };
+    let (path, file) = match Assets::get(&path) {
+        Some(file) => (path, file),
+        None => match Assets::get("index.html") {
+            Some(file) => ("index.html".to_string(), file),
+            None => return StatusCode::NOT_FOUND.into_response(),
+        },
+    };
+
+    let accept_encoding = request
+        .headers()
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let (encoding, body) = ["br", "gzip"]
+        .into_iter()
+        .filter(|encoding| accepts(accept_encoding, encoding))
+        .find_map(|encoding| {
+            let ext = if encoding == "gzip" { "gz" } else { encoding };
+            Assets::get(&format!("{path}.{ext}")).map(|file| (Some(encoding), file))
+        })
+        .unwrap_or((None, file.clone()));
+
+    let etag = etag(&body);
+    if request
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|value| value.as_bytes() == etag.as_bytes())
+    {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+    }
+
+    let mut response = Response::builder()
+        .header(header::CONTENT_TYPE, file.metadata.mimetype())
+        .header(header::ETAG, etag)
+        .header(header::VARY, "accept-encoding");
+    if let Some(encoding) = encoding {
+        response = response.header(header::CONTENT_ENCODING, encoding);
+    }
+    let body = if request.method() == Method::HEAD {
+        Body::empty()
+    } else {
+        Body::from(body.data)
+    };
+    response.body(body).unwrap()
+}
+
+fn accepts(accept_encoding: &str, encoding: &str) -> bool {
+    accept_encoding.split(',').any(|value| {
+        let mut parts = value.split(';');
+        parts.next().map(str::trim) == Some(encoding)
+            && !parts.any(|param| param.trim().replace(' ', "") == "q=0")
+    })
+}
+
+fn etag(file: &EmbeddedFile) -> HeaderValue {
+    let hash = BASE64_URL_SAFE_NO_PAD.encode(file.metadata.sha256_hash());
+    HeaderValue::try_from(format!("\"{hash}\"")).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::accepts;
+
+    #[test]
+    fn accepts_parses_accept_encoding() {
+        assert!(accepts("gzip, deflate, br", "br"));
+        assert!(accepts("gzip;q=0.5, br;q=1.0", "gzip"));
+        assert!(!accepts("gzip;q=0, br", "gzip"));
+        assert!(!accepts("deflate", "gzip"));
+        assert!(!accepts("", "br"));
+    }
+}
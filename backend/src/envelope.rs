@@ -0,0 +1,402 @@
+//! Request and response bodies of `/api/seal` and `/api/unseal`, and the checks `/api/seal`
+//! makes of envelopes.
+
+use anyhow::{Context as _, Result};
+use base64::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, env, fmt};
+use zeroize::{Zeroize, Zeroizing};
+
+/// A data encryption key and the email addresses allowed to unseal it. The key is base64url
+/// encoded without padding, is wiped from memory on drop, and is left out of `Debug` output.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    pub dek: String,
+    pub emails: Vec<String>,
+    /// How many times the envelope may be unsealed before the server forgets it. Needs a
+    /// server with a blob store.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_unseals: Option<u32>,
+    /// A random id the server assigns when sealing, to count unseals and revoke the envelope.
+    /// Envelopes sealed before ids were added have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+}
+
+impl fmt::Debug for Envelope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Envelope")
+            .field("dek", &"<redacted>")
+            .field("emails", &self.emails)
+            .field("max_unseals", &self.max_unseals)
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+impl Drop for Envelope {
+    fn drop(&mut self) {
+        self.dek.zeroize();
+    }
+}
+
+/// An [`Envelope`] encrypted under the server's key encryption key `kid`. The nonce and data are
+/// base64url encoded without padding.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SealedEnvelope {
+    pub kid: String,
+    pub nonce: String,
+    pub data: String,
+    /// The envelope's id, returned by `/api/seal` so the sender can revoke it. It isn't part of
+    /// the payload; the server only trusts the copy inside `data`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+}
+
+/// The most recipients an envelope may have by default.
+pub const DEFAULT_MAX_RECIPIENTS: usize = 100;
+
+/// What `/api/seal` accepts beyond a well-formed envelope.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SealPolicy {
+    pub max_recipients: usize,
+    /// Domains recipients must be at, e.g. `example.com`, or `*.example.com` for any subdomain
+    /// of it. Any domain is allowed if empty.
+    pub allowed_domains: Vec<String>,
+}
+
+impl Default for SealPolicy {
+    fn default() -> Self {
+        SealPolicy {
+            max_recipients: DEFAULT_MAX_RECIPIENTS,
+            allowed_domains: Vec::new(),
+        }
+    }
+}
+
+impl SealPolicy {
+    /// Reads `SEAL_MAX_RECIPIENTS` and `SEAL_ALLOWED_DOMAINS`, a comma-separated list, falling
+    /// back to the defaults for unset variables.
+    pub fn from_env() -> Result<SealPolicy> {
+        let mut policy = SealPolicy::default();
+        if let Ok(max_recipients) = env::var("SEAL_MAX_RECIPIENTS") {
+            policy.max_recipients = max_recipients
+                .parse()
+                .ok()
+                .filter(|max_recipients| *max_recipients > 0)
+                .context("SEAL_MAX_RECIPIENTS should be a positive number")?;
+        }
+        if let Ok(domains) = env::var("SEAL_ALLOWED_DOMAINS") {
+            policy.allowed_domains = domains
+                .split(',')
+                .map(|domain| domain.trim().to_ascii_lowercase())
+                .filter(|domain| !domain.is_empty())
+                .collect();
+        }
+        Ok(policy)
+    }
+
+    /// Checks `envelope` against the policy, and drops recipients listed more than once, in any
+    /// case. Reports every problem found rather than only the first, with the indices the sender
+    /// used.
+    pub fn check(&self, envelope: &mut Envelope) -> Result<(), Vec<Violation>> {
+        let mut violations = Vec::new();
+        let dek = BASE64_URL_SAFE_NO_PAD
+            .decode(&envelope.dek)
+            .map(Zeroizing::new);
+        if !dek.is_ok_and(|dek| dek.len() == 32) {
+            violations.push(Violation::new(
+                "dek",
+                ViolationCode::InvalidDek,
+                "DEK should be 32 bytes, base64url encoded without padding",
+            ));
+        }
+        if envelope.max_unseals == Some(0) {
+            violations.push(Violation::new(
+                "max_unseals",
+                ViolationCode::InvalidMaxUnseals,
+                "max_unseals should be at least 1",
+            ));
+        }
+
+        // Addresses are checked where the sender put them, so each field names the right one.
+        let mut email_violations = Vec::new();
+        for (i, email) in envelope.emails.iter().enumerate() {
+            let field = format!("emails[{i}]");
+            match email_domain(email) {
+                None => email_violations.push(Violation::new(
+                    field,
+                    ViolationCode::InvalidEmail,
+                    format!("{email:?} is not an email address"),
+                )),
+                Some(domain) if !self.allows(domain) => email_violations.push(Violation::new(
+                    field,
+                    ViolationCode::DisallowedDomain,
+                    format!("recipients at {domain} are not allowed"),
+                )),
+                Some(_) => {}
+            }
+        }
+
+        // Unseal matches addresses ignoring case, so duplicates are found the same way.
+        let mut seen = HashSet::new();
+        envelope
+            .emails
+            .retain(|email| seen.insert(email.to_ascii_lowercase()));
+        if envelope.emails.is_empty() {
+            violations.push(Violation::new(
+                "emails",
+                ViolationCode::NoRecipients,
+                "at least one recipient is needed",
+            ));
+        }
+        if envelope.emails.len() > self.max_recipients {
+            violations.push(Violation::new(
+                "emails",
+                ViolationCode::TooManyRecipients,
+                format!("at most {} recipients are allowed", self.max_recipients),
+            ));
+        }
+        violations.extend(email_violations);
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+
+    /// Domains are compared ignoring case, however `allowed_domains` was set.
+    fn allows(&self, domain: &str) -> bool {
+        let domain = domain.to_ascii_lowercase();
+        self.allowed_domains.is_empty()
+            || self
+                .allowed_domains
+                .iter()
+                .map(|allowed| allowed.to_ascii_lowercase())
+                .any(|allowed| match allowed.strip_prefix("*.") {
+                    Some(parent) => domain
+                        .strip_suffix(parent)
+                        .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+                    None => domain == allowed,
+                })
+    }
+}
+
+/// The domain of `email` if it's a plausible address: a local part of printable ASCII without
+/// the characters that need quoting, and a domain of at least two dot-separated labels.
+fn email_domain(email: &str) -> Option<&str> {
+    let (local, domain) = email.split_once('@')?;
+    let local_ok = (1..=64).contains(&local.len())
+        && local
+            .chars()
+            .all(|c| c.is_ascii_graphic() && !"()<>[]\\,;:@\"".contains(c))
+        && !local.starts_with('.')
+        && !local.ends_with('.')
+        && !local.contains("..");
+    let labels: Vec<_> = domain.split('.').collect();
+    let domain_ok = domain.len() <= 253
+        && labels.len() >= 2
+        && labels.iter().all(|label| {
+            (1..=63).contains(&label.len())
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+        });
+    (local_ok && domain_ok).then_some(domain)
+}
+
+/// A problem with an envelope sent to `/api/seal`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Violation {
+    /// The field at fault, e.g. `dek` or `emails[2]`.
+    pub field: String,
+    pub code: ViolationCode,
+    pub message: String,
+}
+
+impl Violation {
+    pub(crate) fn new(
+        field: impl Into<String>,
+        code: ViolationCode,
+        message: impl Into<String>,
+    ) -> Self {
+        Violation {
+            field: field.into(),
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+/// The body of a 400 from `/api/seal` for an envelope that breaks the [`SealPolicy`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InvalidEnvelope {
+    pub error: String,
+    pub violations: Vec<Violation>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ViolationCode {
+    InvalidDek,
+    NoRecipients,
+    TooManyRecipients,
+    InvalidEmail,
+    DisallowedDomain,
+    InvalidMaxUnseals,
+    /// The server has no store to count unseals in.
+    UnsupportedMaxUnseals,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Envelope, SealPolicy, Violation, ViolationCode};
+
+    const DEK: &str = "gVwG8pMMMtdq6mS0OW19Kn7XwvdUcFJpkYN8cEnwnvs";
+
+    fn envelope(dek: &str, emails: &[&str]) -> Envelope {
+        Envelope {
+            dek: dek.into(),
+            emails: emails.iter().map(|email| email.to_string()).collect(),
+            max_unseals: None,
+            id: None,
+        }
+    }
+
+    fn codes(result: Result<(), Vec<Violation>>) -> Vec<(String, ViolationCode)> {
+        result
+            .unwrap_err()
+            .into_iter()
+            .map(|violation| (violation.field, violation.code))
+            .collect()
+    }
+
+    #[test]
+    fn debug_redacts_dek() {
+        let envelope = Envelope {
+            dek: "secret-dek".into(),
+            emails: vec!["alice@email.com".into()],
+            max_unseals: None,
+            id: None,
+        };
+        let debug = format!("{envelope:?}");
+        assert!(!debug.contains("secret-dek"));
+        assert!(debug.contains("alice@email.com"));
+    }
+
+    #[test]
+    fn check_dedupes_recipients() {
+        // Violations name the address as sent, before duplicates are dropped.
+        let mut invalid = envelope(DEK, &["alice@email.com", "ALICE@email.com", "bob"]);
+        assert_eq!(
+            codes(SealPolicy::default().check(&mut invalid)),
+            [("emails[2]".into(), ViolationCode::InvalidEmail)]
+        );
+
+        let mut envelope = envelope(
+            DEK,
+            &["alice@email.com", "bob@email.com", "Alice@Email.com"],
+        );
+        assert_eq!(SealPolicy::default().check(&mut envelope), Ok(()));
+        assert_eq!(envelope.emails, ["alice@email.com", "bob@email.com"]);
+    }
+
+    #[test]
+    fn check_reports_every_violation() {
+        let policy = SealPolicy {
+            max_recipients: 2,
+            ..Default::default()
+        };
+        let mut invalid = envelope(
+            "c2hvcnQ",
+            &[
+                "alice@email.com",
+                "bob",
+                "carol@localhost",
+                "dave@email.com",
+            ],
+        );
+        assert_eq!(
+            codes(policy.check(&mut invalid)),
+            [
+                ("dek".into(), ViolationCode::InvalidDek),
+                ("emails".into(), ViolationCode::TooManyRecipients),
+                ("emails[1]".into(), ViolationCode::InvalidEmail),
+                ("emails[2]".into(), ViolationCode::InvalidEmail),
+            ]
+        );
+        assert_eq!(
+            codes(policy.check(&mut envelope(&format!("{DEK}=="), &[]))),
+            [
+                ("dek".into(), ViolationCode::InvalidDek),
+                ("emails".into(), ViolationCode::NoRecipients),
+            ]
+        );
+        for email in [
+            "@email.com",
+            "alice@",
+            "alice@email..com",
+            "alice@-email.com",
+            "al ice@email.com",
+            "a\"lice@email.com",
+            ".alice@email.com",
+            "alice@bob@email.com",
+        ] {
+            assert_eq!(
+                codes(policy.check(&mut envelope(DEK, &[email]))),
+                [("emails[0]".into(), ViolationCode::InvalidEmail)],
+                "{email}"
+            );
+        }
+    }
+
+    #[test]
+    fn check_refuses_zero_max_unseals() {
+        let mut unlimited = envelope(DEK, &["alice@email.com"]);
+        unlimited.max_unseals = Some(0);
+        assert_eq!(
+            codes(SealPolicy::default().check(&mut unlimited)),
+            [("max_unseals".into(), ViolationCode::InvalidMaxUnseals)]
+        );
+    }
+
+    #[test]
+    fn check_enforces_allowed_domains() {
+        let policy = SealPolicy {
+            allowed_domains: vec!["example.com".into(), "*.Corp.Example".into()],
+            ..Default::default()
+        };
+        let mut allowed = envelope(
+            DEK,
+            &[
+                "alice@Example.com",
+                "bob@eng.corp.example",
+                "carol@a.b.corp.example",
+            ],
+        );
+        assert_eq!(policy.check(&mut allowed), Ok(()));
+        let mut disallowed = envelope(
+            DEK,
+            &[
+                "alice@sub.example.com",
+                "bob@corp.example",
+                "carol@xcorp.example",
+            ],
+        );
+        assert_eq!(
+            codes(policy.check(&mut disallowed)),
+            [
+                ("emails[0]".into(), ViolationCode::DisallowedDomain),
+                ("emails[1]".into(), ViolationCode::DisallowedDomain),
+                ("emails[2]".into(), ViolationCode::DisallowedDomain),
+            ]
+        );
+    }
+}
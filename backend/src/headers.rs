@@ -0,0 +1,103 @@
+use anyhow::{Context as _, Result};
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, header},
+    middleware::Next,
+    response::Response,
+};
+use std::{env, sync::Arc};
+
+/// Policy for every response. The SvelteKit app's pages carry the rest of their policy, with the
+/// hashes of their inline scripts, in a `<meta>` tag (`kit.csp` in `frontend/svelte.config.js`),
+/// so scripts aren't restricted here; both policies apply. This adds what a `<meta>` tag can't
+/// carry, like `frame-ancestors`, and covers API responses.
+pub const DEFAULT_CONTENT_SECURITY_POLICY: &str = concat!(
+    "object-src 'none'; ",
+    "base-uri 'none'; ",
+    "form-action 'self'; ",
+    "frame-ancestors 'none'"
+);
+pub const DEFAULT_PERMISSIONS_POLICY: &str =
+    "camera=(), microphone=(), geolocation=(), payment=(), usb=(), interest-cohort=()";
+
+/// Headers added to every response. A `None` field leaves that header unset.
+pub struct SecurityHeaders {
+    pub content_security_policy: Option<String>,
+    pub permissions_policy: Option<String>,
+    /// Secrets live in URL paths and fragments, so this should stay `no-referrer`.
+    pub referrer_policy: Option<String>,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        SecurityHeaders {
+            content_security_policy: Some(DEFAULT_CONTENT_SECURITY_POLICY.into()),
+            permissions_policy: Some(DEFAULT_PERMISSIONS_POLICY.into()),
+            referrer_policy: Some("no-referrer".into()),
+        }
+    }
+}
+
+impl SecurityHeaders {
+    /// Starts from the defaults and applies `CONTENT_SECURITY_POLICY`, `PERMISSIONS_POLICY` and
+    /// `REFERRER_POLICY` overrides. Setting a variable to an empty string disables that header.
+    pub fn from_env() -> SecurityHeaders {
+        let defaults = SecurityHeaders::default();
+        let var = |name: &str, default: Option<String>| match env::var(name) {
+            Ok(value) if value.is_empty() => None,
+            Ok(value) => Some(value),
+            Err(_) => default,
+        };
+        SecurityHeaders {
+            content_security_policy: var(
+                "CONTENT_SECURITY_POLICY",
+                defaults.content_security_policy,
+            ),
+            permissions_policy: var("PERMISSIONS_POLICY", defaults.permissions_policy),
+            referrer_policy: var("REFERRER_POLICY", defaults.referrer_policy),
+        }
+    }
+
+    pub fn to_header_map(&self) -> Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::X_CONTENT_TYPE_OPTIONS,
+            HeaderValue::from_static("nosniff"),
+        );
+        let configured = [
+            (
+                header::CONTENT_SECURITY_POLICY,
+                &self.content_security_policy,
+            ),
+            (
+                HeaderName::from_static("permissions-policy"),
+                &self.permissions_policy,
+            ),
+            (header::REFERRER_POLICY, &self.referrer_policy),
+        ];
+        for (name, value) in configured {
+            if let Some(value) = value {
+                let value = HeaderValue::try_from(value)
+                    .with_context(|| format!("Invalid {name} header: {value}"))?;
+                headers.insert(name, value);
+            }
+        }
+        Ok(headers)
+    }
+}
+
+/// Adds the configured security headers unless the handler already set them.
+pub async fn set_security_headers(
+    State(headers): State<Arc<HeaderMap>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    for (name, value) in headers.iter() {
+        response
+            .headers_mut()
+            .entry(name)
+            .or_insert_with(|| value.clone());
+    }
+    response
+}
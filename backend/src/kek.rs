@@ -1,37 +1,826 @@
-use aes_gcm::{Aes256Gcm, Key, aead::KeyInit};
-use anyhow::{Context as _, Result};
+use crate::{
+    envelope::{Envelope, SealedEnvelope},
+    shamir::{self, Share},
+};
+use aes_gcm::{
+    AeadCore, Aes256Gcm,
+    aead::{Aead, KeyInit, OsRng, rand_core::RngCore},
+};
+use anyhow::{Context as _, Result, anyhow};
+use argon2::{Algorithm, Argon2, Params, Version};
 use base64::prelude::*;
+use serde::{
+    Deserialize, Deserializer, Serialize,
+    de::{MapAccess, Visitor},
+};
 use serde_json::Value;
-use std::collections::HashMap;
-
-pub type Keks = HashMap<String, Aes256Gcm>;
-
-pub fn parse(json: &str) -> Result<Keks> {
-    serde_json::from_str::<HashMap<String, Value>>(json)?
-        .into_iter()
-        .map(|(key, value)| {
-            let base64_kek = value
-                .as_str()
-                .context("KEK should be a Base64 encoded string")?;
-            let bytes_kek = BASE64_URL_SAFE_NO_PAD.decode(base64_kek)?;
-            #[allow(deprecated)] // https://github.com/RustCrypto/AEADs/issues/730
-            let kek = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&bytes_kek));
-            Ok((key, kek))
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fmt, fs,
+    io::{self, IsTerminal as _},
+};
+use zeroize::{Zeroize, Zeroizing};
+
+const SELF_TEST_PLAINTEXT: &[u8] = b"cipherly KEK self-test";
+
+/// Something wrong with the `KEKS` configuration.
+#[derive(Debug)]
+pub enum KeyConfigError {
+    /// `KEKS` is not valid JSON, or not shaped like a key ring.
+    Json(serde_json::Error),
+    /// A KEK is not a string.
+    NotAString {
+        kid: String,
+    },
+    /// A KEK is not valid base64url.
+    Base64 {
+        kid: String,
+        source: base64::DecodeError,
+    },
+    /// A KEK is not 32 bytes long.
+    WrongLength {
+        kid: String,
+        len: usize,
+    },
+    /// A kid appears more than once.
+    DuplicateKid {
+        kid: String,
+    },
+    /// Two kids have the same key.
+    DuplicateKey {
+        kid: String,
+        other: String,
+    },
+    NoPrimary,
+    UnknownPrimary {
+        kid: String,
+    },
+    InactivePrimary {
+        kid: String,
+        state: KeyState,
+    },
+    /// A KEK failed to decrypt what it encrypted.
+    SelfTest {
+        kid: String,
+    },
+    /// An encrypted key ring file has unusable KDF parameters.
+    Kdf(argon2::Error),
+    /// An encrypted key ring file is damaged.
+    MalformedFile(&'static str),
+    /// An encrypted key ring file doesn't decrypt with the passphrase.
+    WrongPassphrase,
+    /// An encrypted key ring file is protected by a split master key, not a passphrase.
+    Split {
+        threshold: u8,
+    },
+    /// An encrypted key ring file doesn't decrypt with the combined shares.
+    WrongShares,
+}
+
+impl fmt::Display for KeyConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyConfigError::Json(err) => write!(f, "KEKS is not a valid key ring: {err}"),
+            KeyConfigError::NotAString { kid } => {
+                write!(f, "KEK {kid} should be a Base64 encoded string")
+            }
+            KeyConfigError::Base64 { kid, source } => {
+                write!(f, "KEK {kid} is not valid base64url: {source}")
+            }
+            KeyConfigError::WrongLength { kid, len } => {
+                write!(f, "KEK {kid} is {len} bytes; it should be 32")
+            }
+            KeyConfigError::DuplicateKid { kid } => write!(f, "KEK {kid} appears more than once"),
+            KeyConfigError::DuplicateKey { kid, other } => {
+                write!(f, "KEKs {other} and {kid} are the same key")
+            }
+            KeyConfigError::NoPrimary => write!(f, "No primary KEK"),
+            KeyConfigError::UnknownPrimary { kid } => {
+                write!(f, "Primary KEK {kid} does not exist")
+            }
+            KeyConfigError::InactivePrimary { kid, state } => {
+                write!(f, "Primary KEK {kid} is {state:?}")
+            }
+            KeyConfigError::SelfTest { kid } => write!(f, "KEK {kid} failed its self-test"),
+            KeyConfigError::Kdf(err) => write!(f, "KEKS file has invalid KDF parameters: {err}"),
+            KeyConfigError::MalformedFile(problem) => write!(f, "KEKS file is damaged: {problem}"),
+            KeyConfigError::WrongPassphrase => {
+                write!(
+                    f,
+                    "KEKS file can't be decrypted; is the passphrase correct?"
+                )
+            }
+            KeyConfigError::Split { threshold } => write!(
+                f,
+                "KEKS file is protected by a split master key; it needs {threshold} shares"
+            ),
+            KeyConfigError::WrongShares => write!(
+                f,
+                "KEKS file can't be decrypted; are the shares all of its master key?"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for KeyConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KeyConfigError::Json(err) => Some(err),
+            KeyConfigError::Base64 { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for KeyConfigError {
+    fn from(err: serde_json::Error) -> Self {
+        KeyConfigError::Json(err)
+    }
+}
+
+/// The `KEKS` JSON:
+///
+/// ```json
+/// {"primary": "v2", "keys": {"v1": {"key": "...", "state": "retired"}, "v2": {"key": "..."}}}
+/// ```
+///
+/// The older `{"v1": "..."}` form is still accepted, with `v1` as the primary.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct KeyRing {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub primary: Option<String>,
+    pub keys: BTreeMap<String, KeyEntry>,
+}
+
+/// A KEK in a [`KeyRing`]. The key is wiped from memory on drop and left out of `Debug` output.
+#[derive(Serialize, Deserialize)]
+pub struct KeyEntry {
+    /// Base64url encoded, without padding.
+    pub key: String,
+    #[serde(default, skip_serializing_if = "KeyState::is_active")]
+    pub state: KeyState,
+}
+
+impl fmt::Debug for KeyEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyEntry")
+            .field("key", &"<redacted>")
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+impl Drop for KeyEntry {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyState {
+    /// Unseals, and can be made the primary.
+    #[default]
+    Active,
+    /// Unseals envelopes sealed before a rotation, but is never sealed with again.
+    Retired,
+    /// Kept only so its kid isn't reused. Not loaded by the server.
+    Disabled,
+}
+
+impl KeyState {
+    fn is_active(&self) -> bool {
+        *self == KeyState::Active
+    }
+}
+
+/// A JSON object's entries in order, including duplicate keys that a map would silently drop.
+struct Entries(Vec<(String, Value)>);
+
+impl<'de> Deserialize<'de> for Entries {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EntriesVisitor;
+
+        impl<'de> Visitor<'de> for EntriesVisitor {
+            type Value = Entries;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a JSON object")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Entries, A::Error> {
+                let mut entries = Vec::new();
+                while let Some(entry) = map.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(Entries(entries))
+            }
+        }
+
+        deserializer.deserialize_map(EntriesVisitor)
+    }
+}
+
+#[derive(Deserialize)]
+struct KeyRingJson {
+    #[serde(default)]
+    primary: Option<String>,
+    keys: Entries,
+}
+
+impl KeyRing {
+    pub fn from_json(json: &str) -> Result<KeyRing, KeyConfigError> {
+        let value: Value = serde_json::from_str(json)?;
+        let is_legacy = !value.get("keys").is_some_and(Value::is_object);
+        let (primary, entries) = if is_legacy {
+            (None, serde_json::from_str::<Entries>(json)?)
+        } else {
+            let key_ring: KeyRingJson = serde_json::from_str(json)?;
+            (key_ring.primary, key_ring.keys)
+        };
+
+        let mut keys = BTreeMap::new();
+        for (kid, value) in entries.0 {
+            let entry = if is_legacy {
+                let key = value
+                    .as_str()
+                    .ok_or_else(|| KeyConfigError::NotAString { kid: kid.clone() })?;
+                KeyEntry {
+                    key: key.into(),
+                    state: KeyState::Active,
+                }
+            } else if value.get("key").is_some_and(Value::is_string) {
+                serde_json::from_value(value)?
+            } else {
+                return Err(KeyConfigError::NotAString { kid });
+            };
+            if keys.insert(kid.clone(), entry).is_some() {
+                return Err(KeyConfigError::DuplicateKid { kid });
+            }
+        }
+
+        // The server always sealed with v1 before key rings had a primary.
+        let primary = match primary {
+            Some(primary) => Some(primary),
+            None if !is_legacy => None,
+            None if keys.contains_key("v1") => Some("v1".to_string()),
+            None if keys.len() == 1 => keys.keys().next().cloned(),
+            None => None,
+        };
+        Ok(KeyRing { primary, keys })
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    /// The raw bytes of every KEK, including disabled ones, by kid.
+    pub fn raw_keys(&self) -> Result<HashMap<String, Zeroizing<Vec<u8>>>, KeyConfigError> {
+        self.keys
+            .iter()
+            .map(|(kid, entry)| Ok((kid.clone(), decode_base64(kid, &entry.key)?)))
+            .collect()
+    }
+
+    /// Everything wrong with the key ring, or nothing if it's usable.
+    pub fn validate(&self) -> Vec<KeyConfigError> {
+        let mut problems = Vec::new();
+        let mut kids_by_fingerprint = HashMap::new();
+        for (kid, entry) in &self.keys {
+            match decode_key(kid, &entry.key) {
+                Ok(bytes) => {
+                    if let Some(other) = kids_by_fingerprint.insert(fingerprint(&bytes), kid) {
+                        problems.push(KeyConfigError::DuplicateKey {
+                            kid: kid.clone(),
+                            other: other.clone(),
+                        });
+                    }
+                }
+                Err(err) => problems.push(err),
+            }
+        }
+        if let Err(err) = self.check_primary() {
+            problems.push(err);
+        }
+        problems
+    }
+
+    fn check_primary(&self) -> Result<(), KeyConfigError> {
+        let kid = self.primary.as_ref().ok_or(KeyConfigError::NoPrimary)?;
+        match self.keys.get(kid) {
+            None => Err(KeyConfigError::UnknownPrimary { kid: kid.clone() }),
+            Some(entry) if entry.state != KeyState::Active => {
+                Err(KeyConfigError::InactivePrimary {
+                    kid: kid.clone(),
+                    state: entry.state,
+                })
+            }
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+fn decode_base64(kid: &str, key: &str) -> Result<Zeroizing<Vec<u8>>, KeyConfigError> {
+    BASE64_URL_SAFE_NO_PAD
+        .decode(key)
+        .map(Zeroizing::new)
+        .map_err(|source| KeyConfigError::Base64 {
+            kid: kid.into(),
+            source,
         })
+}
+
+/// Decodes a KEK and checks it is the right length for AES-256.
+fn decode_key(kid: &str, key: &str) -> Result<Zeroizing<Vec<u8>>, KeyConfigError> {
+    let bytes = decode_base64(kid, key)?;
+    if bytes.len() != 32 {
+        return Err(KeyConfigError::WrongLength {
+            kid: kid.into(),
+            len: bytes.len(),
+        });
+    }
+    Ok(bytes)
+}
+
+/// Identifies a KEK without revealing it: the first 8 bytes of its SHA-256, in hex.
+pub fn fingerprint(kek: &[u8]) -> String {
+    Sha256::digest(kek)[..8]
+        .iter()
+        .map(|b| format!("{b:02x}"))
         .collect()
 }
 
+/// The KEKs the server seals and unseals with. Their key schedules are wiped from memory on drop.
+pub struct Keks {
+    primary: Option<String>,
+    keys: HashMap<String, Aes256Gcm>,
+}
+
+impl Keks {
+    pub fn get(&self, kid: &str) -> Option<&Aes256Gcm> {
+        self.keys.get(kid)
+    }
+
+    pub fn contains_key(&self, kid: &str) -> bool {
+        self.keys.contains_key(kid)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// The kid new envelopes are sealed with.
+    pub fn primary(&self) -> Option<&str> {
+        self.primary.as_deref()
+    }
+
+    /// Encrypts and decrypts a known value under every KEK, so a broken key fails at startup
+    /// instead of on the first request.
+    pub fn self_test(&self) -> Result<(), KeyConfigError> {
+        for (kid, kek) in &self.keys {
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            let round_trips = kek
+                .encrypt(&nonce, SELF_TEST_PLAINTEXT)
+                .and_then(|ciphertext| kek.decrypt(&nonce, ciphertext.as_slice()))
+                .is_ok_and(|plaintext| plaintext == SELF_TEST_PLAINTEXT);
+            if !round_trips {
+                return Err(KeyConfigError::SelfTest { kid: kid.clone() });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decodes the `KEKS` JSON into the raw bytes of each KEK, by kid.
+pub fn decode(json: &str) -> Result<HashMap<String, Zeroizing<Vec<u8>>>, KeyConfigError> {
+    KeyRing::from_json(json)?.raw_keys()
+}
+
+/// Loads every KEK that isn't disabled. Fails on the first malformed one, or if there's no
+/// primary to seal with, e.g. an older key ring of several KEKs without `v1`.
+pub fn parse(json: &str) -> Result<Keks, KeyConfigError> {
+    let key_ring = KeyRing::from_json(json)?;
+    key_ring.check_primary()?;
+    let mut keys = HashMap::new();
+    for (kid, entry) in &key_ring.keys {
+        if entry.state == KeyState::Disabled {
+            continue;
+        }
+        let bytes = decode_key(kid, &entry.key)?;
+        let kek = Aes256Gcm::new_from_slice(&bytes).map_err(|_| KeyConfigError::WrongLength {
+            kid: kid.clone(),
+            len: bytes.len(),
+        })?;
+        keys.insert(kid.clone(), kek);
+    }
+    Ok(Keks {
+        primary: key_ring.primary,
+        keys,
+    })
+}
+
+/// Loads the KEKs from `KEKS`, or from the encrypted key ring file at `KEKS_FILE`. The file's
+/// passphrase is read from the file at `KEKS_PASSPHRASE_FILE`, from `KEKS_PASSPHRASE`, or from
+/// the terminal.
+pub fn from_env() -> Result<Keks> {
+    let Ok(path) = env::var("KEKS_FILE") else {
+        let keks =
+            Zeroizing::new(env::var("KEKS").context("KEKS environment variable is not set")?);
+        return parse(&keks).context("Failed to parse KEKs");
+    };
+    let file = fs::read_to_string(&path).with_context(|| format!("Failed to read {path}"))?;
+    let passphrase = passphrase_from_env(&path)?;
+    parse_encrypted(&file, &passphrase).with_context(|| format!("Failed to load KEKs from {path}"))
+}
+
+/// Reads the passphrase of the key ring file at `path` as [`from_env`] does.
+pub fn passphrase_from_env(path: &str) -> Result<Zeroizing<String>> {
+    Ok(Zeroizing::new(match env::var("KEKS_PASSPHRASE_FILE") {
+        Ok(passphrase_path) => fs::read_to_string(&passphrase_path)
+            .with_context(|| format!("Failed to read {passphrase_path}"))?
+            .trim_end_matches(['\r', '\n'])
+            .to_string(),
+        Err(_) => match env::var("KEKS_PASSPHRASE") {
+            Ok(passphrase) => passphrase,
+            Err(_) if io::stdin().is_terminal() => {
+                rpassword::prompt_password(format!("Passphrase for {path}: "))?
+            }
+            Err(_) => {
+                return Err(anyhow!(
+                    "Set KEKS_PASSPHRASE_FILE or KEKS_PASSPHRASE to decrypt {path}"
+                ));
+            }
+        },
+    }))
+}
+
+/// Loads a key ring file written by [`encrypt_key_ring`], like [`parse`].
+pub fn parse_encrypted(file: &str, passphrase: &str) -> Result<Keks, KeyConfigError> {
+    parse(&decrypt_key_ring(file, passphrase)?)
+}
+
+/// A key ring encrypted at rest: AES-256-GCM under a key derived from a passphrase, or under a
+/// master key split into shares.
+#[derive(Serialize, Deserialize)]
+struct EncryptedKeyRing {
+    kdf: Kdf,
+    nonce: String,
+    data: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "algorithm", rename_all = "lowercase")]
+enum Kdf {
+    Argon2id {
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+        salt: String,
+    },
+    /// The key is a random master key, split with [`shamir`] so that `threshold` shares recover
+    /// it.
+    Shamir { threshold: u8 },
+}
+
+impl Kdf {
+    fn derive_key(&self, passphrase: &str) -> Result<Zeroizing<[u8; 32]>, KeyConfigError> {
+        let (m_cost, t_cost, p_cost, salt) = match self {
+            Kdf::Argon2id {
+                m_cost,
+                t_cost,
+                p_cost,
+                salt,
+            } => (*m_cost, *t_cost, *p_cost, salt),
+            Kdf::Shamir { threshold } => {
+                return Err(KeyConfigError::Split {
+                    threshold: *threshold,
+                });
+            }
+        };
+        let salt = BASE64_URL_SAFE_NO_PAD
+            .decode(salt)
+            .map_err(|_| KeyConfigError::MalformedFile("salt is not valid base64url"))?;
+        let params = Params::new(m_cost, t_cost, p_cost, Some(32)).map_err(KeyConfigError::Kdf)?;
+        let mut key = Zeroizing::new([0; 32]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &salt, key.as_mut_slice())
+            .map_err(KeyConfigError::Kdf)?;
+        Ok(key)
+    }
+}
+
+impl EncryptedKeyRing {
+    fn encrypt(json: &str, kdf: Kdf, key: &[u8; 32]) -> Result<String> {
+        KeyRing::from_json(json)?;
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let data = Aes256Gcm::new(key.into())
+            .encrypt(&nonce, json.as_bytes())
+            .map_err(|_| anyhow!("Failed to encrypt key ring"))?;
+        Ok(serde_json::to_string_pretty(&EncryptedKeyRing {
+            kdf,
+            #[allow(deprecated)] // https://github.com/RustCrypto/AEADs/issues/730
+            nonce: BASE64_URL_SAFE_NO_PAD.encode(nonce.as_slice()),
+            data: BASE64_URL_SAFE_NO_PAD.encode(data),
+        })?)
+    }
+
+    /// Decrypts the key ring JSON, failing with `wrong_key()` if `key` doesn't open it.
+    fn decrypt(
+        &self,
+        key: &[u8],
+        wrong_key: impl Fn() -> KeyConfigError,
+    ) -> Result<Zeroizing<String>, KeyConfigError> {
+        let nonce = BASE64_URL_SAFE_NO_PAD
+            .decode(&self.nonce)
+            .ok()
+            .filter(|nonce| nonce.len() == 12)
+            .ok_or(KeyConfigError::MalformedFile(
+                "nonce should be 12 base64url bytes",
+            ))?;
+        let data = BASE64_URL_SAFE_NO_PAD
+            .decode(&self.data)
+            .map_err(|_| KeyConfigError::MalformedFile("data is not valid base64url"))?;
+        let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| wrong_key())?;
+        let json = Zeroizing::new(
+            cipher
+                .decrypt(nonce.as_slice().into(), data.as_slice())
+                .map_err(|_| wrong_key())?,
+        );
+        let json = std::str::from_utf8(&json)
+            .map_err(|_| KeyConfigError::MalformedFile("key ring is not UTF-8"))?;
+        Ok(Zeroizing::new(json.to_string()))
+    }
+}
+
+/// Encrypts a `KEKS` JSON under `passphrase`, with the key derived by Argon2id.
+pub fn encrypt_key_ring(json: &str, passphrase: &str) -> Result<String> {
+    let mut salt = [0; 16];
+    OsRng.fill_bytes(&mut salt);
+    let params = Params::default();
+    let kdf = Kdf::Argon2id {
+        m_cost: params.m_cost(),
+        t_cost: params.t_cost(),
+        p_cost: params.p_cost(),
+        salt: BASE64_URL_SAFE_NO_PAD.encode(salt),
+    };
+    let key = kdf.derive_key(passphrase)?;
+    EncryptedKeyRing::encrypt(json, kdf, &key)
+}
+
+/// Decrypts a key ring file written by [`encrypt_key_ring`] back into `KEKS` JSON.
+pub fn decrypt_key_ring(file: &str, passphrase: &str) -> Result<Zeroizing<String>, KeyConfigError> {
+    let file: EncryptedKeyRing = serde_json::from_str(file)?;
+    let key = file.kdf.derive_key(passphrase)?;
+    file.decrypt(key.as_slice(), || KeyConfigError::WrongPassphrase)
+}
+
+/// Encrypts a `KEKS` JSON under a new random master key, and splits the master key into `shares`
+/// shares, any `threshold` of which decrypt it.
+pub fn split_key_ring(json: &str, threshold: u8, shares: u8) -> Result<(String, Vec<Share>)> {
+    let mut master_key = Zeroizing::new([0; 32]);
+    OsRng.fill_bytes(master_key.as_mut_slice());
+    let shares = shamir::split(master_key.as_slice(), threshold, shares)?;
+    let file = EncryptedKeyRing::encrypt(json, Kdf::Shamir { threshold }, &master_key)?;
+    Ok((file, shares))
+}
+
+/// Decrypts a key ring file written by [`split_key_ring`] back into `KEKS` JSON.
+pub fn combine_key_ring(file: &str, shares: &[Share]) -> Result<Zeroizing<String>, KeyConfigError> {
+    let file: EncryptedKeyRing = serde_json::from_str(file)?;
+    let master_key = shamir::combine(shares).map_err(|_| KeyConfigError::WrongShares)?;
+    file.decrypt(&master_key, || KeyConfigError::WrongShares)
+}
+
+/// How many shares the key ring file needs, if it was written by [`split_key_ring`].
+pub fn split_threshold(file: &str) -> Result<Option<u8>, KeyConfigError> {
+    match serde_json::from_str::<EncryptedKeyRing>(file)?.kdf {
+        Kdf::Shamir { threshold } => Ok(Some(threshold)),
+        Kdf::Argon2id { .. } => Ok(None),
+    }
+}
+
+/// Encrypts `envelope` under the primary KEK.
+pub fn seal(keks: &Keks, envelope: &Envelope) -> Result<SealedEnvelope> {
+    // Named fields, so optional ones can be left out. Envelopes sealed as arrays still unseal.
+    // The buffer holds the DEK, so reserve enough up front that growing it leaves no unwiped copy.
+    let capacity =
+        64 + envelope.dek.len() + envelope.emails.iter().map(|e| e.len() + 8).sum::<usize>();
+    let mut buf = Zeroizing::new(Vec::with_capacity(capacity));
+    envelope.serialize(&mut rmp_serde::Serializer::new(&mut *buf).with_struct_map())?;
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let kid = keks.primary().context("No primary KEK")?;
+    let kek = keks.get(kid).with_context(|| format!("No KEK {kid}"))?;
+    let ciphertext = kek
+        .encrypt(&nonce, buf.as_slice())
+        .map_err(|_| anyhow!("Failed to seal envelope"))?;
+    Ok(SealedEnvelope {
+        kid: kid.into(),
+        #[allow(deprecated)] // https://github.com/RustCrypto/AEADs/issues/730
+        nonce: BASE64_URL_SAFE_NO_PAD.encode(nonce.as_slice()),
+        data: BASE64_URL_SAFE_NO_PAD.encode(ciphertext.as_slice()),
+        id: envelope.id.clone(),
+    })
+}
+
+/// Decrypts `sealed` with the KEK it names. Does not check who is asking.
+pub fn unseal(keks: &Keks, sealed: &SealedEnvelope) -> Result<Envelope> {
+    let nonce = BASE64_URL_SAFE_NO_PAD.decode(&sealed.nonce)?;
+    let ciphertext = BASE64_URL_SAFE_NO_PAD.decode(&sealed.data)?;
+    if nonce.len() != 12 {
+        return Err(anyhow!("Nonce should be 12 bytes"));
+    }
+    let kek = keks
+        .get(&sealed.kid)
+        .with_context(|| format!("No KEK {}", sealed.kid))?;
+    let plaintext = Zeroizing::new(
+        kek.decrypt(nonce.as_slice().into(), ciphertext.as_slice())
+            .map_err(|_| anyhow!("Failed to unseal envelope"))?,
+    );
+    Ok(rmp_serde::from_slice(&plaintext)?)
+}
+
 #[cfg(test)]
 mod tests {
-    use super::parse;
+    use super::{
+        KeyConfigError, KeyRing, KeyState, encrypt_key_ring, parse, parse_encrypted, seal, unseal,
+    };
+    use crate::envelope::{Envelope, SealedEnvelope};
+    use aes_gcm::aead::Aead;
+    use base64::prelude::*;
 
-    const TEST_KEKS: &str = r#"{"t1":"jRg36ErQ6FLcc7nZgngOpjJnJLGwA3xaMy0yx1pxJrI","t2":"5wasFWpc1thRkR8Wkghn5hZwWF-vimSxIYYZuALL3i8"}"#;
+    const TEST_KEKS: &str = r#"{"v1":"jRg36ErQ6FLcc7nZgngOpjJnJLGwA3xaMy0yx1pxJrI","t2":"5wasFWpc1thRkR8Wkghn5hZwWF-vimSxIYYZuALL3i8"}"#;
+    const TEST_KEY_RING: &str = r#"{"primary":"t2","keys":{"t1":{"key":"jRg36ErQ6FLcc7nZgngOpjJnJLGwA3xaMy0yx1pxJrI","state":"retired"},"t2":{"key":"5wasFWpc1thRkR8Wkghn5hZwWF-vimSxIYYZuALL3i8"},"t3":{"key":"i3t5Wv9dbm5Js8oSmBN5nXsaCpHIpgsT4ZQBOIQ1HkE","state":"disabled"}}}"#;
+
+    fn envelope() -> Envelope {
+        Envelope {
+            dek: "dek".into(),
+            emails: vec!["alice@email.com".into()],
+            max_unseals: None,
+            id: None,
+        }
+    }
 
     #[test]
     fn parse_succeeds() {
         let keks = parse(TEST_KEKS).unwrap();
         assert_eq!(keks.len(), 2);
-        assert!(keks.contains_key("t1"));
+        assert!(keks.contains_key("v1"));
         assert!(keks.contains_key("t2"));
+        assert_eq!(keks.primary(), Some("v1"));
+    }
+
+    #[test]
+    fn parse_refuses_key_rings_without_a_primary() {
+        // Older key rings sealed with v1, so several KEKs without it leave nothing to seal with.
+        assert!(matches!(
+            parse(
+                r#"{"t1":"jRg36ErQ6FLcc7nZgngOpjJnJLGwA3xaMy0yx1pxJrI","t2":"5wasFWpc1thRkR8Wkghn5hZwWF-vimSxIYYZuALL3i8"}"#
+            ),
+            Err(KeyConfigError::NoPrimary)
+        ));
+        assert!(matches!(
+            parse(r#"{"keys":{"t1":{"key":"jRg36ErQ6FLcc7nZgngOpjJnJLGwA3xaMy0yx1pxJrI"}}}"#),
+            Err(KeyConfigError::NoPrimary)
+        ));
+    }
+
+    #[test]
+    fn parse_key_ring_skips_disabled_keys() {
+        let keks = parse(TEST_KEY_RING).unwrap();
+        assert_eq!(keks.primary(), Some("t2"));
+        assert!(keks.contains_key("t1"));
+        assert!(!keks.contains_key("t3"));
+    }
+
+    #[test]
+    fn key_ring_round_trips() {
+        let key_ring = KeyRing::from_json(TEST_KEY_RING).unwrap();
+        assert_eq!(key_ring.keys["t1"].state, KeyState::Retired);
+        assert_eq!(key_ring.to_json().unwrap(), TEST_KEY_RING);
+    }
+
+    #[test]
+    fn legacy_keks_seal_with_v1() {
+        let keks = parse(r#"{"v1":"jRg36ErQ6FLcc7nZgngOpjJnJLGwA3xaMy0yx1pxJrI"}"#).unwrap();
+        assert_eq!(seal(&keks, &envelope()).unwrap().kid, "v1");
+    }
+
+    #[test]
+    fn validate_reports_problems() {
+        let key_ring = KeyRing::from_json(
+            r#"{"primary":"t1","keys":{"t1":{"key":"AAAA","state":"retired"},"t2":{"key":"5wasFWpc1thRkR8Wkghn5hZwWF-vimSxIYYZuALL3i8"},"t3":{"key":"5wasFWpc1thRkR8Wkghn5hZwWF-vimSxIYYZuALL3i8"}}}"#,
+        )
+        .unwrap();
+        let problems: Vec<_> = key_ring
+            .validate()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            problems,
+            vec![
+                "KEK t1 is 3 bytes; it should be 32",
+                "KEKs t2 and t3 are the same key",
+                "Primary KEK t1 is Retired",
+            ]
+        );
+        assert!(
+            KeyRing::from_json(TEST_KEY_RING)
+                .unwrap()
+                .validate()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn parse_reports_wrong_length() {
+        let result = parse(r#"{"v1":"jRg36ErQ6FLcc7nZgngOpjJnJLGwA3xaMy0yx1pxJrI","v2":"AAAA"}"#);
+        assert!(matches!(
+            result,
+            Err(KeyConfigError::WrongLength { kid, len: 3 }) if kid == "v2"
+        ));
+    }
+
+    #[test]
+    fn parse_reports_malformed_config() {
+        assert!(matches!(parse("{"), Err(KeyConfigError::Json(_))));
+        assert!(matches!(
+            parse(r#"{"v1":1}"#),
+            Err(KeyConfigError::NotAString { kid }) if kid == "v1"
+        ));
+        assert!(matches!(
+            parse(r#"{"keys":{"v1":{"state":"retired"}}}"#),
+            Err(KeyConfigError::NotAString { kid }) if kid == "v1"
+        ));
+        assert!(matches!(
+            parse(r#"{"v1":"not base64!"}"#),
+            Err(KeyConfigError::Base64 { kid, .. }) if kid == "v1"
+        ));
+        assert!(matches!(
+            parse(r#"{"v1":"jRg36ErQ6FLcc7nZgngOpjJnJLGwA3xaMy0yx1pxJrI","v1":"5wasFWpc1thRkR8Wkghn5hZwWF-vimSxIYYZuALL3i8"}"#),
+            Err(KeyConfigError::DuplicateKid { kid }) if kid == "v1"
+        ));
+        assert!(matches!(
+            parse(r#"{"primary":"t1","keys":{"t1":{"key":"jRg36ErQ6FLcc7nZgngOpjJnJLGwA3xaMy0yx1pxJrI","state":"disabled"}}}"#),
+            Err(KeyConfigError::InactivePrimary { kid, state: KeyState::Disabled }) if kid == "t1"
+        ));
+    }
+
+    #[test]
+    fn self_test_succeeds() {
+        parse(TEST_KEY_RING).unwrap().self_test().unwrap();
+    }
+
+    #[test]
+    fn encrypted_key_ring_round_trips() {
+        let file = encrypt_key_ring(TEST_KEY_RING, "correct horse").unwrap();
+        assert!(!file.contains("5wasFWpc1thRkR8Wkghn5hZwWF-vimSxIYYZuALL3i8"));
+        let keks = parse_encrypted(&file, "correct horse").unwrap();
+        assert_eq!(keks.primary(), Some("t2"));
+        assert!(matches!(
+            parse_encrypted(&file, "battery staple"),
+            Err(KeyConfigError::WrongPassphrase)
+        ));
+    }
+
+    #[test]
+    fn seal_and_unseal_succeeds() {
+        let keks = parse(TEST_KEY_RING).unwrap();
+        let sealed = seal(&keks, &envelope()).unwrap();
+        assert_eq!(sealed.kid, "t2");
+        assert_eq!(unseal(&keks, &sealed).unwrap(), envelope());
+    }
+
+    #[test]
+    fn unseal_with_unknown_kid_fails() {
+        let keks = parse(TEST_KEY_RING).unwrap();
+        let mut sealed = seal(&keks, &envelope()).unwrap();
+        sealed.kid = "t3".into();
+        assert!(unseal(&keks, &sealed).is_err());
+    }
+
+    #[test]
+    fn unseal_reads_envelopes_sealed_as_arrays() {
+        let keks = parse(TEST_KEY_RING).unwrap();
+        let buf = rmp_serde::to_vec(&("dek", ["alice@email.com"])).unwrap();
+        let nonce = [7; 12];
+        #[allow(deprecated)] // https://github.com/RustCrypto/AEADs/issues/730
+        let data = keks
+            .get("t2")
+            .unwrap()
+            .encrypt(&nonce.into(), buf.as_slice())
+            .unwrap();
+        let sealed = SealedEnvelope {
+            kid: "t2".into(),
+            nonce: BASE64_URL_SAFE_NO_PAD.encode(nonce),
+            data: BASE64_URL_SAFE_NO_PAD.encode(data),
+            id: None,
+        };
+        assert_eq!(unseal(&keks, &sealed).unwrap(), envelope());
     }
 }
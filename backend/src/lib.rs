@@ -0,0 +1,23 @@
+//! The cipherly server, plus types and a client for sharing secrets through it.
+
+pub mod audit;
+pub mod challenge;
+pub mod client;
+#[cfg(feature = "embed-frontend")]
+mod embedded;
+pub mod envelope;
+pub mod google;
+pub mod headers;
+pub mod kek;
+pub mod listener;
+pub mod payload;
+mod server;
+pub mod shamir;
+pub mod storage;
+pub mod stream;
+pub mod tls;
+pub mod vault;
+pub mod webhook;
+
+pub use google::authenticate;
+pub use server::{Config, router, run_server, seal, unseal};
@@ -0,0 +1,172 @@
+use crate::tls::{TlsConfig, TlsListener};
+use anyhow::{Context as _, Result, anyhow};
+use axum::{extract::connect_info::Connected, serve};
+use listenfd::ListenFd;
+use std::{
+    env, fmt, io,
+    net::SocketAddr,
+    os::unix::fs::FileTypeExt as _,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, UnixListener},
+};
+use tokio_util::sync::CancellationToken;
+
+/// A connection accepted by any of the supported listeners.
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+/// Where the server should accept connections.
+#[derive(Clone, Debug)]
+pub enum Bind {
+    Tcp(u16),
+    Unix(PathBuf),
+    /// The first socket passed in by systemd socket activation (`LISTEN_FDS`).
+    Systemd,
+}
+
+impl Bind {
+    /// Prefers a socket inherited from systemd, then `UNIX_SOCKET`, then `PORT` (default 8000).
+    pub fn from_env() -> Result<Bind> {
+        if env::var_os("LISTEN_FDS").is_some() {
+            return Ok(Bind::Systemd);
+        }
+        if let Some(path) = env::var_os("UNIX_SOCKET") {
+            return Ok(Bind::Unix(path.into()));
+        }
+        let port = env::var("PORT").unwrap_or("8000".into());
+        Ok(Bind::Tcp(port.parse().context("PORT should be a number")?))
+    }
+}
+
+/// Address of either end of a connection.
+#[derive(Clone, Debug)]
+pub enum Addr {
+    Tcp(SocketAddr),
+    /// Clients connecting over a Unix socket are usually unnamed.
+    Unix(Option<PathBuf>),
+}
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Addr::Tcp(addr) => write!(f, "{addr}"),
+            Addr::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            Addr::Unix(None) => write!(f, "unix:(unnamed)"),
+        }
+    }
+}
+
+impl From<tokio::net::unix::SocketAddr> for Addr {
+    fn from(addr: tokio::net::unix::SocketAddr) -> Self {
+        Addr::Unix(addr.as_pathname().map(Path::to_path_buf))
+    }
+}
+
+/// The socket the server accepts connections on.
+pub enum Listener {
+    Tcp(TcpListener),
+    Tls(TlsListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub async fn bind(
+        bind: Bind,
+        tls: Option<&TlsConfig>,
+        shutdown_signal: CancellationToken,
+    ) -> Result<Listener> {
+        let listener = match bind {
+            Bind::Tcp(port) => Listener::Tcp(TcpListener::bind(format!("0.0.0.0:{port}")).await?),
+            Bind::Unix(path) => {
+                // A socket file left behind by an unclean shutdown would make bind fail. Anything
+                // else at the path is more likely a typo, so leave it be.
+                match std::fs::symlink_metadata(&path) {
+                    Ok(metadata) if metadata.file_type().is_socket() => {
+                        std::fs::remove_file(&path).with_context(|| {
+                            format!("Failed to remove stale socket {}", path.display())
+                        })?;
+                    }
+                    Ok(_) => {
+                        return Err(anyhow!(
+                            "{} exists and is not a socket; refusing to replace it",
+                            path.display()
+                        ));
+                    }
+                    Err(_) => {}
+                }
+                Listener::Unix(UnixListener::bind(&path)?)
+            }
+            Bind::Systemd => Self::from_systemd()?,
+        };
+        match (listener, tls) {
+            (Listener::Tcp(listener), Some(tls)) => Ok(Listener::Tls(TlsListener::new(
+                listener,
+                tls,
+                shutdown_signal,
+            )?)),
+            (Listener::Unix(_), Some(_)) => Err(anyhow!("TLS is only supported on TCP sockets")),
+            (listener, _) => Ok(listener),
+        }
+    }
+
+    fn from_systemd() -> Result<Listener> {
+        let mut fds = ListenFd::from_env();
+        if fds.len() != 1 {
+            return Err(anyhow!(
+                "Expected exactly one socket from systemd, got {}",
+                fds.len()
+            ));
+        }
+        if let Ok(Some(listener)) = fds.take_tcp_listener(0) {
+            listener.set_nonblocking(true)?;
+            return Ok(Listener::Tcp(TcpListener::from_std(listener)?));
+        }
+        let listener = fds
+            .take_unix_listener(0)?
+            .context("Socket from systemd was already taken")?;
+        listener.set_nonblocking(true)?;
+        Ok(Listener::Unix(UnixListener::from_std(listener)?))
+    }
+}
+
+impl serve::Listener for Listener {
+    type Io = Box<dyn Io>;
+    type Addr = Addr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = serve::Listener::accept(listener).await;
+                (Box::new(stream), Addr::Tcp(addr))
+            }
+            Listener::Tls(listener) => {
+                let (stream, addr) = serve::Listener::accept(listener).await;
+                (Box::new(stream), Addr::Tcp(addr))
+            }
+            Listener::Unix(listener) => {
+                let (stream, addr) = serve::Listener::accept(listener).await;
+                (Box::new(stream), addr.into())
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(Addr::Tcp),
+            Listener::Tls(listener) => serve::Listener::local_addr(listener).map(Addr::Tcp),
+            Listener::Unix(listener) => listener.local_addr().map(Addr::from),
+        }
+    }
+}
+
+/// Lets handlers extract `ConnectInfo<Addr>` when served with
+/// `into_make_service_with_connect_info::<Addr>()`.
+impl Connected<serve::IncomingStream<'_, Listener>> for Addr {
+    fn connect_info(stream: serve::IncomingStream<'_, Listener>) -> Self {
+        stream.remote_addr().clone()
+    }
+}
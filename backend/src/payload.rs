@@ -0,0 +1,418 @@
+//! The payload format from `frontend/src/lib/cipherly.ts`: a msgpack map carried after the `#`
+//! of a `/decrypt` URL, base64url encoded for text and raw for `.cly` files. Payloads too long
+//! for a link can instead be stored on the server, encrypted as a blob whose key is carried in
+//! a `/s/<id>#<key>` short link.
+
+use crate::envelope::{Envelope, SealedEnvelope};
+use aes_gcm::{
+    AeadCore, Aes256Gcm, Key, KeyInit,
+    aead::{Aead, OsRng},
+};
+use anyhow::{Context as _, Result, anyhow};
+use base64::prelude::*;
+use rmp_serde::{from_slice, to_vec_named};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use zeroize::Zeroizing;
+
+/// Mirrors `EncryptionScheme` in `cipherly.ts`.
+pub const PASSWORD_SCHEME: u8 = 0;
+pub const AUTH_SCHEME: u8 = 1;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct PasswordPayload {
+    pub es: u8,
+    #[serde(rename = "fn", default, skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    #[serde(with = "serde_bytes")]
+    pub s: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub iv: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub ct: Vec<u8>,
+}
+
+/// The web app always writes `fn`, as nil when there's no filename.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuthPayload {
+    pub es: u8,
+    #[serde(rename = "fn", default)]
+    pub filename: Option<String>,
+    pub k: String,
+    #[serde(with = "serde_bytes")]
+    pub n: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub se: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub iv: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub ct: Vec<u8>,
+}
+
+impl AuthPayload {
+    /// The envelope to have the server unseal.
+    pub fn sealed_envelope(&self) -> SealedEnvelope {
+        SealedEnvelope {
+            kid: self.k.clone(),
+            nonce: BASE64_URL_SAFE_NO_PAD.encode(&self.n),
+            data: BASE64_URL_SAFE_NO_PAD.encode(&self.se),
+            id: None,
+        }
+    }
+
+    /// Decrypts the payload with the DEK from its unsealed envelope.
+    pub fn decrypt(&self, envelope: &Envelope) -> Result<Vec<u8>> {
+        let dek = Zeroizing::new(
+            BASE64_URL_SAFE_NO_PAD
+                .decode(envelope.dek.trim_end_matches('='))
+                .context("DEK is not valid base64")?,
+        );
+        if dek.len() != 32 || self.iv.len() != 12 {
+            return Err(anyhow!("Payload has an invalid key or IV"));
+        }
+        #[allow(deprecated)] // https://github.com/RustCrypto/AEADs/issues/730
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&dek))
+            .decrypt(self.iv.as_slice().into(), self.ct.as_slice())
+            .map_err(|_| anyhow!("Decryption failed"))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Payload {
+    Password(PasswordPayload),
+    Auth(AuthPayload),
+}
+
+#[derive(Deserialize)]
+struct PayloadHeader {
+    es: u8,
+}
+
+impl Payload {
+    pub fn filename(&self) -> Option<&str> {
+        match self {
+            Payload::Password(payload) => payload.filename.as_deref(),
+            Payload::Auth(payload) => payload.filename.as_deref(),
+        }
+    }
+
+    fn to_msgpack(&self) -> Result<Vec<u8>> {
+        Ok(match self {
+            Payload::Password(payload) => to_vec_named(payload)?,
+            Payload::Auth(payload) => to_vec_named(payload)?,
+        })
+    }
+
+    fn from_msgpack(data: &[u8]) -> Result<Payload> {
+        let header: PayloadHeader = from_slice(data).context("Payload is not valid msgpack")?;
+        match header.es {
+            PASSWORD_SCHEME => Ok(Payload::Password(from_slice(data)?)),
+            AUTH_SCHEME => Ok(Payload::Auth(from_slice(data)?)),
+            es => Err(anyhow!("Unsupported encryption scheme: {es}")),
+        }
+    }
+}
+
+fn decrypt_url(base_url: &str) -> String {
+    format!("{}/decrypt#", base_url.trim_end_matches('/'))
+}
+
+/// Encodes a payload as a link the web app can open.
+pub fn encode_link(base_url: &str, payload: &Payload) -> Result<String> {
+    Ok(format!(
+        "{}{}",
+        decrypt_url(base_url),
+        BASE64_URL_SAFE_NO_PAD.encode(payload.to_msgpack()?)
+    ))
+}
+
+/// Encodes a payload as the contents of a `.cly` file.
+pub fn encode_file(base_url: &str, payload: &Payload) -> Result<Vec<u8>> {
+    let mut data = decrypt_url(base_url).into_bytes();
+    data.extend(payload.to_msgpack()?);
+    Ok(data)
+}
+
+/// Returns the base URL of the instance the payload was made for.
+fn base_url(url_header: &[u8]) -> Result<String> {
+    let url_header = std::str::from_utf8(url_header).context("URL header is not valid UTF-8")?;
+    url_header
+        .strip_suffix("/decrypt#")
+        .map(Into::into)
+        .context("URL header is not a cipherly /decrypt URL")
+}
+
+/// Decodes a link, returning the base URL of its cipherly instance and the payload.
+pub fn decode_link(link: &str) -> Result<(String, Payload)> {
+    let link = link.trim();
+    let end_of_url = link.find('#').context("Payload is missing URL header")? + 1;
+    let data = BASE64_URL_SAFE_NO_PAD
+        .decode(link[end_of_url..].trim_end_matches('='))
+        .context("Payload is not valid base64")?;
+    Ok((
+        base_url(&link.as_bytes()[..end_of_url])?,
+        Payload::from_msgpack(&data)?,
+    ))
+}
+
+/// Decodes a `.cly` file, returning the base URL of its cipherly instance and the payload.
+pub fn decode_file(data: &[u8]) -> Result<(String, Payload)> {
+    let end_of_url = data
+        .iter()
+        .position(|&b| b == b'#')
+        .context("Payload is missing URL header")?
+        + 1;
+    Ok((
+        base_url(&data[..end_of_url])?,
+        Payload::from_msgpack(&data[end_of_url..])?,
+    ))
+}
+
+/// A link to a blob made by [`encrypt_blob`]: `<base_url>/s/<id>#<key>`.
+#[derive(Debug, PartialEq)]
+pub struct ShortLink {
+    pub base_url: String,
+    pub id: String,
+    pub key: Zeroizing<String>,
+}
+
+impl ShortLink {
+    /// Parses a short link, or returns `None` if `link` isn't one.
+    pub fn parse(link: &str) -> Option<ShortLink> {
+        let (url, key) = link.trim().split_once('#')?;
+        let (base_url, id) = url.rsplit_once("/s/")?;
+        if id.is_empty() || id.contains('/') || key.is_empty() {
+            return None;
+        }
+        Some(ShortLink {
+            base_url: base_url.into(),
+            id: id.into(),
+            key: Zeroizing::new(key.into()),
+        })
+    }
+
+    /// Decrypts a blob fetched by the link's id, returning the base URL of its cipherly
+    /// instance and the payload.
+    pub fn decrypt_blob(&self, blob: &[u8]) -> Result<(String, Payload)> {
+        let key = Zeroizing::new(
+            BASE64_URL_SAFE_NO_PAD
+                .decode(self.key.as_str())
+                .context("Link key is not valid base64")?,
+        );
+        if key.len() != 32 || blob.len() < 12 {
+            return Err(anyhow!("Blob or link key is malformed"));
+        }
+        let (nonce, ciphertext) = blob.split_at(12);
+        #[allow(deprecated)] // https://github.com/RustCrypto/AEADs/issues/730
+        let data = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+            .decrypt(nonce.into(), ciphertext)
+            .map_err(|_| anyhow!("Decryption failed"))?;
+        decode_file(&data)
+    }
+}
+
+impl fmt::Display for ShortLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/s/{}#{}",
+            self.base_url.trim_end_matches('/'),
+            self.id,
+            self.key.as_str()
+        )
+    }
+}
+
+/// Encrypts a payload, in `.cly` form, under a new random key for storage on the server.
+/// Returns the blob, which is the nonce followed by the ciphertext, and the base64url key.
+pub fn encrypt_blob(base_url: &str, payload: &Payload) -> Result<(Vec<u8>, Zeroizing<String>)> {
+    let key = Aes256Gcm::generate_key(OsRng);
+    let nonce = Aes256Gcm::generate_nonce(OsRng);
+    let ciphertext = Aes256Gcm::new(&key)
+        .encrypt(&nonce, encode_file(base_url, payload)?.as_slice())
+        .map_err(|_| anyhow!("Encryption failed"))?;
+    let mut blob = nonce.to_vec();
+    blob.extend(ciphertext);
+    Ok((blob, Zeroizing::new(BASE64_URL_SAFE_NO_PAD.encode(key))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    /// Shared with `cipherly.test.ts` so both codecs are held to the same bytes.
+    const VECTORS: &str = include_str!("../../frontend/src/lib/testdata/payload-vectors.json");
+
+    #[derive(Deserialize)]
+    struct Vectors {
+        valid: Vec<Vector>,
+        invalid: Vec<Vector>,
+    }
+
+    #[derive(Deserialize)]
+    struct Vector {
+        name: String,
+        file: bool,
+        #[serde(default)]
+        payload: Value,
+        encoded: String,
+    }
+
+    impl Vector {
+        fn encoded(&self) -> Vec<u8> {
+            BASE64_URL_SAFE_NO_PAD.decode(&self.encoded).unwrap()
+        }
+
+        fn decode(&self) -> Result<(String, Payload)> {
+            let encoded = self.encoded();
+            if self.file {
+                decode_file(&encoded)
+            } else {
+                decode_link(std::str::from_utf8(&encoded)?)
+            }
+        }
+
+        fn encode(&self, base_url: &str, payload: &Payload) -> Vec<u8> {
+            if self.file {
+                encode_file(base_url, payload).unwrap()
+            } else {
+                encode_link(base_url, payload).unwrap().into_bytes()
+            }
+        }
+
+        /// The vector's payload, with its base64url byte fields decoded.
+        fn payload(&self) -> Payload {
+            let mut payload = self.payload.clone();
+            for field in ["s", "n", "se", "iv", "ct"] {
+                if let Some(Value::String(data)) = payload.get(field) {
+                    payload[field] = BASE64_URL_SAFE_NO_PAD.decode(data).unwrap().into();
+                }
+            }
+            match payload["es"].as_u64() {
+                Some(0) => Payload::Password(serde_json::from_value(payload).unwrap()),
+                Some(1) => Payload::Auth(serde_json::from_value(payload).unwrap()),
+                es => panic!("{}: unexpected scheme {es:?}", self.name),
+            }
+        }
+    }
+
+    fn password_payload(filename: Option<&str>) -> Payload {
+        Payload::Password(PasswordPayload {
+            es: PASSWORD_SCHEME,
+            filename: filename.map(Into::into),
+            s: vec![1; 16],
+            iv: vec![2; 12],
+            ct: vec![3; 21],
+        })
+    }
+
+    fn auth_payload() -> Payload {
+        Payload::Auth(AuthPayload {
+            es: AUTH_SCHEME,
+            filename: None,
+            k: "v1".into(),
+            n: vec![1; 12],
+            se: vec![2; 64],
+            iv: vec![3; 12],
+            ct: vec![4; 21],
+        })
+    }
+
+    #[test]
+    fn encode_link_round_trips() {
+        let payload = password_payload(None);
+        let link = encode_link("https://cipherly.example/", &payload).unwrap();
+        assert!(link.starts_with("https://cipherly.example/decrypt#"));
+        assert_eq!(
+            decode_link(&link).unwrap(),
+            ("https://cipherly.example".into(), payload)
+        );
+    }
+
+    #[test]
+    fn encode_file_round_trips() {
+        let payload = password_payload(Some("plain.txt"));
+        let data = encode_file("https://cipherly.example", &payload).unwrap();
+        assert_eq!(
+            decode_file(&data).unwrap(),
+            ("https://cipherly.example".into(), payload)
+        );
+    }
+
+    #[test]
+    fn encode_auth_link_round_trips() {
+        let payload = auth_payload();
+        let link = encode_link("https://cipherly.example", &payload).unwrap();
+        assert_eq!(decode_link(&link).unwrap().1, payload);
+    }
+
+    #[test]
+    fn decode_file_reads_web_app_file() {
+        let (base_url, payload) =
+            decode_file(include_bytes!("../../frontend/plain.txt.cly")).unwrap();
+        assert_eq!(base_url, "http://localhost:5173");
+        assert_eq!(payload.filename(), Some("plain.txt"));
+    }
+
+    #[test]
+    fn short_link_decrypts_blob() {
+        let payload = password_payload(Some("plain.txt"));
+        let (blob, key) = encrypt_blob("https://cipherly.example", &payload).unwrap();
+        let link = ShortLink {
+            base_url: "https://cipherly.example/".into(),
+            id: "AAAAAAAAAAAAAAAA".into(),
+            key,
+        }
+        .to_string();
+        assert!(link.starts_with("https://cipherly.example/s/AAAAAAAAAAAAAAAA#"));
+
+        let link = ShortLink::parse(&link).unwrap();
+        assert_eq!(link.base_url, "https://cipherly.example");
+        assert_eq!(
+            link.decrypt_blob(&blob).unwrap(),
+            ("https://cipherly.example".into(), payload)
+        );
+        let mut tampered = blob.clone();
+        tampered[20] ^= 1;
+        assert!(link.decrypt_blob(&tampered).is_err());
+    }
+
+    #[test]
+    fn short_link_rejects_other_links() {
+        assert_eq!(
+            ShortLink::parse("https://cipherly.example/decrypt#abc"),
+            None
+        );
+        assert_eq!(ShortLink::parse("https://cipherly.example/s/abc"), None);
+    }
+
+    #[test]
+    fn decode_link_rejects_missing_header() {
+        assert!(decode_link("not a link").is_err());
+    }
+
+    #[test]
+    fn valid_vectors_round_trip() {
+        let vectors: Vectors = serde_json::from_str(VECTORS).unwrap();
+        for vector in vectors.valid {
+            let (base_url, payload) = vector.decode().unwrap();
+            assert_eq!(base_url, "https://cipherly.example", "{}", vector.name);
+            assert_eq!(payload, vector.payload(), "{}", vector.name);
+            assert_eq!(
+                vector.encode(&base_url, &payload),
+                vector.encoded(),
+                "{}",
+                vector.name
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_vectors_are_rejected() {
+        let vectors: Vectors = serde_json::from_str(VECTORS).unwrap();
+        for vector in vectors.invalid {
+            assert!(vector.decode().is_err(), "{}", vector.name);
+        }
+    }
+}
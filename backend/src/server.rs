@@ -0,0 +1,2252 @@
+use crate::{
+    audit::{self, Action, AuditEvent, AuditLog, Outcome, Requester},
+    challenge::{self, Challenge, Challenger},
+    envelope::{Envelope, InvalidEnvelope, SealPolicy, SealedEnvelope, Violation, ViolationCode},
+    google::{self, KeySet},
+    headers::{self, SecurityHeaders},
+    kek::{self, Keks},
+    listener::{self, Bind, Listener},
+    storage::{self, BlobLimits, BlobStore, StoreFull, StreamStore},
+    tls::TlsConfig,
+    vault::{self, Admins, UnsealError, Vault, VaultStatus},
+    webhook::Webhooks,
+};
+use anyhow::{Context as _, Result, anyhow};
+use axum::{
+    Extension, Json, Router,
+    body::{Body, Bytes},
+    extract::{DefaultBodyLimit, Path, Query, Request},
+    http::{HeaderName, HeaderValue, StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Redirect, Response},
+    routing::{delete, get, get_service, post},
+    serve::Listener as _,
+};
+use futures_util::StreamExt as _;
+use sd_notify::NotifyState;
+use serde::{Deserialize, Serialize};
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::task::JoinHandle;
+use tokio_util::{io::ReaderStream, sync::CancellationToken};
+use tower::ServiceBuilder;
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    set_header::SetResponseHeaderLayer,
+    timeout::TimeoutLayer,
+    trace::TraceLayer,
+};
+use zeroize::Zeroizing;
+
+/// Handles `POST /api/seal`. Needs an `Arc<Keks>` extension, and an `Arc<dyn BlobStore>` one to
+/// seal envelopes with `max_unseals` or to let signed-in senders revoke them. Put
+/// [`google::authenticate_optional`] in front of it to record senders. Envelopes are checked
+/// against the `Arc<SealPolicy>` extension, or the default policy without one, and each violation
+/// is reported in a 400. With an `Arc<dyn AuditLog>` extension, the seal is recorded before the
+/// envelope is returned, and with an `Arc<Webhooks>` one, it fires webhooks.
+#[tracing::instrument(skip_all)]
+#[allow(clippy::too_many_arguments)]
+pub async fn seal(
+    Extension(keks): Extension<Arc<Keks>>,
+    claims: Option<Extension<google::Claims>>,
+    store: Option<Extension<Arc<dyn BlobStore>>>,
+    policy: Option<Extension<Arc<SealPolicy>>>,
+    audit_log: Option<Extension<Arc<dyn AuditLog>>>,
+    webhooks: Option<Extension<Arc<Webhooks>>>,
+    requester: Requester,
+    Json(mut envelope): Json<Envelope>,
+) -> Result<Json<SealedEnvelope>, Response> {
+    let email = claims
+        .as_ref()
+        .map(|Extension(claims)| claims.email.clone());
+    let policy = policy.map(|Extension(policy)| policy).unwrap_or_default();
+    let result = match policy.check(&mut envelope) {
+        Ok(()) => seal_envelope(&keks, claims, store, envelope).await,
+        Err(violations) => {
+            tracing::info!(?violations, "Rejected envelope");
+            Err(invalid_envelope(violations))
+        }
+    };
+    let mut event = AuditEvent::now(Action::Seal, requester, Outcome::Sealed);
+    event.email = email;
+    match &result {
+        Ok(sealed) => {
+            event.envelope = sealed.id.clone();
+            event.kid = Some(sealed.kid.clone());
+        }
+        Err(response) if response.status() == StatusCode::BAD_REQUEST => {
+            event.outcome = Outcome::Invalid
+        }
+        Err(_) => event.outcome = Outcome::Error,
+    }
+    record(audit_log, webhooks, event)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    result.map(Json)
+}
+
+fn invalid_envelope(violations: Vec<Violation>) -> Response {
+    let body = InvalidEnvelope {
+        error: "Invalid Envelope".into(),
+        violations,
+    };
+    (StatusCode::BAD_REQUEST, Json(body)).into_response()
+}
+
+/// Seals `envelope` before storing its records, so an envelope that can't be sealed leaves none.
+async fn seal_envelope(
+    keks: &Keks,
+    claims: Option<Extension<google::Claims>>,
+    store: Option<Extension<Arc<dyn BlobStore>>>,
+    mut envelope: Envelope,
+) -> Result<SealedEnvelope, Response> {
+    if envelope.max_unseals.is_some() && store.is_none() {
+        return Err(invalid_envelope(vec![Violation::new(
+            "max_unseals",
+            ViolationCode::UnsupportedMaxUnseals,
+            "this server can't limit unseals",
+        )]));
+    }
+    let id = storage::new_id();
+    envelope.id = Some(id.clone());
+    let sealed = kek::seal(keks, &envelope).map_err(|err| {
+        tracing::error!("Failed to seal envelope: {err:#}");
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })?;
+    let Some(Extension(store)) = store else {
+        return Ok(sealed);
+    };
+    if let Some(max_unseals) = envelope.max_unseals {
+        blocking({
+            let (id, store) = (id.clone(), store.clone());
+            move || store.put(&storage::envelope_record(&id), &[], Some(max_unseals))
+        })
+        .await
+        .map_err(IntoResponse::into_response)?;
+        tracing::info!(id, max_unseals, "Sealing limited envelope");
+    }
+    if let Some(Extension(claims)) = claims {
+        blocking(move || store.put(&storage::sender_record(&id), claims.email.as_bytes(), None))
+            .await
+            .map_err(IntoResponse::into_response)?;
+    }
+    Ok(sealed)
+}
+
+/// Handles `POST /api/unseal`. Needs an `Arc<Keks>` extension, and [`google::authenticate`] in
+/// front of it to provide the caller's claims. With an `Arc<dyn BlobStore>` extension, revoked
+/// envelopes are refused, and envelopes with `max_unseals` are gone once they've been unsealed
+/// that many times. With an `Arc<dyn AuditLog>` extension, every attempt is recorded before the
+/// response is sent, and the envelope isn't returned, or its unseal counted, if that fails. With
+/// an `Arc<Webhooks>` one, unseals and attempts by non-recipients fire webhooks. Requests without
+/// a valid ID token never get here, so they aren't recorded.
+#[tracing::instrument(skip_all)]
+pub async fn unseal(
+    Extension(keks): Extension<Arc<Keks>>,
+    Extension(claims): Extension<google::Claims>,
+    store: Option<Extension<Arc<dyn BlobStore>>>,
+    audit_log: Option<Extension<Arc<dyn AuditLog>>>,
+    webhooks: Option<Extension<Arc<Webhooks>>>,
+    requester: Requester,
+    Json(sealed_envelope): Json<SealedEnvelope>,
+) -> Result<Json<Envelope>, StatusCode> {
+    let (id, result) = check_unseal(&keks, &claims, store.clone(), &sealed_envelope).await;
+    let outcome = *result.as_ref().err().unwrap_or(&Outcome::Unsealed);
+    let mut event = AuditEvent::now(Action::Unseal, requester, outcome);
+    event.envelope = id.clone();
+    event.kid = Some(sealed_envelope.kid);
+    event.email = Some(claims.email);
+    if let Err(status) = record(audit_log, webhooks, event).await {
+        // The unseal wasn't recorded, so it mustn't count against the envelope's limit either.
+        if let (Ok(envelope), Some(id), Some(Extension(store))) = (&result, id, store)
+            && envelope.max_unseals.is_some()
+        {
+            let restored = blocking(move || store.give_back(&storage::envelope_record(&id), &[]));
+            if restored.await.is_err() {
+                tracing::error!("Failed to give back an unrecorded unseal");
+            }
+        }
+        return Err(status);
+    }
+    result.map(Json).map_err(|outcome| match outcome {
+        Outcome::Invalid | Outcome::NotRecipient => StatusCode::UNAUTHORIZED,
+        Outcome::Revoked | Outcome::UsedUp => StatusCode::GONE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    })
+}
+
+/// Opens `sealed_envelope` for the caller, returning its id, once it's known to be genuine, along
+/// with the envelope or why it can't be had.
+async fn check_unseal(
+    keks: &Keks,
+    claims: &google::Claims,
+    store: Option<Extension<Arc<dyn BlobStore>>>,
+    sealed_envelope: &SealedEnvelope,
+) -> (Option<String>, Result<Envelope, Outcome>) {
+    let Ok(envelope) = kek::unseal(keks, sealed_envelope) else {
+        return (None, Err(Outcome::Invalid));
+    };
+    let id = envelope.id.clone();
+    // Seal dedupes recipients ignoring case, so whichever spelling was kept has to match. This
+    // applies to envelopes sealed before then too: an account whose email differs from a
+    // recipient's only in case can unseal them, which for Google accounts is the same mailbox.
+    if !envelope
+        .emails
+        .iter()
+        .any(|email| email.eq_ignore_ascii_case(&claims.email))
+    {
+        return (id, Err(Outcome::NotRecipient));
+    }
+    let (Some(id), Some(Extension(store))) = (id.clone(), store) else {
+        if envelope.max_unseals.is_some() {
+            tracing::error!("Can't count unseals of a limited envelope without a store");
+            return (id, Err(Outcome::Error));
+        }
+        return (id, Ok(envelope));
+    };
+    let revoked = blocking({
+        let (id, store) = (id.clone(), store.clone());
+        move || store.get(&storage::revoked_record(&id))
+    })
+    .await;
+    match revoked {
+        Ok(None) => {}
+        Ok(Some(_)) => {
+            tracing::info!(id, "Refused to unseal revoked envelope");
+            return (Some(id), Err(Outcome::Revoked));
+        }
+        Err(_) => return (Some(id), Err(Outcome::Error)),
+    }
+    // Only count unseals by recipients, so anyone else can't use them up.
+    if envelope.max_unseals.is_some() {
+        let record = {
+            let (id, store) = (id.clone(), store.clone());
+            blocking(move || store.get(&storage::envelope_record(&id))).await
+        };
+        match record {
+            Ok(Some(_)) => {}
+            Ok(None) => return (Some(id), Err(Outcome::UsedUp)),
+            Err(_) => return (Some(id), Err(Outcome::Error)),
+        }
+    }
+    (Some(id), Ok(envelope))
+}
+
+/// Handles `GET /api/envelopes/{id}/access`: the audited unseal attempts of an envelope, oldest
+/// first. Only its sender may see them, as for [`revoke`]. Needs `Arc<dyn BlobStore>` and
+/// `Arc<dyn AuditLog>` extensions and [`google::authenticate`] in front of it.
+#[tracing::instrument(skip(store, audit_log, claims))]
+async fn access_history(
+    Extension(store): Extension<Arc<dyn BlobStore>>,
+    Extension(audit_log): Extension<Arc<dyn AuditLog>>,
+    Extension(claims): Extension<google::Claims>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<AuditEvent>>, StatusCode> {
+    if !storage::is_valid_id(&id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let sender = blocking({
+        let id = id.clone();
+        move || store.get(&storage::sender_record(&id))
+    })
+    .await?
+    .ok_or(StatusCode::NOT_FOUND)?;
+    if sender != claims.email.as_bytes() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let mut events = blocking(move || audit_log.for_envelope(&id)).await?;
+    events.retain(|event| event.action == Action::Unseal);
+    Ok(Json(events))
+}
+
+/// Handles `DELETE /api/envelopes/{id}`: revokes an envelope so it can't be unsealed again. Only
+/// the sender, if they were signed in when sealing it, may revoke it. Needs an
+/// `Arc<dyn BlobStore>` extension and [`google::authenticate`] in front of it. With an
+/// `Arc<dyn AuditLog>` extension, revocations and refused attempts are recorded.
+#[tracing::instrument(skip(store, claims, audit_log, requester))]
+async fn revoke(
+    Extension(store): Extension<Arc<dyn BlobStore>>,
+    Extension(claims): Extension<google::Claims>,
+    audit_log: Option<Extension<Arc<dyn AuditLog>>>,
+    requester: Requester,
+    Path(id): Path<String>,
+) -> StatusCode {
+    if !storage::is_valid_id(&id) {
+        return StatusCode::NOT_FOUND;
+    }
+    let result = blocking({
+        let (id, email) = (id.clone(), claims.email.clone());
+        move || {
+            let Some(sender) = store.get(&storage::sender_record(&id))? else {
+                return Ok(StatusCode::NOT_FOUND);
+            };
+            if sender != email.as_bytes() {
+                return Ok(StatusCode::FORBIDDEN);
+            }
+            store.put(&storage::revoked_record(&id), &[], None)?;
+            tracing::info!(id, "Revoked envelope");
+            Ok(StatusCode::NO_CONTENT)
+        }
+    })
+    .await;
+    let outcome = match result {
+        Ok(StatusCode::NO_CONTENT) => Outcome::Revoked,
+        Ok(StatusCode::FORBIDDEN) => Outcome::Invalid,
+        Err(_) => Outcome::Error,
+        // Unknown envelopes aren't anyone's to audit.
+        Ok(status) => return status,
+    };
+    let mut event = AuditEvent::now(Action::Revoke, requester, outcome);
+    event.envelope = Some(id);
+    event.email = Some(claims.email);
+    if let Err(status) = record(audit_log, None, event).await {
+        return status;
+    }
+    result.unwrap_or_else(|status| status)
+}
+
+/// Appends `event` to the audit log, if there is one, and then fires any webhooks for it.
+async fn record(
+    audit_log: Option<Extension<Arc<dyn AuditLog>>>,
+    webhooks: Option<Extension<Arc<Webhooks>>>,
+    event: AuditEvent,
+) -> Result<(), StatusCode> {
+    let event = match audit_log {
+        Some(Extension(audit_log)) => {
+            blocking(move || audit_log.append(&event).map(|_| event)).await?
+        }
+        None => event,
+    };
+    if let Some(Extension(webhooks)) = webhooks {
+        webhooks.notify(&event);
+    }
+    Ok(())
+}
+
+/// Runs a blob store or audit log call on the blocking thread pool.
+async fn blocking<T: Send + 'static>(
+    call: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T, StatusCode> {
+    tokio::task::spawn_blocking(call)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|err| {
+            tracing::error!("Storage failed: {err:#}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
+
+/// The body of an API error response.
+#[derive(Debug, Serialize)]
+pub(crate) struct ApiError {
+    pub(crate) error: &'static str,
+}
+
+#[derive(Deserialize)]
+struct ShareRequest {
+    share: Zeroizing<String>,
+}
+
+/// Handles `GET /api/admin/unseal`.
+async fn vault_status(Extension(vault): Extension<Arc<Vault>>) -> Json<VaultStatus> {
+    Json(vault.status())
+}
+
+/// Handles `POST /api/admin/unseal`: adds a share of the master key to a sealed vault. Put
+/// [`vault::require_admin`] in front of it. With an `Arc<dyn AuditLog>` extension, every share
+/// sent is recorded with its sender, though never the share itself.
+#[tracing::instrument(skip_all)]
+async fn add_share(
+    Extension(vault): Extension<Arc<Vault>>,
+    Extension(claims): Extension<google::Claims>,
+    audit_log: Option<Extension<Arc<dyn AuditLog>>>,
+    requester: Requester,
+    Json(request): Json<ShareRequest>,
+) -> Result<Json<VaultStatus>, (StatusCode, Json<ApiError>)> {
+    let result = vault.add_share(&request.share, &claims.email);
+    let outcome = match result {
+        Ok(_) => Outcome::ShareAccepted,
+        Err(_) => Outcome::Invalid,
+    };
+    let mut event = AuditEvent::now(Action::AddShare, requester, outcome);
+    event.email = Some(claims.email);
+    record(audit_log, None, event).await.map_err(|status| {
+        let error = "Internal Server Error";
+        (status, Json(ApiError { error }))
+    })?;
+    result.map(Json).map_err(|err| {
+        tracing::warn!("Rejected share: {err}");
+        let error = match err {
+            UnsealError::InvalidShare => "Invalid Share",
+            UnsealError::DuplicateShare => "Duplicate Share",
+            UnsealError::AlreadySubmitted => "Already Submitted",
+            UnsealError::MismatchedShare => "Mismatched Share",
+            UnsealError::WrongShares(_) => "Wrong Shares",
+        };
+        (StatusCode::BAD_REQUEST, Json(ApiError { error }))
+    })
+}
+
+/// The largest blob `POST /api/blobs` accepts.
+const MAX_BLOB_SIZE: usize = 32 * 1024 * 1024;
+
+#[derive(Serialize)]
+struct BlobId {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct BlobParams {
+    max_views: Option<u32>,
+}
+
+/// Handles `POST /api/blobs`: stores an already-encrypted blob under a new random id. With
+/// `?max_views=N`, the blob is deleted once it has been fetched `N` times. Responds with 507
+/// Insufficient Storage while the store is at its quota.
+#[tracing::instrument(skip_all)]
+async fn put_blob(
+    Extension(store): Extension<Arc<dyn BlobStore>>,
+    Extension(limits): Extension<Arc<BlobLimits>>,
+    Query(params): Query<BlobParams>,
+    blob: Bytes,
+) -> Result<Json<BlobId>, StatusCode> {
+    if blob.is_empty() || params.max_views == Some(0) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let id = storage::new_id();
+    let size = blob.len();
+    let stored = blocking({
+        let id = id.clone();
+        move || {
+            // Concurrent uploads can each overshoot the quota by at most MAX_BLOB_SIZE.
+            if !limits.fits(store.size()?, size as u64) {
+                return Ok(false);
+            }
+            store.put(&id, &blob, params.max_views)?;
+            Ok(true)
+        }
+    })
+    .await?;
+    if !stored {
+        tracing::warn!(size, "Blob store is full");
+        return Err(StatusCode::INSUFFICIENT_STORAGE);
+    }
+    tracing::info!(id, size, max_views = params.max_views, "Stored blob");
+    Ok(Json(BlobId { id }))
+}
+
+/// Handles `GET /api/blobs/{id}`.
+#[tracing::instrument(skip(store))]
+async fn get_blob(
+    Extension(store): Extension<Arc<dyn BlobStore>>,
+    Path(id): Path<String>,
+) -> Result<Response, StatusCode> {
+    if !storage::is_valid_id(&id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let blob = blocking(move || store.get(&id))
+        .await?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(([(header::CONTENT_TYPE, "application/octet-stream")], blob).into_response())
+}
+
+/// How long `/api/streams` waits for the next piece of a body before giving up. Streams are
+/// exempt from the overall request timeout, since multi-GB ones take a while.
+const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Handles `POST /api/streams`: writes an already-encrypted body, in the [`crate::stream`]
+/// format, to a new stream as it arrives, so it's never held in memory. Responds with its id, or
+/// with 507 Insufficient Storage once the store is at its quota.
+#[tracing::instrument(skip_all)]
+async fn put_stream(
+    Extension(streams): Extension<Arc<StreamStore>>,
+    body: Body,
+) -> Result<Json<BlobId>, StatusCode> {
+    let id = storage::new_id();
+    let mut stream = streams.create(&id).await.map_err(stream_failed)?;
+    let mut body = body.into_data_stream();
+    let mut size = 0;
+    loop {
+        let data = match tokio::time::timeout(STREAM_IDLE_TIMEOUT, body.next()).await {
+            Err(_) => return Err(StatusCode::REQUEST_TIMEOUT),
+            Ok(None) => break,
+            Ok(Some(Err(_))) => return Err(StatusCode::BAD_REQUEST),
+            Ok(Some(Ok(data))) => data,
+        };
+        size += data.len() as u64;
+        if size > streams.max_size() {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        stream.write(&data).await.map_err(|err| {
+            if err.is::<StoreFull>() {
+                tracing::warn!(size, "Stream store is full");
+                StatusCode::INSUFFICIENT_STORAGE
+            } else {
+                stream_failed(err)
+            }
+        })?;
+    }
+    if size == 0 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    stream.commit().await.map_err(stream_failed)?;
+    tracing::info!(id, size, "Stored stream");
+    Ok(Json(BlobId { id }))
+}
+
+/// Handles `GET /api/streams/{id}`, reading the stream from disk as the response is sent.
+#[tracing::instrument(skip(streams))]
+async fn get_stream(
+    Extension(streams): Extension<Arc<StreamStore>>,
+    Path(id): Path<String>,
+) -> Result<Response, StatusCode> {
+    if !storage::is_valid_id(&id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let (file, len) = streams
+        .open(&id)
+        .await
+        .map_err(stream_failed)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (header::CONTENT_LENGTH, len.to_string()),
+        ],
+        Body::from_stream(ReaderStream::new(file)),
+    )
+        .into_response())
+}
+
+fn stream_failed(err: anyhow::Error) -> StatusCode {
+    tracing::error!("Stream store failed: {err:#}");
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+/// Handles `GET /api/challenge`: issues a challenge to solve before an anonymous seal.
+async fn get_challenge(Extension(challenger): Extension<Arc<Challenger>>) -> Json<Challenge> {
+    Json(challenger.issue())
+}
+
+/// Handles `GET /s/{id}`: sends short links to the web app, which fetches the blob. Browsers
+/// carry the key in the fragment across the redirect.
+async fn short_link(Path(id): Path<String>) -> Result<Redirect, StatusCode> {
+    if !storage::is_valid_id(&id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(Redirect::to(&format!("/decrypt?s={id}")))
+}
+
+async fn api_not_found() -> (StatusCode, Json<ApiError>) {
+    (StatusCode::NOT_FOUND, Json(ApiError { error: "Not Found" }))
+}
+
+async fn api_method_not_allowed() -> (StatusCode, Json<ApiError>) {
+    (
+        StatusCode::METHOD_NOT_ALLOWED,
+        Json(ApiError {
+            error: "Method Not Allowed",
+        }),
+    )
+}
+
+#[derive(Default)]
+pub struct Config {
+    pub port: Option<u16>,
+    pub unix_socket: Option<PathBuf>,
+    pub key_set: Option<KeySet>,
+    pub enable_test_creds: bool,
+    /// OAuth clients besides the web app whose ID tokens are accepted.
+    pub extra_client_ids: Vec<String>,
+    pub keks: Option<Keks>,
+    /// Takes precedence over `keks`, e.g. to start sealed.
+    pub vault: Option<Vault>,
+    /// Who may submit shares to unseal a sealed vault.
+    pub admins: Admins,
+    pub tls: Option<TlsConfig>,
+    /// Enables `/api/blobs` and `/s/<id>` short links.
+    pub blob_store: Option<Arc<dyn BlobStore>>,
+    /// How much `/api/blobs` may store, and for how long.
+    pub blob_limits: BlobLimits,
+    /// Records unseal attempts, and with `blob_store`, enables `/api/envelopes/<id>/access`.
+    pub audit_log: Option<Arc<dyn AuditLog>>,
+    /// Notified of seals, unseals and attempts by non-recipients.
+    pub webhooks: Option<Arc<Webhooks>>,
+    /// Enables `/api/streams`.
+    pub stream_store: Option<Arc<StreamStore>>,
+    /// Makes anonymous seals solve a challenge from `/api/challenge` first.
+    pub seal_challenge: Option<Arc<Challenger>>,
+    /// What `/api/seal` accepts.
+    pub seal_policy: SealPolicy,
+    pub security_headers: SecurityHeaders,
+    pub shutdown_signal: CancellationToken,
+}
+
+/// Builds the cipherly app: `/api/seal`, `/api/unseal`, blob and stream storage if configured and
+/// the frontend, with security headers. Only the key set, KEKs, client IDs, blob and stream
+/// stores, blob limits, audit log, webhooks, seal challenge and policy, admins and security headers
+/// of `config` are used; the rest configures [`run_server`], which also deletes expired blobs and
+/// streams.
+pub async fn router(config: Config) -> Result<Router> {
+    let mut key_set = match config.key_set {
+        Some(certs) => certs,
+        None => KeySet::new(config.enable_test_creds)
+            .await
+            .context("Failed to fetch Google certs")?,
+    };
+    key_set.allow_client_ids(config.extra_client_ids);
+    let vault = match (config.vault, config.keks) {
+        (Some(vault), _) => vault,
+        (None, Some(keks)) => Vault::unsealed(keks),
+        (None, None) => Vault::from_env()?,
+    };
+    if let Some(keks) = vault.keks() {
+        keks.self_test().context("KEK self-test failed")?;
+    } else if config.admins.emails.is_empty() {
+        return Err(anyhow!(
+            "The KEKs are sealed, but no ADMIN_EMAILS are allowed to submit shares"
+        ));
+    }
+
+    #[cfg(not(feature = "embed-frontend"))]
+    let static_files = tower_http::services::ServeDir::new("static")
+        .precompressed_gzip()
+        .precompressed_br()
+        .fallback(tower_http::services::ServeFile::new("static/index.html"));
+    #[cfg(feature = "embed-frontend")]
+    if !crate::embedded::has_frontend() {
+        tracing::warn!("No frontend was embedded at build time; only /api is served");
+    }
+    #[cfg(feature = "embed-frontend")]
+    let static_files = axum::handler::HandlerWithoutStateExt::into_service(crate::embedded::serve);
+
+    let security_headers = Arc::new(config.security_headers.to_header_map()?);
+    let mut seal_route = post(seal);
+    if config.seal_challenge.is_some() {
+        seal_route = seal_route.route_layer(middleware::from_fn(challenge::require_solution));
+    }
+    let mut api = Router::new()
+        .route(
+            "/unseal",
+            post(unseal).route_layer(middleware::from_fn(google::authenticate)),
+        )
+        .route(
+            "/seal",
+            seal_route.route_layer(middleware::from_fn(google::authenticate_optional)),
+        )
+        .route_layer(middleware::from_fn(vault::require_unsealed))
+        .route(
+            "/admin/unseal",
+            get(vault_status).merge(
+                post(add_share)
+                    .route_layer(middleware::from_fn(vault::require_admin))
+                    .route_layer(middleware::from_fn(google::authenticate)),
+            ),
+        )
+        .layer((
+            Extension(Arc::new(config.seal_policy)),
+            Extension(Arc::new(config.admins)),
+        ));
+    if let Some(challenger) = config.seal_challenge {
+        api = api
+            .route("/challenge", get(get_challenge))
+            .layer(Extension(challenger));
+    }
+    let mut app = Router::new();
+    if let Some(store) = config.blob_store {
+        api = api.merge(
+            Router::new()
+                .route(
+                    "/blobs",
+                    post(put_blob).layer(DefaultBodyLimit::max(MAX_BLOB_SIZE)),
+                )
+                .route("/blobs/{id}", get(get_blob))
+                .route(
+                    "/envelopes/{id}",
+                    delete(revoke).route_layer(middleware::from_fn(google::authenticate)),
+                )
+                .layer(Extension(Arc::new(config.blob_limits))),
+        );
+        if config.audit_log.is_some() {
+            api = api.route(
+                "/envelopes/{id}/access",
+                get(access_history).route_layer(middleware::from_fn(google::authenticate)),
+            );
+        }
+        // Also lets seal and unseal limit and revoke envelopes.
+        api = api.layer(Extension(store));
+        app = app.route("/s/{id}", get(short_link));
+    }
+    if let Some(audit_log) = config.audit_log {
+        api = api.layer(Extension(audit_log));
+    }
+    if let Some(webhooks) = config.webhooks {
+        api = api.layer(Extension(webhooks));
+    }
+    // Graceful shutdown will wait for outstanding requests to complete. Add a timeout so requests
+    // don't hang forever.
+    api = api.layer(TimeoutLayer::with_status_code(
+        StatusCode::REQUEST_TIMEOUT,
+        Duration::from_secs(10),
+    ));
+    if let Some(streams) = config.stream_store {
+        api = api.merge(
+            Router::new()
+                .route("/streams", post(put_stream))
+                .route("/streams/{id}", get(get_stream))
+                .layer(Extension(streams)),
+        );
+    }
+    Ok(app
+        .nest(
+            "/api",
+            api.fallback(api_not_found)
+                .method_not_allowed_fallback(api_method_not_allowed)
+                // Envelopes carry key material; never let a browser or proxy cache them.
+                .layer(SetResponseHeaderLayer::overriding(
+                    header::CACHE_CONTROL,
+                    HeaderValue::from_static("no-store"),
+                )),
+        )
+        .layer(
+            ServiceBuilder::new()
+                .layer((Extension(Arc::new(key_set)), Extension(Arc::new(vault))))
+                .layer(SetRequestIdLayer::new(
+                    HeaderName::from_static("x-request-id"),
+                    MakeRequestUuid,
+                ))
+                .layer(PropagateRequestIdLayer::new(HeaderName::from_static(
+                    "x-request-id",
+                )))
+                // Enable request tracing. Must enable `tower_http=debug)
+                .layer(TraceLayer::new_for_http()),
+        )
+        // Only GET and HEAD reach the frontend; anything else is a 405 rather than index.html.
+        .fallback_service(get_service(
+            ServiceBuilder::new()
+                .layer((
+                    TimeoutLayer::with_status_code(
+                        StatusCode::REQUEST_TIMEOUT,
+                        Duration::from_secs(20),
+                    ),
+                    middleware::from_fn(set_static_cache_control),
+                ))
+                .service(static_files),
+        ))
+        .layer(middleware::from_fn_with_state(
+            security_headers,
+            headers::set_security_headers,
+        )))
+}
+
+/// Binds the configured socket and serves [`router`] on it until `shutdown_signal` is cancelled.
+pub async fn run_server(mut config: Config) -> Result<(listener::Addr, JoinHandle<Result<()>>)> {
+    let tls = config.tls.take();
+    let shutdown_signal = config.shutdown_signal.clone();
+    let bind = match (config.unix_socket.take(), config.port) {
+        (Some(path), _) => Bind::Unix(path),
+        (None, Some(port)) => Bind::Tcp(port),
+        (None, None) => Bind::from_env()?,
+    };
+    let socket_path = match &bind {
+        Bind::Unix(path) => Some(path.clone()),
+        _ => None,
+    };
+
+    let audit_log = config.audit_log.clone();
+    let blob_store = config.blob_store.clone();
+    let blob_ttl = config.blob_limits.ttl;
+    let stream_store = config.stream_store.clone();
+    let mut app = router(config).await?;
+    if let Some(hsts) = tls.as_ref().and_then(TlsConfig::hsts_header) {
+        app = app.layer(SetResponseHeaderLayer::overriding(
+            header::STRICT_TRANSPORT_SECURITY,
+            HeaderValue::try_from(hsts)?,
+        ));
+    }
+    let listener = Listener::bind(bind, tls.as_ref(), shutdown_signal.clone()).await?;
+    let addr = listener.local_addr()?;
+
+    if let Some(audit_log) = audit_log.clone() {
+        tokio::spawn(audit::checkpoint_periodically(
+            audit_log,
+            audit::CHECKPOINT_PERIOD,
+            shutdown_signal.clone(),
+        ));
+    }
+    if let Some(store) = blob_store {
+        tokio::spawn(storage::sweep_periodically(
+            "blobs",
+            move || store.delete_before(SystemTime::now() - blob_ttl),
+            storage::SWEEP_PERIOD,
+            shutdown_signal.clone(),
+        ));
+    }
+    if let Some(streams) = stream_store {
+        tokio::spawn(storage::sweep_periodically(
+            "streams",
+            move || streams.delete_before(SystemTime::now() - streams.ttl()),
+            storage::SWEEP_PERIOD,
+            shutdown_signal.clone(),
+        ));
+    }
+    let serve = tokio::spawn({
+        let addr = addr.clone();
+        async move {
+            tracing::info!("server listening on {}", addr);
+            // Tell systemd we're up. This is a no-op when not running under systemd.
+            if let Err(err) = sd_notify::notify(false, &[NotifyState::Ready]) {
+                tracing::warn!("Failed to notify systemd of readiness: {err}");
+            }
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<listener::Addr>(),
+            )
+            .with_graceful_shutdown(async move {
+                shutdown_signal.cancelled().await;
+                if let Err(err) = sd_notify::notify(false, &[NotifyState::Stopping]) {
+                    tracing::warn!("Failed to notify systemd of shutdown: {err}");
+                }
+            })
+            .await
+            .context("serve failed")?;
+            if let Some(path) = socket_path {
+                std::fs::remove_file(&path)
+                    .with_context(|| format!("Failed to remove socket {}", path.display()))?;
+            }
+            Ok(())
+        }
+    });
+    Ok((addr, serve))
+}
+
+// Built frontend files in /_app/immutable/ are immutable and never change.
+// Allow them to be cached as such.
+async fn set_static_cache_control(request: Request, next: Next) -> Response {
+    let header = if request.uri().path().starts_with("/_app/immutable/") {
+        "public, immutable, max-age=31536000"
+    } else if request.uri().path() == "/robots.txt" || request.uri().path() == "/favicon.svg" {
+        "public, max-age=345600, stale-while-revalidate=345600"
+    } else {
+        "public, max-age=3600, stale-while-revalidate=3600"
+    };
+
+    let mut response = next.run(request).await;
+    if response.status().is_success() {
+        response.headers_mut().insert(
+            reqwest::header::CACHE_CONTROL,
+            HeaderValue::from_static(header),
+        );
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        Config,
+        audit::{Action, AuditEvent, AuditLog, FileAuditLog, MemoryAuditLog, Outcome},
+        challenge::{self, Challenge, Challenger},
+        envelope::{Envelope, InvalidEnvelope, SealPolicy, ViolationCode},
+        google::{Claims, testing::new_fake_key_set},
+        headers::{self, SecurityHeaders},
+        kek, listener, router, run_server,
+        storage::{BlobLimits, MemoryBlobStore, StreamStore},
+        stream::{self, Decryptor, Encryptor},
+        tls::TlsConfig,
+        vault::{Admins, Vault},
+        webhook::{self, EventType, WebhookEvent, Webhooks},
+    };
+    use anyhow::{Result, anyhow};
+    use axum::http::{HeaderName, HeaderValue};
+    use ed25519_dalek::SigningKey;
+    use jsonwebtoken::{EncodingKey, encode};
+    use reqwest::{Certificate, Client, StatusCode, Version, tls::TlsInfo};
+    use rustls::pki_types::{CertificateDer, pem::PemObject};
+    use serde_json::{Value, json};
+    use std::{
+        fs,
+        net::SocketAddr,
+        path::Path,
+        sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+        },
+        time::Duration,
+    };
+    use tokio::{net::TcpListener, task::JoinHandle};
+    use tokio_util::sync::CancellationToken;
+    use tower_http::set_header::SetResponseHeaderLayer;
+
+    const TEST_KEK: &str = r#"{"v1":"jRg36ErQ6FLcc7nZgngOpjJnJLGwA3xaMy0yx1pxJrI"}"#;
+    const ALICE_ENVELOPE: &str =
+        r#"{"dek":"gVwG8pMMMtdq6mS0OW19Kn7XwvdUcFJpkYN8cEnwnvs","emails":["alice@email.com"]}"#;
+
+    fn bearer(email: &str, name: &str) -> String {
+        bearer_for(
+            email,
+            name,
+            "981002175662-g8jr2n89bptsn8n9ds1fn5edfheojr7i.apps.googleusercontent.com",
+        )
+    }
+
+    fn bearer_for(email: &str, name: &str, aud: &str) -> String {
+        let encoding_key =
+            EncodingKey::from_rsa_pem(include_str!("testdata/pk.pem").as_bytes()).unwrap();
+        let mut header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256);
+        header.kid = Some("1".into());
+        let claims = Claims {
+            email: email.into(),
+            name: name.into(),
+            exp: 2524636800,
+            iss: "https://accounts.google.com".to_string(),
+            aud: aud.to_string(),
+        };
+        encode(&header, &claims, &encoding_key).unwrap()
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn post_seal_succeeds() {
+        let (server, addr) = start_server().await;
+        let client = Client::default();
+
+        let resp = client
+            .post(format!("http://{addr}/api/seal"))
+            .header("Content-Type", "application/json")
+            .body(ALICE_ENVELOPE)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        println!("{:?}", resp.text().await.unwrap());
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn post_unseal_alice_succeeds() {
+        let (server, addr) = start_server().await;
+        let client = Client::default();
+
+        let resp = client
+            .post(format!("http://{addr}/api/unseal"))
+            .header("Content-Type", "application/json")
+            .bearer_auth(bearer("alice@email.com", "Alice"))
+            .body(include_str!("testdata/alice.sealed"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+
+    async fn post_unseal_eve_fails() {
+        let (server, addr) = start_server().await;
+        let client = Client::default();
+
+        let resp = client
+            .post(format!("http://{addr}/api/unseal"))
+            .header("Content-Type", "application/json")
+            .bearer_auth(bearer("eve@email.com", "Eve"))
+            .body(include_str!("testdata/alice.sealed"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn post_unseal_with_extra_client_id_succeeds() {
+        let (server, addr) = start_server_with(crate::Config {
+            extra_client_ids: vec!["cli.apps.googleusercontent.com".into()],
+            ..Default::default()
+        })
+        .await;
+        let listener::Addr::Tcp(addr) = addr else {
+            panic!("Expected a TCP address, got {addr}");
+        };
+        let client = Client::default();
+
+        let resp = client
+            .post(format!("http://{addr}/api/unseal"))
+            .header("Content-Type", "application/json")
+            .bearer_auth(bearer_for(
+                "alice@email.com",
+                "Alice",
+                "cli.apps.googleusercontent.com",
+            ))
+            .body(include_str!("testdata/alice.sealed"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn post_unseal_with_unknown_client_id_fails() {
+        let (server, addr) = start_server().await;
+        let client = Client::default();
+
+        let resp = client
+            .post(format!("http://{addr}/api/unseal"))
+            .header("Content-Type", "application/json")
+            .bearer_auth(bearer_for(
+                "alice@email.com",
+                "Alice",
+                "cli.apps.googleusercontent.com",
+            ))
+            .body(include_str!("testdata/alice.sealed"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn post_unseal_no_auth_fails() {
+        let (server, addr) = start_server().await;
+        let client = Client::default();
+
+        let resp = client
+            .post(format!("http://{addr}/api/unseal"))
+            .header("Content-Type", "application/json")
+            .body(include_str!("testdata/alice.sealed"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn seal_and_unseal_succeeds() {
+        let (server, addr) = start_server().await;
+        let client = Client::default();
+
+        let seal_resp = client
+            .post(format!("http://{addr}/api/seal"))
+            .header("Content-Type", "application/json")
+            .body(ALICE_ENVELOPE)
+            .send()
+            .await
+            .expect("Failed to send seal request.");
+
+        assert_eq!(seal_resp.status(), StatusCode::OK);
+        let body = seal_resp.text().await.expect("Failed to read response");
+        let id = serde_json::from_str::<Value>(&body).unwrap()["id"].clone();
+        assert!(id.is_string());
+
+        let unseal_resp = client
+            .post(format!("http://{addr}/api/unseal"))
+            .header("Content-Type", "application/json")
+            .bearer_auth(bearer("alice@email.com", "Alice"))
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to send unseal request.");
+        assert_eq!(unseal_resp.status(), StatusCode::OK);
+        let mut envelope: Value = serde_json::from_str(ALICE_ENVELOPE).unwrap();
+        envelope["id"] = id;
+        assert_eq!(unseal_resp.json::<Value>().await.unwrap(), envelope);
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn limited_envelope_is_gone_after_max_unseals() {
+        let (server, addr) = start_server_with(Config {
+            blob_store: Some(Arc::new(MemoryBlobStore::default())),
+            ..Default::default()
+        })
+        .await;
+        let client = Client::default();
+        let sealed = client
+            .post(format!("http://{addr}/api/seal"))
+            .json(&json!({
+                "dek": "gVwG8pMMMtdq6mS0OW19Kn7XwvdUcFJpkYN8cEnwnvs",
+                "emails": ["alice@email.com"],
+                "max_unseals": 1,
+            }))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let unseal = |email: &str| {
+            client
+                .post(format!("http://{addr}/api/unseal"))
+                .header("Content-Type", "application/json")
+                .bearer_auth(bearer(email, "Name"))
+                .body(sealed.clone())
+                .send()
+        };
+
+        // A non-recipient's attempt doesn't count.
+        let resp = unseal("eve@email.com").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = unseal("alice@email.com").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let envelope = resp.json::<Value>().await.unwrap();
+        assert_eq!(envelope["max_unseals"], 1);
+        assert!(envelope["id"].is_string());
+        let resp = unseal("alice@email.com").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::GONE);
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn limits_need_a_store() {
+        let (server, addr) = start_server().await;
+        let resp = Client::default()
+            .post(format!("http://{addr}/api/seal"))
+            .json(&json!({
+                "dek": "gVwG8pMMMtdq6mS0OW19Kn7XwvdUcFJpkYN8cEnwnvs",
+                "emails": ["alice@email.com"],
+                "max_unseals": 1,
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: InvalidEnvelope = resp.json().await.unwrap();
+        let codes: Vec<_> = body
+            .violations
+            .iter()
+            .map(|violation| (violation.field.as_str(), violation.code))
+            .collect();
+        assert_eq!(
+            codes,
+            [("max_unseals", ViolationCode::UnsupportedMaxUnseals)]
+        );
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    /// Fails to append while `failing` is set.
+    #[derive(Default)]
+    struct FlakyAuditLog {
+        failing: AtomicBool,
+    }
+
+    impl AuditLog for FlakyAuditLog {
+        fn append(&self, _: &AuditEvent) -> Result<()> {
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(anyhow!("disk full"));
+            }
+            Ok(())
+        }
+
+        fn for_envelope(&self, _: &str) -> Result<Vec<AuditEvent>> {
+            Ok(Vec::new())
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn unrecorded_unseals_dont_count() {
+        let audit_log = Arc::new(FlakyAuditLog::default());
+        let (server, addr) = start_server_with(Config {
+            blob_store: Some(Arc::new(MemoryBlobStore::default())),
+            audit_log: Some(audit_log.clone()),
+            ..Default::default()
+        })
+        .await;
+        let client = Client::default();
+        let sealed = client
+            .post(format!("http://{addr}/api/seal"))
+            .json(&json!({
+                "dek": "gVwG8pMMMtdq6mS0OW19Kn7XwvdUcFJpkYN8cEnwnvs",
+                "emails": ["alice@email.com"],
+                "max_unseals": 1,
+            }))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let unseal = || {
+            client
+                .post(format!("http://{addr}/api/unseal"))
+                .header("Content-Type", "application/json")
+                .bearer_auth(bearer("alice@email.com", "Alice"))
+                .body(sealed.clone())
+                .send()
+        };
+
+        audit_log.failing.store(true, Ordering::SeqCst);
+        let resp = unseal().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        audit_log.failing.store(false, Ordering::SeqCst);
+        assert_eq!(unseal().await.unwrap().status(), StatusCode::OK);
+        assert_eq!(unseal().await.unwrap().status(), StatusCode::GONE);
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn sender_revokes_envelope() {
+        let audit_log = Arc::new(MemoryAuditLog::default());
+        let (server, addr) = start_server_with(Config {
+            blob_store: Some(Arc::new(MemoryBlobStore::default())),
+            audit_log: Some(audit_log.clone()),
+            ..Default::default()
+        })
+        .await;
+        let client = Client::default();
+        let seal = |token: Option<String>| {
+            let mut request = client
+                .post(format!("http://{addr}/api/seal"))
+                .header("Content-Type", "application/json")
+                .body(ALICE_ENVELOPE);
+            if let Some(token) = token {
+                request = request.bearer_auth(token);
+            }
+            request.send()
+        };
+        let unseal = |sealed: &str| {
+            client
+                .post(format!("http://{addr}/api/unseal"))
+                .header("Content-Type", "application/json")
+                .bearer_auth(bearer("alice@email.com", "Alice"))
+                .body(sealed.to_string())
+                .send()
+        };
+        let revoke = |id: &str, email: &str| {
+            client
+                .delete(format!("http://{addr}/api/envelopes/{id}"))
+                .bearer_auth(bearer(email, "Name"))
+                .send()
+        };
+
+        let sealed = seal(Some(bearer("bob@email.com", "Bob")))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let id = serde_json::from_str::<Value>(&sealed).unwrap()["id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(unseal(&sealed).await.unwrap().status(), StatusCode::OK);
+
+        let resp = revoke(&id, "alice@email.com").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = revoke(&id, "bob@email.com").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(unseal(&sealed).await.unwrap().status(), StatusCode::GONE);
+        // Revoking again is no error.
+        let resp = revoke(&id, "bob@email.com").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let revocations: Vec<_> = audit_log
+            .for_envelope(&id)
+            .unwrap()
+            .into_iter()
+            .filter(|event| event.action == Action::Revoke)
+            .map(|event| (event.email.unwrap(), event.outcome))
+            .collect();
+        assert_eq!(
+            revocations,
+            [
+                ("alice@email.com".into(), Outcome::Invalid),
+                ("bob@email.com".into(), Outcome::Revoked),
+                ("bob@email.com".into(), Outcome::Revoked),
+            ]
+        );
+
+        // Envelopes sealed anonymously have no sender to revoke them.
+        let sealed = seal(None).await.unwrap().text().await.unwrap();
+        let id = serde_json::from_str::<Value>(&sealed).unwrap()["id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let resp = revoke(&id, "bob@email.com").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // A bad token on seal is rejected rather than ignored.
+        let resp = seal(Some("not a token".into())).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn sender_sees_who_accessed_envelope() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let (server, addr) = start_server_with(Config {
+            blob_store: Some(Arc::new(MemoryBlobStore::default())),
+            audit_log: Some(Arc::new(
+                FileAuditLog::open(&path, SigningKey::from_bytes(&[7; 32])).unwrap(),
+            )),
+            ..Default::default()
+        })
+        .await;
+        let client = Client::default();
+        let sealed = client
+            .post(format!("http://{addr}/api/seal"))
+            .header("Content-Type", "application/json")
+            .bearer_auth(bearer("bob@email.com", "Bob"))
+            .body(ALICE_ENVELOPE)
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let id = serde_json::from_str::<Value>(&sealed).unwrap()["id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        for (email, status) in [
+            ("eve@email.com", StatusCode::UNAUTHORIZED),
+            ("alice@email.com", StatusCode::OK),
+        ] {
+            let resp = client
+                .post(format!("http://{addr}/api/unseal"))
+                .header("Content-Type", "application/json")
+                .header("User-Agent", "test-agent")
+                .bearer_auth(bearer(email, "Name"))
+                .body(sealed.clone())
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), status);
+        }
+        let access = |email: &str| {
+            client
+                .get(format!("http://{addr}/api/envelopes/{id}/access"))
+                .bearer_auth(bearer(email, "Name"))
+                .send()
+        };
+
+        let resp = access("alice@email.com").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = access("bob@email.com").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let events: Vec<AuditEvent> = resp.json().await.unwrap();
+        let attempts: Vec<_> = events
+            .iter()
+            .map(|event| (event.email.as_deref().unwrap(), event.outcome))
+            .collect();
+        assert_eq!(
+            attempts,
+            [
+                ("eve@email.com", Outcome::NotRecipient),
+                ("alice@email.com", Outcome::Unsealed)
+            ]
+        );
+        for event in &events {
+            assert_eq!(event.envelope.as_deref(), Some(id.as_str()));
+            assert_eq!(event.kid.as_deref(), Some("v1"));
+            assert_eq!(event.requester.user_agent.as_deref(), Some("test-agent"));
+            assert!(
+                event
+                    .requester
+                    .ip
+                    .as_ref()
+                    .unwrap()
+                    .starts_with("127.0.0.1:")
+            );
+        }
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn seal_and_unseal_fire_webhooks() {
+        let (url, mut received) = webhook::testing::receiver(StatusCode::OK).await;
+        let (server, addr) = start_server_with(Config {
+            webhooks: Some(Arc::new(Webhooks::new(vec![url], b"secret"))),
+            ..Default::default()
+        })
+        .await;
+        let client = Client::default();
+        let sealed = client
+            .post(format!("http://{addr}/api/seal"))
+            .header("Content-Type", "application/json")
+            .body(ALICE_ENVELOPE)
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        for email in ["eve@email.com", "alice@email.com", "bob@email.com"] {
+            client
+                .post(format!("http://{addr}/api/unseal"))
+                .header("Content-Type", "application/json")
+                .bearer_auth(bearer(email, "Name"))
+                .body(sealed.clone())
+                .send()
+                .await
+                .unwrap();
+        }
+
+        // Deliveries run concurrently, so may arrive in any order.
+        let mut events = Vec::new();
+        for _ in 0..4 {
+            let (_, body) = received.recv().await.unwrap();
+            let event: WebhookEvent = serde_json::from_slice(&body).unwrap();
+            events.push((event.kind, event.event.email));
+        }
+        for event in [
+            (EventType::Sealed, None),
+            (EventType::NotRecipient, Some("eve@email.com".into())),
+            (EventType::Unsealed, Some("alice@email.com".into())),
+            (EventType::NotRecipient, Some("bob@email.com".into())),
+        ] {
+            assert!(events.contains(&event), "{event:?} not in {events:?}");
+        }
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn streams_are_stored_and_fetched_by_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let (server, addr) = start_server_with(Config {
+            stream_store: Some(Arc::new(
+                StreamStore::new(dir.path()).unwrap().with_max_size(300_000),
+            )),
+            ..Default::default()
+        })
+        .await;
+        let key = stream::new_key();
+        let plaintext: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+        let mut encryptor = Encryptor::new(&key);
+        let mut pieces: Vec<Result<Vec<u8>>> = plaintext
+            .chunks(50_000)
+            .map(|data| encryptor.update(data))
+            .collect();
+        pieces.push(encryptor.finish());
+        let client = crate::client::Client::new(format!("http://{addr}"));
+        let id = client
+            .upload_stream(reqwest::Body::wrap_stream(futures_util::stream::iter(
+                pieces,
+            )))
+            .await
+            .unwrap();
+
+        let mut response = client.download_stream(&id).await.unwrap();
+        let mut decryptor = Decryptor::new(&key);
+        let mut decrypted = Vec::new();
+        while let Some(data) = response.chunk().await.unwrap() {
+            decrypted.extend(decryptor.update(&data).unwrap());
+        }
+        decrypted.extend(decryptor.finish().unwrap());
+        assert_eq!(decrypted, plaintext);
+
+        let resp = Client::default()
+            .post(format!("http://{addr}/api/streams"))
+            .body(vec![0; 300_001])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        // Only the stream uploaded in full is left.
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        let resp = Client::default()
+            .get(format!("http://{addr}/api/streams/AAAAAAAAAAAAAAAA"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn streams_are_refused_over_the_quota() {
+        let dir = tempfile::tempdir().unwrap();
+        let (server, addr) = start_server_with(Config {
+            stream_store: Some(Arc::new(
+                StreamStore::new(dir.path()).unwrap().with_quota(100),
+            )),
+            ..Default::default()
+        })
+        .await;
+        let put = |size| {
+            Client::default()
+                .post(format!("http://{addr}/api/streams"))
+                .body(vec![0; size])
+                .send()
+        };
+        assert_eq!(put(60).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(
+            put(60).await.unwrap().status(),
+            StatusCode::INSUFFICIENT_STORAGE
+        );
+        assert_eq!(put(40).await.unwrap().status(), StatusCode::OK);
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn anonymous_seals_need_a_solved_challenge() {
+        let (server, addr) = start_server_with(Config {
+            seal_challenge: Some(Arc::new(Challenger::new(&[0; 32], 4))),
+            ..Default::default()
+        })
+        .await;
+        let seal = |challenge: Option<String>| {
+            let mut request = Client::default()
+                .post(format!("http://{addr}/api/seal"))
+                .header("Content-Type", "application/json")
+                .body(ALICE_ENVELOPE);
+            if let Some(challenge) = challenge {
+                request = request.header(challenge::CHALLENGE_HEADER, challenge);
+            }
+            request.send()
+        };
+        let resp = seal(None).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PRECONDITION_REQUIRED);
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body, json!({"error": "Challenge Required"}));
+
+        let challenge: Challenge = Client::default()
+            .get(format!("http://{addr}/api/challenge"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let solution = format!("{}:{}", challenge.token, challenge::solve(&challenge));
+        let resp = seal(Some(solution.clone())).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = seal(Some(solution)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body, json!({"error": "Reused Challenge"}));
+
+        // Signed-in senders skip the challenge, and the client solves one when asked.
+        let resp = Client::default()
+            .post(format!("http://{addr}/api/seal"))
+            .bearer_auth(bearer("alice@email.com", "Alice"))
+            .header("Content-Type", "application/json")
+            .body(ALICE_ENVELOPE)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        crate::client::Client::new(format!("http://{addr}"))
+            .seal(&serde_json::from_str(ALICE_ENVELOPE).unwrap())
+            .await
+            .unwrap();
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn only_solved_challenges_raise_the_difficulty() {
+        let challenger = Arc::new(Challenger::new(&[0; 32], 4).with_load_target(1));
+        let (server, addr) = start_server_with(Config {
+            seal_challenge: Some(challenger.clone()),
+            ..Default::default()
+        })
+        .await;
+        // Genuine, but 0 solves one in 2^30 of them.
+        let hard = Challenger::new(&[0; 32], 30).issue();
+        let forged = Challenger::new(&[1; 32], 4).issue();
+        for header in [
+            None,
+            Some(format!("{}:0", hard.token)),
+            Some(format!("{}:{}", forged.token, challenge::solve(&forged))),
+            Some("malformed".into()),
+        ] {
+            for _ in 0..5 {
+                let mut request = Client::default()
+                    .post(format!("http://{addr}/api/seal"))
+                    .header("Content-Type", "application/json")
+                    .body(ALICE_ENVELOPE);
+                if let Some(header) = &header {
+                    request = request.header(challenge::CHALLENGE_HEADER, header);
+                }
+                assert!(!request.send().await.unwrap().status().is_success());
+            }
+        }
+        assert_eq!(challenger.difficulty(), 4);
+
+        let client = crate::client::Client::new(format!("http://{addr}"));
+        for _ in 0..2 {
+            client
+                .seal(&serde_json::from_str(ALICE_ENVELOPE).unwrap())
+                .await
+                .unwrap();
+        }
+        assert_eq!(challenger.difficulty(), 5);
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn seal_reports_each_violation() {
+        let (server, addr) = start_server_with(Config {
+            seal_policy: SealPolicy {
+                max_recipients: 2,
+                allowed_domains: vec!["email.com".into()],
+            },
+            ..Default::default()
+        })
+        .await;
+        let resp = Client::default()
+            .post(format!("http://{addr}/api/seal"))
+            .json(&json!({
+                "dek": "short",
+                "emails": ["alice@email.com", "Alice@email.com", "bob", "eve@evil.com"],
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: Value = resp.json().await.unwrap();
+        let violations: Vec<_> = body["violations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|violation| (violation["field"].clone(), violation["code"].clone()))
+            .collect();
+        assert_eq!(
+            violations,
+            [
+                (json!("dek"), json!("invalid_dek")),
+                (json!("emails"), json!("too_many_recipients")),
+                (json!("emails[2]"), json!("invalid_email")),
+                (json!("emails[3]"), json!("disallowed_domain")),
+            ]
+        );
+
+        let err = crate::client::Client::new(format!("http://{addr}"))
+            .seal(&Envelope {
+                dek: "gVwG8pMMMtdq6mS0OW19Kn7XwvdUcFJpkYN8cEnwnvs".into(),
+                emails: vec![],
+                max_unseals: None,
+                id: None,
+            })
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, crate::client::Error::InvalidEnvelope { violations, .. }
+                if violations[0].code == ViolationCode::NoRecipients),
+            "{err}"
+        );
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn recipients_differing_in_case_are_one_recipient() {
+        let (server, addr) = start_server().await;
+        let client = Client::default();
+        let sealed = client
+            .post(format!("http://{addr}/api/seal"))
+            .json(&json!({
+                "dek": "gVwG8pMMMtdq6mS0OW19Kn7XwvdUcFJpkYN8cEnwnvs",
+                "emails": ["Alice@Email.com", "bob@email.com", "alice@email.com"],
+            }))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        for email in ["alice@email.com", "ALICE@email.com", "bob@email.com"] {
+            let resp = client
+                .post(format!("http://{addr}/api/unseal"))
+                .header("Content-Type", "application/json")
+                .bearer_auth(bearer(email, "Name"))
+                .body(sealed.clone())
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK, "{email}");
+            let envelope: Value = resp.json().await.unwrap();
+            assert_eq!(
+                envelope["emails"],
+                json!(["Alice@Email.com", "bob@email.com"])
+            );
+        }
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn limited_envelope_needs_a_store() {
+        let (server, addr) = start_server().await;
+        let resp = Client::default()
+            .post(format!("http://{addr}/api/seal"))
+            .json(&json!({
+                "dek": "gVwG8pMMMtdq6mS0OW19Kn7XwvdUcFJpkYN8cEnwnvs",
+                "emails": ["alice@email.com"],
+                "max_unseals": 1,
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    fn write_tls_files(dir: &Path, name: &str) {
+        let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/testdata");
+        fs::copy(testdata.join(format!("{name}.crt")), dir.join("tls.crt")).unwrap();
+        fs::copy(testdata.join(format!("{name}.key")), dir.join("tls.key")).unwrap();
+    }
+
+    fn tls_client() -> Client {
+        Client::builder()
+            .add_root_certificate(
+                Certificate::from_pem(include_bytes!("testdata/tls-a.crt")).unwrap(),
+            )
+            .add_root_certificate(
+                Certificate::from_pem(include_bytes!("testdata/tls-b.crt")).unwrap(),
+            )
+            .tls_info(true)
+            .build()
+            .unwrap()
+    }
+
+    async fn start_tls_server(dir: &Path) -> (ServerHandle, SocketAddr) {
+        let (server, addr) = start_server_with(crate::Config {
+            tls: Some(TlsConfig {
+                cert_path: dir.join("tls.crt"),
+                key_path: dir.join("tls.key"),
+                reload_interval: Duration::from_millis(50),
+                hsts_max_age: Some(Duration::from_secs(31536000)),
+            }),
+            ..Default::default()
+        })
+        .await;
+        let listener::Addr::Tcp(addr) = addr else {
+            panic!("Expected a TCP address, got {addr}");
+        };
+        (server, addr)
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn post_seal_over_tls_succeeds() {
+        let dir = tempfile::tempdir().unwrap();
+        write_tls_files(dir.path(), "tls-a");
+        let (server, addr) = start_tls_server(dir.path()).await;
+
+        let resp = tls_client()
+            .post(format!("https://127.0.0.1:{}/api/seal", addr.port()))
+            .header("Content-Type", "application/json")
+            .body(ALICE_ENVELOPE)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.version(), Version::HTTP_2);
+        assert_eq!(
+            resp.headers()["strict-transport-security"],
+            "max-age=31536000; includeSubDomains"
+        );
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn tls_certificate_is_reloaded_when_files_change() {
+        let dir = tempfile::tempdir().unwrap();
+        write_tls_files(dir.path(), "tls-a");
+        let (server, addr) = start_tls_server(dir.path()).await;
+        // Use a fresh client each time so TLS session resumption doesn't hide the new certificate.
+        let peer_certificate = || async {
+            let resp = tls_client()
+                .get(format!("https://127.0.0.1:{}/robots.txt", addr.port()))
+                .send()
+                .await
+                .unwrap();
+            resp.extensions()
+                .get::<TlsInfo>()
+                .and_then(TlsInfo::peer_certificate)
+                .map(<[u8]>::to_vec)
+                .unwrap()
+        };
+        let cert_a = CertificateDer::from_pem_slice(include_bytes!("testdata/tls-a.crt")).unwrap();
+        let cert_b = CertificateDer::from_pem_slice(include_bytes!("testdata/tls-b.crt")).unwrap();
+        assert_eq!(peer_certificate().await, cert_a.as_ref());
+
+        write_tls_files(dir.path(), "tls-b");
+        let mut reloaded = false;
+        for _ in 0..100 {
+            if peer_certificate().await == cert_b.as_ref() {
+                reloaded = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(reloaded, "Server never picked up the new certificate");
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn post_seal_over_unix_socket_succeeds() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cipherly.sock");
+        let (server, addr) = start_server_with(crate::Config {
+            unix_socket: Some(path.clone()),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(addr.to_string(), format!("unix:{}", path.display()));
+
+        let resp = Client::builder()
+            .unix_socket(path.clone())
+            .build()
+            .unwrap()
+            .post("http://localhost/api/seal")
+            .header("Content-Type", "application/json")
+            .body(ALICE_ENVELOPE)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        server.shutdown_and_wait().await.unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn unix_socket_replaces_only_stale_sockets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cipherly.sock");
+        let stale = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        drop(stale);
+        let bind = || {
+            listener::Listener::bind(
+                listener::Bind::Unix(path.clone()),
+                None,
+                CancellationToken::new(),
+            )
+        };
+        drop(bind().await.unwrap());
+
+        fs::remove_file(&path).unwrap();
+        fs::write(&path, "not a socket").unwrap();
+        assert!(bind().await.is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not a socket");
+    }
+
+    #[cfg(feature = "embed-frontend")]
+    #[test_log::test(tokio::test)]
+    async fn get_embedded_frontend_supports_etags() {
+        // Without a frontend build there's nothing to serve.
+        if !crate::embedded::has_frontend() {
+            return;
+        }
+        let (server, addr) = start_server().await;
+        let client = Client::default();
+
+        let resp = client
+            .get(format!("http://{addr}/decrypt"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], "text/html");
+        assert_eq!(
+            resp.headers()["cache-control"],
+            "public, max-age=3600, stale-while-revalidate=3600"
+        );
+        let etag = resp.headers()["etag"].clone();
+
+        let resp = client
+            .get(format!("http://{addr}/decrypt"))
+            .header("If-None-Match", etag)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn post_unknown_api_route_returns_json_not_found() {
+        let (server, addr) = start_server().await;
+        let client = Client::default();
+
+        let resp = client
+            .post(format!("http://{addr}/api/seel"))
+            .header("Content-Type", "application/json")
+            .body(ALICE_ENVELOPE)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(resp.headers()["content-type"], "application/json");
+        assert_eq!(resp.text().await.unwrap(), r#"{"error":"Not Found"}"#);
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn get_unseal_returns_json_method_not_allowed() {
+        let (server, addr) = start_server().await;
+        let client = Client::default();
+
+        let resp = client
+            .get(format!("http://{addr}/api/unseal"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.headers()["allow"], "POST");
+        assert_eq!(resp.headers()["content-type"], "application/json");
+        assert_eq!(
+            resp.text().await.unwrap(),
+            r#"{"error":"Method Not Allowed"}"#
+        );
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn api_responses_include_security_headers() {
+        let (server, addr) = start_server().await;
+        let client = Client::default();
+
+        let resp = client
+            .post(format!("http://{addr}/api/seal"))
+            .header("Content-Type", "application/json")
+            .body(ALICE_ENVELOPE)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["cache-control"], "no-store");
+        assert_eq!(resp.headers()["referrer-policy"], "no-referrer");
+        assert_eq!(resp.headers()["x-content-type-options"], "nosniff");
+        assert_eq!(
+            resp.headers()["content-security-policy"],
+            headers::DEFAULT_CONTENT_SECURITY_POLICY
+        );
+        assert_eq!(
+            resp.headers()["permissions-policy"],
+            headers::DEFAULT_PERMISSIONS_POLICY
+        );
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn security_headers_can_be_disabled() {
+        let (server, addr) = start_server_with(crate::Config {
+            security_headers: SecurityHeaders {
+                content_security_policy: None,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .await;
+        let client = Client::default();
+
+        let resp = client
+            .get(format!("http://{addr}/robots.txt"))
+            .send()
+            .await
+            .unwrap();
+        assert!(!resp.headers().contains_key("content-security-policy"));
+        assert_eq!(resp.headers()["referrer-policy"], "no-referrer");
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn post_to_frontend_returns_method_not_allowed() {
+        let (server, addr) = start_server().await;
+        let client = Client::default();
+
+        let resp = client
+            .post(format!("http://{addr}/decrypt"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn router_can_be_nested_in_another_app() {
+        let cipherly = router(Config {
+            key_set: Some(new_fake_key_set(true).unwrap()),
+            keks: Some(kek::parse(TEST_KEK).unwrap()),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let app = axum::Router::new().nest("/cipherly", cipherly).layer(
+            SetResponseHeaderLayer::overriding(
+                HeaderName::from_static("x-gateway"),
+                HeaderValue::from_static("1"),
+            ),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let resp = Client::default()
+            .post(format!("http://{addr}/cipherly/api/seal"))
+            .header("Content-Type", "application/json")
+            .body(ALICE_ENVELOPE)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["x-gateway"], "1");
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn sealed_server_unseals_with_shares() {
+        let (file, shares) = kek::split_key_ring(TEST_KEK, 2, 3).unwrap();
+        let (server, addr) = start_server_with(Config {
+            vault: Some(Vault::sealed(file).unwrap()),
+            admins: Admins {
+                emails: vec!["alice@email.com".into(), "Bob@email.com".into()],
+                local_only: false,
+            },
+            ..Default::default()
+        })
+        .await;
+        let client = Client::default();
+        let seal = || {
+            client
+                .post(format!("http://{addr}/api/seal"))
+                .header("Content-Type", "application/json")
+                .body(ALICE_ENVELOPE)
+                .send()
+        };
+        let add_share = |share: &str, email: Option<&str>| {
+            let mut request = client
+                .post(format!("http://{addr}/api/admin/unseal"))
+                .json(&json!({ "share": share }));
+            if let Some(email) = email {
+                request = request.bearer_auth(bearer(email, "Name"));
+            }
+            request.send()
+        };
+
+        let resp = seal().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // Only admins may submit shares, so nobody else can use up or spoil the count.
+        let resp = add_share(&shares[1].encode(), None).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = add_share(&shares[1].encode(), Some("eve@email.com"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = add_share(&shares[0].encode(), Some("alice@email.com"))
+            .await
+            .unwrap();
+        assert_eq!(
+            resp.json::<Value>().await.unwrap(),
+            json!({ "sealed": true, "shares": 1, "threshold": 2 })
+        );
+        // Each admin submits one share.
+        let resp = add_share(&shares[1].encode(), Some("alice@email.com"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = add_share(&shares[0].encode(), Some("bob@email.com"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp = add_share(&shares[2].encode(), Some("bob@email.com"))
+            .await
+            .unwrap();
+        assert_eq!(
+            resp.json::<Value>().await.unwrap(),
+            json!({ "sealed": false })
+        );
+        let resp = seal().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn blobs_are_stored_and_fetched_by_id() {
+        let (server, addr) = start_server_with(Config {
+            blob_store: Some(Arc::new(MemoryBlobStore::default())),
+            ..Default::default()
+        })
+        .await;
+        let client = Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+
+        let resp = client
+            .post(format!("http://{addr}/api/blobs"))
+            .body("ciphertext")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let id = resp.json::<Value>().await.unwrap()["id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let resp = client
+            .get(format!("http://{addr}/api/blobs/{id}"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["cache-control"], "no-store");
+        assert_eq!(resp.bytes().await.unwrap(), "ciphertext");
+
+        let resp = client
+            .get(format!("http://{addr}/s/{id}"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert_eq!(resp.headers()["location"], format!("/decrypt?s={id}"));
+
+        let resp = client
+            .post(format!("http://{addr}/api/blobs?max_views=1"))
+            .body("burn after reading")
+            .send()
+            .await
+            .unwrap();
+        let id = resp.json::<Value>().await.unwrap()["id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let fetch = || client.get(format!("http://{addr}/api/blobs/{id}")).send();
+        assert_eq!(fetch().await.unwrap().status(), StatusCode::OK);
+        assert_eq!(fetch().await.unwrap().status(), StatusCode::NOT_FOUND);
+
+        for path in ["/api/blobs/AAAAAAAAAAAAAAAA", "/api/blobs/..%2Fsecret"] {
+            let resp = client
+                .get(format!("http://{addr}{path}"))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{path}");
+        }
+
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn blobs_are_refused_over_the_quota() {
+        let (server, addr) = start_server_with(Config {
+            blob_store: Some(Arc::new(MemoryBlobStore::default())),
+            blob_limits: BlobLimits {
+                quota: 15,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .await;
+        let put = |body: &'static str| {
+            Client::default()
+                .post(format!("http://{addr}/api/blobs"))
+                .body(body)
+                .send()
+        };
+        assert_eq!(put("ciphertext").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(put("12345").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(
+            put("1").await.unwrap().status(),
+            StatusCode::INSUFFICIENT_STORAGE
+        );
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn blobs_are_not_found_without_a_store() {
+        let (server, addr) = start_server().await;
+        let resp = Client::default()
+            .post(format!("http://{addr}/api/blobs"))
+            .body("ciphertext")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        server.shutdown_and_wait().await.unwrap();
+    }
+
+    struct ServerHandle {
+        closer: CancellationToken,
+        serve: JoinHandle<Result<()>>,
+    }
+
+    impl ServerHandle {
+        async fn shutdown_and_wait(mut self) -> Result<()> {
+            self.start_shutdown().await;
+            self.wait_for_shutdown().await
+        }
+
+        async fn start_shutdown(&mut self) {
+            tracing::info!("Sending server shutdown signal...");
+            self.closer.cancel();
+        }
+
+        async fn wait_for_shutdown(self) -> Result<()> {
+            match tokio::time::timeout(Duration::from_secs(20), self.serve).await {
+                Ok(_) => Ok(()),
+                Err(e) => Err(anyhow!(
+                    "Timed out waiting for shutdown after 20 seconds: {e}"
+                )),
+            }
+        }
+    }
+
+    async fn start_server() -> (ServerHandle, SocketAddr) {
+        let (server, addr) = start_server_with(Config::default()).await;
+        let listener::Addr::Tcp(addr) = addr else {
+            panic!("Expected a TCP address, got {addr}");
+        };
+        (server, addr)
+    }
+
+    async fn start_server_with(config: Config) -> (ServerHandle, listener::Addr) {
+        let key_set = new_fake_key_set(true).unwrap();
+        let keks = kek::parse(TEST_KEK).unwrap();
+
+        let cancel = CancellationToken::new();
+        let (addr, serve) = run_server(Config {
+            port: Some(0),
+            key_set: Some(key_set),
+            enable_test_creds: true,
+            keks: Some(keks),
+            shutdown_signal: cancel.clone(),
+            ..config
+        })
+        .await
+        .unwrap();
+
+        (
+            ServerHandle {
+                closer: cancel,
+                serve,
+            },
+            addr,
+        )
+    }
+}
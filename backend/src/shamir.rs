@@ -0,0 +1,158 @@
+//! Shamir's secret sharing over GF(2^8), for splitting the master key that protects the KEKs.
+//! Each byte of the secret is the constant term of its own random polynomial of degree
+//! `threshold - 1`; a share is every polynomial evaluated at the share's nonzero `x`.
+
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use anyhow::{Result, anyhow};
+use base64::prelude::*;
+use zeroize::Zeroizing;
+
+/// One share of a secret. Encoded as base64url of `x` followed by the polynomials' values.
+#[derive(Clone)]
+pub struct Share {
+    pub x: u8,
+    pub y: Zeroizing<Vec<u8>>,
+}
+
+impl Share {
+    pub fn encode(&self) -> Zeroizing<String> {
+        let mut bytes = Zeroizing::new(Vec::with_capacity(self.y.len() + 1));
+        bytes.push(self.x);
+        bytes.extend_from_slice(&self.y);
+        Zeroizing::new(BASE64_URL_SAFE_NO_PAD.encode(bytes.as_slice()))
+    }
+
+    pub fn decode(share: &str) -> Result<Share> {
+        let bytes = Zeroizing::new(
+            BASE64_URL_SAFE_NO_PAD
+                .decode(share.trim())
+                .map_err(|_| anyhow!("Share is not valid base64url"))?,
+        );
+        match bytes.split_first() {
+            Some((&x, y)) if x != 0 && !y.is_empty() => Ok(Share {
+                x,
+                y: Zeroizing::new(y.to_vec()),
+            }),
+            _ => Err(anyhow!("Share is malformed")),
+        }
+    }
+}
+
+/// Multiplies in GF(2^8) with the AES polynomial, without branching on the operands.
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    for _ in 0..8 {
+        product ^= a & 0u8.wrapping_sub(b & 1);
+        let carry = 0u8.wrapping_sub(a >> 7);
+        a = (a << 1) ^ (carry & 0x1b);
+        b >>= 1;
+    }
+    product
+}
+
+/// The multiplicative inverse, as `a^254`. Zero maps to zero.
+fn inv(a: u8) -> u8 {
+    let mut result = 1;
+    let mut power = a;
+    for bit in 0..8 {
+        if (254 >> bit) & 1 == 1 {
+            result = mul(result, power);
+        }
+        power = mul(power, power);
+    }
+    result
+}
+
+/// Splits `secret` into `shares` shares, any `threshold` of which recover it.
+pub fn split(secret: &[u8], threshold: u8, shares: u8) -> Result<Vec<Share>> {
+    if threshold < 2 || threshold > shares {
+        return Err(anyhow!(
+            "Threshold must be at least 2 and at most the number of shares"
+        ));
+    }
+    let mut coefficients = Zeroizing::new(vec![0; usize::from(threshold - 1)]);
+    let mut ys = vec![Zeroizing::new(vec![0; secret.len()]); usize::from(shares)];
+    for (i, &byte) in secret.iter().enumerate() {
+        OsRng.fill_bytes(&mut coefficients);
+        for (x, y) in (1..=shares).zip(ys.iter_mut()) {
+            // Horner's method, from the highest coefficient down to the secret.
+            y[i] = coefficients
+                .iter()
+                .rev()
+                .fold(0, |acc, &coefficient| mul(acc, x) ^ coefficient);
+            y[i] = mul(y[i], x) ^ byte;
+        }
+    }
+    Ok((1..=shares).zip(ys).map(|(x, y)| Share { x, y }).collect())
+}
+
+/// Recovers the secret from at least the threshold number of shares. With fewer shares, or a
+/// share of another secret, the result is garbage rather than an error.
+pub fn combine(shares: &[Share]) -> Result<Zeroizing<Vec<u8>>> {
+    let len = shares.first().ok_or_else(|| anyhow!("No shares"))?.y.len();
+    for (i, share) in shares.iter().enumerate() {
+        if share.x == 0 || share.y.len() != len {
+            return Err(anyhow!("Shares are not of the same secret"));
+        }
+        if shares[..i].iter().any(|other| other.x == share.x) {
+            return Err(anyhow!("Share {} was given twice", share.x));
+        }
+    }
+    let mut secret = Zeroizing::new(vec![0; len]);
+    for share in shares {
+        // The Lagrange basis polynomial for this share, evaluated at zero.
+        let basis = shares
+            .iter()
+            .filter(|other| other.x != share.x)
+            .fold(1, |acc, other| {
+                mul(acc, mul(other.x, inv(other.x ^ share.x)))
+            });
+        for (byte, &y) in secret.iter_mut().zip(share.y.iter()) {
+            *byte ^= mul(basis, y);
+        }
+    }
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Share, combine, inv, mul, split};
+
+    #[test]
+    fn inverse_multiplies_to_one() {
+        for a in 1..=255 {
+            assert_eq!(mul(a, inv(a)), 1);
+        }
+    }
+
+    #[test]
+    fn any_threshold_shares_recover_the_secret() {
+        let secret = b"0123456789abcdef0123456789abcdef";
+        let shares = split(secret, 3, 5).unwrap();
+        assert_eq!(shares.len(), 5);
+        for combination in [[0, 1, 2], [4, 2, 0], [1, 3, 4]] {
+            let subset: Vec<Share> = combination.iter().map(|&i| shares[i].clone()).collect();
+            assert_eq!(combine(&subset).unwrap().as_slice(), secret);
+        }
+        assert_ne!(combine(&shares[..2]).unwrap().as_slice(), secret);
+    }
+
+    #[test]
+    fn shares_round_trip_through_encoding() {
+        let shares = split(b"secret", 2, 2).unwrap();
+        let decoded: Vec<Share> = shares
+            .iter()
+            .map(|share| Share::decode(&share.encode()).unwrap())
+            .collect();
+        assert_eq!(combine(&decoded).unwrap().as_slice(), b"secret");
+    }
+
+    #[test]
+    fn invalid_splits_and_shares_are_rejected() {
+        assert!(split(b"secret", 1, 3).is_err());
+        assert!(split(b"secret", 4, 3).is_err());
+        let shares = split(b"secret", 2, 3).unwrap();
+        assert!(combine(&[shares[0].clone(), shares[0].clone()]).is_err());
+        assert!(Share::decode("AA").is_err());
+    }
+}
@@ -0,0 +1,991 @@
+//! Optional storage for payloads too long to carry in a link. Clients encrypt a payload with a
+//! random key before uploading it and keep the key in the link's fragment, so the server only
+//! ever holds ciphertext: `/s/<id>#<key>`.
+//!
+//! A record may be limited to a number of reads, after which it is deleted. The server also
+//! keeps records about envelopes here: an empty one with such a limit for each envelope sealed
+//! with a maximum number of unseals, the email address of each signed-in sender, and a marker
+//! for each revoked envelope.
+//!
+//! Uploads are anonymous, so [`BlobLimits`] caps the bytes they can take up in total and how
+//! long they're kept. Records about envelopes never expire, and don't count against the quota.
+//!
+//! Files too large to hold in memory go to a [`StreamStore`] instead, a piece at a time, with a
+//! quota and expiry of its own.
+
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use anyhow::{Context as _, Result, anyhow};
+use base64::prelude::*;
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::{self, Write as _},
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+use tokio::{io::AsyncWriteExt as _, time::MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+
+/// Stores blobs by id. Calls block, so the server runs them on the blocking thread pool.
+pub trait BlobStore: Send + Sync {
+    /// Stores `blob`, to be read at most `max_reads` times if set, replacing any blob under `id`.
+    fn put(&self, id: &str, blob: &[u8], max_reads: Option<u32>) -> Result<()>;
+    /// Reads a blob. A read of a limited blob counts against its limit, and the read that uses
+    /// the limit up deletes it; no two callers can get the same last read.
+    fn get(&self, id: &str) -> Result<Option<Vec<u8>>>;
+    /// Undoes a [`BlobStore::get`] of limited blob `id`, which read `blob`: gives back the read
+    /// it counted, putting the blob back with one read left if that read deleted it.
+    fn give_back(&self, id: &str, blob: &[u8]) -> Result<()>;
+    /// Total bytes of the blobs stored, not counting records.
+    fn size(&self) -> Result<u64>;
+    /// Deletes the blobs stored before `cutoff`, returning how many. Records are kept.
+    fn delete_before(&self, cutoff: SystemTime) -> Result<usize>;
+}
+
+/// Length of a blob id: 12 random bytes, base64url encoded.
+const ID_LEN: usize = 16;
+
+/// A new random blob id.
+pub fn new_id() -> String {
+    let mut id = [0; ID_LEN / 4 * 3];
+    OsRng.fill_bytes(&mut id);
+    BASE64_URL_SAFE_NO_PAD.encode(id)
+}
+
+/// Whether `id` could have come from [`new_id`]. Ids name files in [`DirBlobStore`], so
+/// anything else is rejected before it reaches a store.
+pub fn is_valid_id(id: &str) -> bool {
+    id.len() == ID_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// The record counting the unseals of envelope `id`. No envelope record can be a valid blob
+/// id, so none can be fetched, or used up, through `/api/blobs`.
+pub fn envelope_record(id: &str) -> String {
+    format!("envelope-{id}")
+}
+
+/// The record holding the email address of the sender of envelope `id`.
+pub fn sender_record(id: &str) -> String {
+    format!("sender-{id}")
+}
+
+/// The record marking envelope `id` as revoked.
+pub fn revoked_record(id: &str) -> String {
+    format!("revoked-{id}")
+}
+
+/// Reads `BLOB_STORE`: `memory`, `dir:<path>` or, with the `sqlite` feature, `sqlite:<path>`.
+/// Storage is disabled when it's unset.
+pub fn from_env() -> Result<Option<Arc<dyn BlobStore>>> {
+    let Ok(spec) = env::var("BLOB_STORE") else {
+        return Ok(None);
+    };
+    let store: Arc<dyn BlobStore> = match spec.split_once(':') {
+        None if spec == "memory" => Arc::new(MemoryBlobStore::default()),
+        Some(("dir", path)) => Arc::new(DirBlobStore::new(path)?),
+        #[cfg(feature = "sqlite")]
+        Some(("sqlite", path)) => Arc::new(SqliteBlobStore::open(path)?),
+        _ => {
+            return Err(anyhow!(
+                "BLOB_STORE should be memory, dir:<path> or sqlite:<path>"
+            ));
+        }
+    };
+    tracing::info!("Storing blobs in {spec}");
+    Ok(Some(store))
+}
+
+/// Bounds what anonymous uploads to a [`BlobStore`] can take up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlobLimits {
+    /// Total bytes of blobs to keep. Uploads beyond it are refused until some expire.
+    pub quota: u64,
+    /// How long to keep a blob, however many reads it has left.
+    pub ttl: Duration,
+}
+
+/// Total bytes of blobs a store keeps by default: 1 GiB.
+pub const DEFAULT_BLOB_QUOTA: u64 = 1 << 30;
+
+/// How long a store keeps blobs by default: a week.
+pub const DEFAULT_BLOB_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+impl Default for BlobLimits {
+    fn default() -> Self {
+        BlobLimits {
+            quota: DEFAULT_BLOB_QUOTA,
+            ttl: DEFAULT_BLOB_TTL,
+        }
+    }
+}
+
+impl BlobLimits {
+    /// Reads `BLOB_QUOTA_MB` and `BLOB_TTL_HOURS`, falling back to the defaults for unset
+    /// variables.
+    pub fn from_env() -> Result<BlobLimits> {
+        let mut limits = BlobLimits::default();
+        if let Some(quota) = positive_var("BLOB_QUOTA_MB")? {
+            limits.quota = quota << 20;
+        }
+        if let Some(hours) = positive_var("BLOB_TTL_HOURS")? {
+            limits.ttl = Duration::from_secs(hours * 60 * 60);
+        }
+        Ok(limits)
+    }
+
+    /// Whether a blob of `size` bytes fits beside the `stored` bytes already kept.
+    pub fn fits(&self, stored: u64, size: u64) -> bool {
+        stored.saturating_add(size) <= self.quota
+    }
+}
+
+fn positive_var(name: &str) -> Result<Option<u64>> {
+    let Ok(value) = env::var(name) else {
+        return Ok(None);
+    };
+    value
+        .parse()
+        .ok()
+        .filter(|value| *value > 0)
+        .map(Some)
+        .with_context(|| format!("{name} should be a positive number"))
+}
+
+/// How often the server deletes expired blobs and streams.
+pub const SWEEP_PERIOD: Duration = Duration::from_secs(60);
+
+/// Runs `sweep`, which returns how many `what` it deleted, on the blocking thread pool every
+/// `period` until `shutdown` is cancelled.
+pub async fn sweep_periodically(
+    what: &'static str,
+    sweep: impl Fn() -> Result<usize> + Clone + Send + 'static,
+    period: Duration,
+    shutdown: CancellationToken,
+) {
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.cancelled() => return,
+        }
+        match tokio::task::spawn_blocking(sweep.clone()).await {
+            Ok(Ok(0)) => {}
+            Ok(Ok(deleted)) => tracing::info!("Deleted {deleted} expired {what}"),
+            Ok(Err(err)) => tracing::error!("Failed to delete expired {what}: {err:#}"),
+            Err(err) => tracing::error!("Failed to delete expired {what}: {err}"),
+        }
+    }
+}
+
+/// Keeps blobs until the server exits. For development and tests.
+#[derive(Default)]
+pub struct MemoryBlobStore {
+    blobs: Mutex<HashMap<String, Record>>,
+}
+
+struct Record {
+    blob: Vec<u8>,
+    reads_left: Option<u32>,
+    stored: SystemTime,
+}
+
+impl BlobStore for MemoryBlobStore {
+    fn put(&self, id: &str, blob: &[u8], max_reads: Option<u32>) -> Result<()> {
+        self.blobs
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .insert(
+                id.into(),
+                Record {
+                    blob: blob.into(),
+                    reads_left: max_reads,
+                    stored: SystemTime::now(),
+                },
+            );
+        Ok(())
+    }
+
+    fn get(&self, id: &str) -> Result<Option<Vec<u8>>> {
+        let mut blobs = self.blobs.lock().unwrap_or_else(|err| err.into_inner());
+        let Some(record) = blobs.get_mut(id) else {
+            return Ok(None);
+        };
+        match &mut record.reads_left {
+            Some(0 | 1) => Ok(blobs.remove(id).map(|record| record.blob)),
+            Some(reads_left) => {
+                *reads_left -= 1;
+                Ok(Some(record.blob.clone()))
+            }
+            None => Ok(Some(record.blob.clone())),
+        }
+    }
+
+    fn give_back(&self, id: &str, blob: &[u8]) -> Result<()> {
+        let mut blobs = self.blobs.lock().unwrap_or_else(|err| err.into_inner());
+        let record = blobs.entry(id.into()).or_insert_with(|| Record {
+            blob: blob.into(),
+            reads_left: Some(0),
+            stored: SystemTime::now(),
+        });
+        if let Some(reads_left) = &mut record.reads_left {
+            *reads_left += 1;
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self
+            .blobs
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .iter()
+            .filter(|(id, _)| is_valid_id(id))
+            .map(|(_, record)| record.blob.len() as u64)
+            .sum())
+    }
+
+    fn delete_before(&self, cutoff: SystemTime) -> Result<usize> {
+        let mut blobs = self.blobs.lock().unwrap_or_else(|err| err.into_inner());
+        let before = blobs.len();
+        blobs.retain(|id, record| !is_valid_id(id) || record.stored >= cutoff);
+        Ok(before - blobs.len())
+    }
+}
+
+/// Keeps each blob in a file named by its id, and the reads left of a limited blob in
+/// `<id>.reads` beside it.
+pub struct DirBlobStore {
+    dir: PathBuf,
+    /// Serializes puts and reads of limited blobs.
+    reads: Mutex<()>,
+}
+
+impl DirBlobStore {
+    /// Opens `dir`, deleting any partial files left by a server that exited mid-write.
+    pub fn new(dir: impl Into<PathBuf>) -> Result<DirBlobStore> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        let context = || format!("Failed to list {}", dir.display());
+        for entry in fs::read_dir(&dir).with_context(context)? {
+            let entry = entry.with_context(context)?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with('.') && name.ends_with(".tmp") {
+                fs::remove_file(entry.path())
+                    .with_context(|| format!("Failed to delete {}", entry.path().display()))?;
+            }
+        }
+        Ok(DirBlobStore {
+            dir,
+            reads: Mutex::new(()),
+        })
+    }
+}
+
+impl DirBlobStore {
+    /// Writes under a temporary name so a reader never sees a partial file. The name is unique
+    /// to the write, so neither another write nor one cut short can get in its way.
+    fn write(&self, name: &str, data: &[u8]) -> Result<()> {
+        let path = self.dir.join(name);
+        let tmp = self.dir.join(format!(".{name}.{}.tmp", new_id()));
+        fs::File::create_new(&tmp)
+            .and_then(|mut file| {
+                file.write_all(data)?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp, &path))
+            .inspect_err(|_| {
+                let _ = fs::remove_file(&tmp);
+            })
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.dir.join(name);
+        match fs::read(&path) {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    /// The blobs in the directory, with their metadata. Records and `.reads` files are skipped.
+    fn blobs(&self) -> Result<Vec<(String, fs::Metadata)>> {
+        let context = || format!("Failed to list {}", self.dir.display());
+        let mut blobs = Vec::new();
+        for entry in fs::read_dir(&self.dir).with_context(context)? {
+            let entry = entry.with_context(context)?;
+            let Some(id) = entry
+                .file_name()
+                .to_str()
+                .filter(|id| is_valid_id(id))
+                .map(String::from)
+            else {
+                continue;
+            };
+            match entry.metadata() {
+                Ok(metadata) => blobs.push((id, metadata)),
+                // Read to its last and deleted since it was listed.
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err).with_context(context),
+            }
+        }
+        Ok(blobs)
+    }
+
+    /// Stores `blob` and its limit, removing any left from a blob it replaces. The caller holds
+    /// `reads`.
+    fn store(&self, id: &str, blob: &[u8], max_reads: Option<u32>) -> Result<()> {
+        let reads_file = format!("{id}.reads");
+        match max_reads {
+            // The limit goes first, so the blob is never readable without it.
+            Some(max_reads) => self.write(&reads_file, max_reads.to_string().as_bytes())?,
+            None => self.remove(&reads_file)?,
+        }
+        self.write(id, blob)
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        match fs::remove_file(self.dir.join(name)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                Err(err).with_context(|| format!("Failed to delete {name}"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl BlobStore for DirBlobStore {
+    fn put(&self, id: &str, blob: &[u8], max_reads: Option<u32>) -> Result<()> {
+        let _reads = self.reads.lock().unwrap_or_else(|err| err.into_inner());
+        self.store(id, blob, max_reads)
+    }
+
+    fn get(&self, id: &str) -> Result<Option<Vec<u8>>> {
+        let _reads = self.reads.lock().unwrap_or_else(|err| err.into_inner());
+        let Some(blob) = self.read(id)? else {
+            return Ok(None);
+        };
+        let reads_file = format!("{id}.reads");
+        let Some(reads_left) = self.read(&reads_file)? else {
+            return Ok(Some(blob));
+        };
+        let reads_left: u32 = std::str::from_utf8(&reads_left)
+            .ok()
+            .and_then(|reads_left| reads_left.parse().ok())
+            .with_context(|| format!("{reads_file} is malformed"))?;
+        if reads_left <= 1 {
+            fs::remove_file(self.dir.join(id))
+                .with_context(|| format!("Failed to delete blob {id}"))?;
+            fs::remove_file(self.dir.join(&reads_file))
+                .with_context(|| format!("Failed to delete {reads_file}"))?;
+        } else {
+            self.write(&reads_file, (reads_left - 1).to_string().as_bytes())?;
+        }
+        Ok(Some(blob))
+    }
+
+    fn give_back(&self, id: &str, blob: &[u8]) -> Result<()> {
+        let _reads = self.reads.lock().unwrap_or_else(|err| err.into_inner());
+        let reads_file = format!("{id}.reads");
+        if self.read(id)?.is_none() {
+            return self.store(id, blob, Some(1));
+        }
+        let Some(reads_left) = self.read(&reads_file)? else {
+            return Ok(());
+        };
+        let reads_left: u32 = std::str::from_utf8(&reads_left)
+            .ok()
+            .and_then(|reads_left| reads_left.parse().ok())
+            .with_context(|| format!("{reads_file} is malformed"))?;
+        self.write(&reads_file, (reads_left + 1).to_string().as_bytes())
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self
+            .blobs()?
+            .iter()
+            .map(|(_, metadata)| metadata.len())
+            .sum())
+    }
+
+    fn delete_before(&self, cutoff: SystemTime) -> Result<usize> {
+        let _reads = self.reads.lock().unwrap_or_else(|err| err.into_inner());
+        let mut deleted = 0;
+        for (id, metadata) in self.blobs()? {
+            if metadata.modified()? < cutoff {
+                // The blob goes first, so it's never readable without its limit.
+                self.remove(&id)?;
+                self.remove(&format!("{id}.reads"))?;
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+}
+
+/// Keeps blobs in a table of a SQLite database.
+#[cfg(feature = "sqlite")]
+pub struct SqliteBlobStore {
+    conn: Mutex<rusqlite::Connection>,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteBlobStore {
+    pub fn open(path: impl AsRef<std::path::Path>) -> Result<SqliteBlobStore> {
+        let path = path.as_ref();
+        let conn = rusqlite::Connection::open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS blobs (
+                id TEXT PRIMARY KEY,
+                blob BLOB NOT NULL,
+                reads_left INTEGER,
+                stored INTEGER NOT NULL DEFAULT (unixepoch())
+            )",
+        )?;
+        // Tables from before blobs expired lack `stored`; their blobs expire a TTL from now.
+        let has_stored: bool = conn.query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('blobs') WHERE name = 'stored'",
+            [],
+            |row| row.get(0),
+        )?;
+        if !has_stored {
+            conn.execute_batch(
+                "ALTER TABLE blobs ADD COLUMN stored INTEGER;
+                UPDATE blobs SET stored = unixepoch();",
+            )?;
+        }
+        Ok(SqliteBlobStore {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl BlobStore for SqliteBlobStore {
+    fn put(&self, id: &str, blob: &[u8], max_reads: Option<u32>) -> Result<()> {
+        self.conn
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .execute(
+                "INSERT OR REPLACE INTO blobs (id, blob, reads_left, stored) VALUES (?1, ?2, ?3, ?4)",
+                rusqlite::params![id, blob, max_reads, unix_time(SystemTime::now())],
+            )?;
+        Ok(())
+    }
+
+    fn get(&self, id: &str) -> Result<Option<Vec<u8>>> {
+        use rusqlite::OptionalExtension as _;
+        let mut conn = self.conn.lock().unwrap_or_else(|err| err.into_inner());
+        let tx = conn.transaction()?;
+        let Some((blob, reads_left)) = tx
+            .query_row(
+                "SELECT blob, reads_left FROM blobs WHERE id = ?1",
+                [id],
+                |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Option<u32>>(1)?)),
+            )
+            .optional()?
+        else {
+            return Ok(None);
+        };
+        match reads_left {
+            Some(reads_left) if reads_left <= 1 => {
+                tx.execute("DELETE FROM blobs WHERE id = ?1", [id])?;
+            }
+            Some(_) => {
+                tx.execute(
+                    "UPDATE blobs SET reads_left = reads_left - 1 WHERE id = ?1",
+                    [id],
+                )?;
+            }
+            None => {}
+        }
+        tx.commit()?;
+        Ok(Some(blob))
+    }
+
+    fn give_back(&self, id: &str, blob: &[u8]) -> Result<()> {
+        self.conn
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .execute(
+                "INSERT INTO blobs (id, blob, reads_left, stored) VALUES (?1, ?2, 1, ?3)
+                ON CONFLICT (id) DO UPDATE SET reads_left = reads_left + 1",
+                rusqlite::params![id, blob, unix_time(SystemTime::now())],
+            )?;
+        Ok(())
+    }
+
+    // Blob ids have a fixed length, and records are longer.
+    fn size(&self) -> Result<u64> {
+        Ok(self
+            .conn
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .query_row(
+                "SELECT COALESCE(SUM(LENGTH(blob)), 0) FROM blobs WHERE LENGTH(id) = ?1",
+                [ID_LEN],
+                |row| row.get(0),
+            )?)
+    }
+
+    fn delete_before(&self, cutoff: SystemTime) -> Result<usize> {
+        Ok(self
+            .conn
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .execute(
+                "DELETE FROM blobs WHERE LENGTH(id) = ?1 AND stored < ?2",
+                rusqlite::params![ID_LEN, unix_time(cutoff)],
+            )?)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn unix_time(time: SystemTime) -> i64 {
+    time.duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() as i64)
+}
+
+/// Keeps streams, blobs too large to hold in memory, as files in a directory. They're written and
+/// read a piece at a time.
+///
+/// Like blobs, streams count against a quota and expire. Bytes are counted as they're written, so
+/// concurrent uploads can't together exceed the quota.
+pub struct StreamStore {
+    dir: PathBuf,
+    max_size: u64,
+    quota: u64,
+    ttl: Duration,
+    /// Bytes of the streams stored and being written.
+    used: Arc<AtomicU64>,
+}
+
+/// The largest stream a [`StreamStore`] accepts by default: 8 GiB, for build artifacts and the
+/// like, within the default quota.
+pub const DEFAULT_MAX_STREAM_SIZE: u64 = 8 << 30;
+
+/// Total bytes of streams a [`StreamStore`] keeps by default: 10 GiB.
+pub const DEFAULT_STREAM_QUOTA: u64 = 10 << 30;
+
+/// How long a [`StreamStore`] keeps streams by default: a week.
+pub const DEFAULT_STREAM_TTL: Duration = DEFAULT_BLOB_TTL;
+
+/// Why a write to a [`NewStream`] failed when the [`StreamStore`] is at its quota.
+#[derive(Debug)]
+pub struct StoreFull;
+
+impl std::fmt::Display for StoreFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("the stream store is full")
+    }
+}
+
+impl std::error::Error for StoreFull {}
+
+impl StreamStore {
+    /// Opens `dir`, deleting any partial streams left by a server that exited mid-upload.
+    pub fn new(dir: impl Into<PathBuf>) -> Result<StreamStore> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        let context = || format!("Failed to list {}", dir.display());
+        let mut used = 0;
+        for entry in fs::read_dir(&dir).with_context(context)? {
+            let entry = entry.with_context(context)?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with('.') && name.ends_with(".tmp") {
+                fs::remove_file(entry.path())
+                    .with_context(|| format!("Failed to delete {}", entry.path().display()))?;
+            } else {
+                used += entry.metadata().with_context(context)?.len();
+            }
+        }
+        Ok(StreamStore {
+            dir,
+            max_size: DEFAULT_MAX_STREAM_SIZE,
+            quota: DEFAULT_STREAM_QUOTA,
+            ttl: DEFAULT_STREAM_TTL,
+            used: Arc::new(AtomicU64::new(used)),
+        })
+    }
+
+    pub fn with_max_size(mut self, max_size: u64) -> StreamStore {
+        self.max_size = max_size;
+        self
+    }
+
+    pub fn with_quota(mut self, quota: u64) -> StreamStore {
+        self.quota = quota;
+        self
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> StreamStore {
+        self.ttl = ttl;
+        self
+    }
+
+    /// The largest stream to accept, in bytes.
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    /// How long to keep a stream.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Starts writing stream `id`, which can't be read until it's committed.
+    pub async fn create(&self, id: &str) -> Result<NewStream> {
+        let tmp = self.dir.join(format!(".{id}.tmp"));
+        let file = tokio::fs::File::create_new(&tmp)
+            .await
+            .with_context(|| format!("Failed to create {}", tmp.display()))?;
+        Ok(NewStream {
+            file,
+            tmp,
+            path: self.dir.join(id),
+            committed: false,
+            written: 0,
+            quota: self.quota,
+            used: self.used.clone(),
+        })
+    }
+
+    /// Opens stream `id` for reading, with its length.
+    pub async fn open(&self, id: &str) -> Result<Option<(tokio::fs::File, u64)>> {
+        let path = self.dir.join(id);
+        let file = match tokio::fs::File::open(&path).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to open {}", path.display()));
+            }
+        };
+        let len = file.metadata().await?.len();
+        Ok(Some((file, len)))
+    }
+
+    /// Deletes the streams committed before `cutoff`, returning how many. Blocks, like
+    /// [`BlobStore`] calls. A download already under way reads on to the end.
+    pub fn delete_before(&self, cutoff: SystemTime) -> Result<usize> {
+        let context = || format!("Failed to list {}", self.dir.display());
+        let mut deleted = 0;
+        for entry in fs::read_dir(&self.dir).with_context(context)? {
+            let entry = entry.with_context(context)?;
+            // Streams being written are named `.<id>.tmp`, and never expire.
+            if !entry.file_name().to_str().is_some_and(is_valid_id) {
+                continue;
+            }
+            let metadata = entry.metadata().with_context(context)?;
+            if metadata.modified()? < cutoff {
+                fs::remove_file(entry.path())
+                    .with_context(|| format!("Failed to delete {}", entry.path().display()))?;
+                release(&self.used, metadata.len());
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+}
+
+fn release(used: &AtomicU64, len: u64) {
+    let _ = used.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+        Some(used.saturating_sub(len))
+    });
+}
+
+/// A stream being written. It's deleted if dropped before [`NewStream::commit`].
+pub struct NewStream {
+    file: tokio::fs::File,
+    tmp: PathBuf,
+    path: PathBuf,
+    committed: bool,
+    written: u64,
+    quota: u64,
+    used: Arc<AtomicU64>,
+}
+
+impl NewStream {
+    /// Appends `data`, failing with [`StoreFull`] if the store has no room for it.
+    pub async fn write(&mut self, data: &[u8]) -> Result<()> {
+        let len = data.len() as u64;
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(len).filter(|used| *used <= self.quota)
+            })
+            .map_err(|_| StoreFull)?;
+        self.written += len;
+        self.file
+            .write_all(data)
+            .await
+            .with_context(|| format!("Failed to write {}", self.tmp.display()))
+    }
+
+    /// Makes the stream readable.
+    pub async fn commit(mut self) -> Result<()> {
+        self.file.sync_all().await?;
+        tokio::fs::rename(&self.tmp, &self.path)
+            .await
+            .with_context(|| format!("Failed to write {}", self.path.display()))?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for NewStream {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.tmp);
+            release(&self.used, self.written);
+        }
+    }
+}
+
+/// Reads `STREAM_DIR`, the directory to keep streams in, and `STREAM_MAX_SIZE_MB`,
+/// `STREAM_QUOTA_MB` and `STREAM_TTL_HOURS`, falling back to the defaults for those. Streams are
+/// disabled when `STREAM_DIR` is unset.
+pub fn stream_store_from_env() -> Result<Option<Arc<StreamStore>>> {
+    let Some(dir) = env::var_os("STREAM_DIR") else {
+        return Ok(None);
+    };
+    let mut store = StreamStore::new(dir)?;
+    if let Some(max_size) = positive_var("STREAM_MAX_SIZE_MB")? {
+        store = store.with_max_size(max_size << 20);
+    }
+    if let Some(quota) = positive_var("STREAM_QUOTA_MB")? {
+        store = store.with_quota(quota << 20);
+    }
+    if let Some(hours) = positive_var("STREAM_TTL_HOURS")? {
+        store = store.with_ttl(Duration::from_secs(hours * 60 * 60));
+    }
+    tracing::info!("Storing streams in {}", store.dir.display());
+    Ok(Some(Arc::new(store)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        BlobStore, DirBlobStore, MemoryBlobStore, StoreFull, StreamStore, envelope_record,
+        is_valid_id, new_id, revoked_record, sender_record,
+    };
+    use std::time::{Duration, SystemTime};
+    use tokio::io::AsyncReadExt as _;
+
+    fn round_trips(store: &dyn BlobStore) {
+        let id = new_id();
+        assert_eq!(store.get(&id).unwrap(), None);
+        store.put(&id, b"ciphertext", None).unwrap();
+        for _ in 0..3 {
+            assert_eq!(store.get(&id).unwrap().as_deref(), Some(&b"ciphertext"[..]));
+        }
+
+        let id = new_id();
+        store.put(&id, b"limited", Some(2)).unwrap();
+        assert_eq!(store.get(&id).unwrap().as_deref(), Some(&b"limited"[..]));
+        assert_eq!(store.get(&id).unwrap().as_deref(), Some(&b"limited"[..]));
+        assert_eq!(store.get(&id).unwrap(), None);
+
+        // A read given back can be had again, even the last.
+        let id = new_id();
+        store.put(&id, b"limited", Some(2)).unwrap();
+        let blob = store.get(&id).unwrap().unwrap();
+        store.give_back(&id, &blob).unwrap();
+        for _ in 0..2 {
+            let blob = store.get(&id).unwrap().unwrap();
+            store.give_back(&id, &blob).unwrap();
+            assert_eq!(store.get(&id).unwrap().as_deref(), Some(&b"limited"[..]));
+        }
+        assert_eq!(store.get(&id).unwrap(), None);
+
+        // Putting an id again replaces it, as revoking twice does.
+        let id = new_id();
+        store.put(&id, b"first", Some(1)).unwrap();
+        store.put(&id, b"second", None).unwrap();
+        for _ in 0..2 {
+            assert_eq!(store.get(&id).unwrap().as_deref(), Some(&b"second"[..]));
+        }
+    }
+
+    fn expires(store: &dyn BlobStore) {
+        let (id, envelope) = (new_id(), new_id());
+        store.put(&id, b"ciphertext", Some(2)).unwrap();
+        store
+            .put(&envelope_record(&envelope), b"", Some(1))
+            .unwrap();
+        store.put(&revoked_record(&envelope), b"", None).unwrap();
+        assert_eq!(store.size().unwrap(), 10);
+
+        let now = SystemTime::now();
+        assert_eq!(
+            store.delete_before(now - Duration::from_secs(60)).unwrap(),
+            0
+        );
+        assert!(store.get(&id).unwrap().is_some());
+        assert_eq!(
+            store.delete_before(now + Duration::from_secs(60)).unwrap(),
+            1
+        );
+        assert_eq!(store.get(&id).unwrap(), None);
+        assert_eq!(store.size().unwrap(), 0);
+        // Records never expire.
+        assert!(store.get(&revoked_record(&envelope)).unwrap().is_some());
+        assert!(store.get(&envelope_record(&envelope)).unwrap().is_some());
+    }
+
+    #[test]
+    fn new_ids_are_valid() {
+        let id = new_id();
+        assert!(is_valid_id(&id), "{id}");
+        assert_ne!(id, new_id());
+        assert!(!is_valid_id("../../etc/passwd"));
+        assert!(!is_valid_id(".abcdefghijklmno"));
+        for record in [
+            envelope_record(&id),
+            sender_record(&id),
+            revoked_record(&id),
+        ] {
+            assert!(!is_valid_id(&record), "{record}");
+        }
+    }
+
+    #[test]
+    fn memory_store_round_trips() {
+        round_trips(&MemoryBlobStore::default());
+        expires(&MemoryBlobStore::default());
+    }
+
+    #[test]
+    fn dir_store_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        round_trips(&DirBlobStore::new(dir.path().join("blobs")).unwrap());
+        expires(&DirBlobStore::new(dir.path().join("expiring")).unwrap());
+    }
+
+    #[test]
+    fn dir_store_survives_partial_writes_and_concurrent_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let id = new_id();
+        std::fs::create_dir(dir.path().join("blobs")).unwrap();
+        std::fs::write(
+            dir.path().join("blobs").join(format!(".{id}.tmp")),
+            b"partial",
+        )
+        .unwrap();
+        let store = DirBlobStore::new(dir.path().join("blobs")).unwrap();
+        assert_eq!(
+            std::fs::read_dir(dir.path().join("blobs")).unwrap().count(),
+            0
+        );
+
+        store.put(&id, b"limited", Some(20)).unwrap();
+        let reads: usize = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| (0..5).filter(|_| store.get(&id).unwrap().is_some()).count())
+                })
+                .collect();
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .sum()
+        });
+        assert_eq!(reads, 20);
+    }
+
+    #[tokio::test]
+    async fn stream_store_only_reads_committed_streams() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = StreamStore::new(dir.path()).unwrap();
+        let (committed, dropped) = (new_id(), new_id());
+        let mut stream = store.create(&committed).await.unwrap();
+        stream.write(b"cipher").await.unwrap();
+        stream.write(b"text").await.unwrap();
+        assert!(store.open(&committed).await.unwrap().is_none());
+        stream.commit().await.unwrap();
+        let (mut file, len) = store.open(&committed).await.unwrap().unwrap();
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).await.unwrap();
+        assert_eq!((data.as_slice(), len), (&b"ciphertext"[..], 10));
+
+        let mut stream = store.create(&dropped).await.unwrap();
+        stream.write(b"partial").await.unwrap();
+        drop(stream);
+        assert!(store.open(&dropped).await.unwrap().is_none());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn stream_store_enforces_its_quota_and_expiry() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = StreamStore::new(dir.path()).unwrap().with_quota(10);
+        let (first, second) = (new_id(), new_id());
+        let mut stream = store.create(&first).await.unwrap();
+        stream.write(b"cipher").await.unwrap();
+        let mut full = store.create(&second).await.unwrap();
+        let err = full.write(b"texts").await.unwrap_err();
+        assert!(err.is::<StoreFull>(), "{err:#}");
+        // Dropping a partial stream gives its bytes back.
+        full.write(b"text").await.unwrap();
+        drop(full);
+        stream.write(b"text").await.unwrap();
+        stream.commit().await.unwrap();
+
+        // A partial stream left behind is deleted, and committed ones still count.
+        std::fs::write(dir.path().join(format!(".{second}.tmp")), b"partial").unwrap();
+        let store = StreamStore::new(dir.path()).unwrap().with_quota(10);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        let mut stream = store.create(&second).await.unwrap();
+        assert!(stream.write(b"x").await.is_err());
+
+        let now = SystemTime::now();
+        assert_eq!(
+            store.delete_before(now - Duration::from_secs(60)).unwrap(),
+            0
+        );
+        assert_eq!(
+            store.delete_before(now + Duration::from_secs(60)).unwrap(),
+            1
+        );
+        assert!(store.open(&first).await.unwrap().is_none());
+        stream.write(b"ciphertext").await.unwrap();
+        stream.commit().await.unwrap();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_store_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        round_trips(&super::SqliteBlobStore::open(dir.path().join("blobs.db")).unwrap());
+        expires(&super::SqliteBlobStore::open(dir.path().join("expiring.db")).unwrap());
+
+        // Blobs from before they expired get a TTL from when the table is upgraded.
+        let path = dir.path().join("old.db");
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE blobs (id TEXT PRIMARY KEY, blob BLOB NOT NULL, reads_left INTEGER);
+                INSERT INTO blobs VALUES ('AAAAAAAAAAAAAAAA', x'00', NULL);",
+            )
+            .unwrap();
+        let store = super::SqliteBlobStore::open(&path).unwrap();
+        let now = SystemTime::now();
+        assert_eq!(
+            store.delete_before(now - Duration::from_secs(60)).unwrap(),
+            0
+        );
+        assert_eq!(
+            store.delete_before(now + Duration::from_secs(60)).unwrap(),
+            1
+        );
+    }
+}
@@ -0,0 +1,362 @@
+//! A chunked AEAD format for files too large to hold in memory, after the STREAM construction of
+//! Hoang, Reyhanitabar, Rogaway and Vizár, for `/api/streams`.
+//!
+//! A stream starts with a 16-byte header: `CLYS`, a version byte, the chunk size as a big-endian
+//! `u32`, and a random 7-byte nonce prefix. Then come the chunks, each up to the chunk size of
+//! plaintext sealed with AES-256-GCM, with the header as associated data, under the nonce
+//! `prefix || counter || last`: the chunk's index as a big-endian `u32`, then 1 for the final
+//! chunk and 0 for the rest. Every chunk but the last is full, so dropping, reordering or
+//! truncating chunks fails decryption.
+//!
+//! [`Encryptor`] and [`Decryptor`] take input in pieces of any size and return output as whole
+//! chunks are ready, so memory use is bounded by the chunk size.
+
+use aes_gcm::{
+    Aes256Gcm, Key, KeyInit,
+    aead::{Aead, OsRng, Payload, rand_core::RngCore},
+};
+use anyhow::{Context as _, Result, anyhow};
+use base64::prelude::*;
+use std::fmt;
+use zeroize::Zeroizing;
+
+/// The plaintext in each chunk but the last.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+/// The largest chunk size a [`Decryptor`] accepts, so a header can't make it buffer much.
+const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+const MAGIC: &[u8; 4] = b"CLYS";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 16;
+const PREFIX_LEN: usize = 7;
+const TAG_LEN: usize = 16;
+
+/// A new random stream key.
+pub fn new_key() -> Zeroizing<[u8; 32]> {
+    let mut key = Zeroizing::new([0; 32]);
+    OsRng.fill_bytes(key.as_mut());
+    key
+}
+
+fn nonce(header: &[u8; HEADER_LEN], counter: u32, last: bool) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[..PREFIX_LEN].copy_from_slice(&header[HEADER_LEN - PREFIX_LEN..]);
+    nonce[PREFIX_LEN..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last.into();
+    nonce
+}
+
+/// Encrypts a stream piece by piece.
+pub struct Encryptor {
+    cipher: Aes256Gcm,
+    header: [u8; HEADER_LEN],
+    chunk_size: usize,
+    counter: u32,
+    started: bool,
+    buf: Zeroizing<Vec<u8>>,
+}
+
+impl Encryptor {
+    pub fn new(key: &[u8; 32]) -> Encryptor {
+        Encryptor::with_chunk_size(key, CHUNK_SIZE)
+    }
+
+    fn with_chunk_size(key: &[u8; 32], chunk_size: usize) -> Encryptor {
+        let mut header = [0; HEADER_LEN];
+        header[..4].copy_from_slice(MAGIC);
+        header[4] = VERSION;
+        header[5..9].copy_from_slice(&(chunk_size as u32).to_be_bytes());
+        OsRng.fill_bytes(&mut header[HEADER_LEN - PREFIX_LEN..]);
+        Encryptor {
+            cipher: Aes256Gcm::new(&Key::<Aes256Gcm>::from(*key)),
+            header,
+            chunk_size,
+            counter: 0,
+            started: false,
+            buf: Zeroizing::new(Vec::with_capacity(chunk_size)),
+        }
+    }
+
+    /// Encrypts `data`, returning the ciphertext of any chunks it completes, after the header on
+    /// the first call.
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut out = self.start();
+        self.buf.extend_from_slice(data);
+        // Hold back a full chunk until more data shows it isn't the last.
+        while self.buf.len() > self.chunk_size {
+            let chunk = Zeroizing::new(self.buf.drain(..self.chunk_size).collect::<Vec<_>>());
+            out.extend(self.seal(&chunk, false)?);
+        }
+        Ok(out)
+    }
+
+    /// Encrypts the last chunk.
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        let mut out = self.start();
+        let chunk = std::mem::take(&mut self.buf);
+        out.extend(self.seal(&chunk, true)?);
+        Ok(out)
+    }
+
+    fn start(&mut self) -> Vec<u8> {
+        if self.started {
+            return Vec::new();
+        }
+        self.started = true;
+        self.header.to_vec()
+    }
+
+    fn seal(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>> {
+        let nonce = nonce(&self.header, self.counter, last);
+        self.counter = self.counter.checked_add(1).context("Stream is too long")?;
+        self.cipher
+            .encrypt(
+                &nonce.into(),
+                Payload {
+                    msg: chunk,
+                    aad: &self.header,
+                },
+            )
+            .map_err(|_| anyhow!("Encryption failed"))
+    }
+}
+
+/// Decrypts a stream piece by piece. Chunks are returned as soon as they're authenticated, but
+/// the stream is only whole once [`Decryptor::finish`] succeeds; until then, it may have been cut
+/// short.
+pub struct Decryptor {
+    cipher: Aes256Gcm,
+    header: Option<[u8; HEADER_LEN]>,
+    chunk_size: usize,
+    counter: u32,
+    buf: Vec<u8>,
+}
+
+impl Decryptor {
+    pub fn new(key: &[u8; 32]) -> Decryptor {
+        Decryptor {
+            cipher: Aes256Gcm::new(&Key::<Aes256Gcm>::from(*key)),
+            header: None,
+            chunk_size: 0,
+            counter: 0,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Decrypts `data`, returning the plaintext of any chunks it completes.
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.buf.extend_from_slice(data);
+        let mut out = Vec::new();
+        if self.header.is_none() && !self.read_header()? {
+            return Ok(out);
+        }
+        // Hold back a full chunk until more data shows it isn't the last.
+        while self.buf.len() > self.chunk_size + TAG_LEN {
+            let chunk: Vec<_> = self.buf.drain(..self.chunk_size + TAG_LEN).collect();
+            out.extend(self.open(&chunk, false)?);
+        }
+        Ok(out)
+    }
+
+    /// Decrypts the last chunk, failing if the stream was cut short.
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        if self.header.is_none() && !self.read_header()? {
+            return Err(anyhow!("Stream is too short"));
+        }
+        if self.buf.len() < TAG_LEN {
+            return Err(anyhow!("Stream is too short"));
+        }
+        let chunk = std::mem::take(&mut self.buf);
+        self.open(&chunk, true)
+    }
+
+    fn read_header(&mut self) -> Result<bool> {
+        if self.buf.len() < HEADER_LEN {
+            return Ok(false);
+        }
+        let header: [u8; HEADER_LEN] = self.buf[..HEADER_LEN].try_into()?;
+        if &header[..4] != MAGIC {
+            return Err(anyhow!("Not a cipherly stream"));
+        }
+        if header[4] != VERSION {
+            return Err(anyhow!("Unsupported stream version {}", header[4]));
+        }
+        let chunk_size = u32::from_be_bytes(header[5..9].try_into()?) as usize;
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(anyhow!("Stream chunk size {chunk_size} is out of range"));
+        }
+        self.buf.drain(..HEADER_LEN);
+        self.header = Some(header);
+        self.chunk_size = chunk_size;
+        Ok(true)
+    }
+
+    fn open(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>> {
+        let header = self.header.context("Stream header is missing")?;
+        let nonce = nonce(&header, self.counter, last);
+        self.counter = self.counter.checked_add(1).context("Stream is too long")?;
+        self.cipher
+            .decrypt(
+                &nonce.into(),
+                Payload {
+                    msg: chunk,
+                    aad: &header,
+                },
+            )
+            .map_err(|_| anyhow!("Decryption failed; the stream is corrupt or cut short"))
+    }
+}
+
+/// A link to a stream on the server: `<base_url>/api/streams/<id>#<key>`.
+#[derive(Debug, PartialEq)]
+pub struct StreamLink {
+    pub base_url: String,
+    pub id: String,
+    pub key: Zeroizing<String>,
+}
+
+impl StreamLink {
+    pub fn new(base_url: &str, id: String, key: &[u8; 32]) -> StreamLink {
+        StreamLink {
+            base_url: base_url.trim_end_matches('/').into(),
+            id,
+            key: Zeroizing::new(BASE64_URL_SAFE_NO_PAD.encode(key)),
+        }
+    }
+
+    /// Parses a stream link, or returns `None` if `link` isn't one.
+    pub fn parse(link: &str) -> Option<StreamLink> {
+        let (url, key) = link.trim().split_once('#')?;
+        let (base_url, id) = url.rsplit_once("/api/streams/")?;
+        if id.is_empty() || id.contains('/') || key.is_empty() {
+            return None;
+        }
+        Some(StreamLink {
+            base_url: base_url.into(),
+            id: id.into(),
+            key: Zeroizing::new(key.into()),
+        })
+    }
+
+    pub fn key(&self) -> Result<Zeroizing<[u8; 32]>> {
+        let key = Zeroizing::new(
+            BASE64_URL_SAFE_NO_PAD
+                .decode(self.key.as_str())
+                .context("Link key is not valid base64")?,
+        );
+        let mut out = Zeroizing::new([0; 32]);
+        if key.len() != out.len() {
+            return Err(anyhow!("Link key is malformed"));
+        }
+        out.copy_from_slice(&key);
+        Ok(out)
+    }
+}
+
+impl fmt::Display for StreamLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/api/streams/{}#{}",
+            self.base_url,
+            self.id,
+            self.key.as_str()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Decryptor, Encryptor, HEADER_LEN, StreamLink, TAG_LEN, new_key};
+    use anyhow::Result;
+
+    const CHUNK: usize = 10;
+
+    fn encrypt(key: &[u8; 32], plaintext: &[u8], piece: usize) -> Vec<u8> {
+        let mut encryptor = Encryptor::with_chunk_size(key, CHUNK);
+        let mut out = Vec::new();
+        for data in plaintext.chunks(piece) {
+            out.extend(encryptor.update(data).unwrap());
+        }
+        out.extend(encryptor.finish().unwrap());
+        out
+    }
+
+    fn decrypt(key: &[u8; 32], stream: &[u8], piece: usize) -> Result<Vec<u8>> {
+        let mut decryptor = Decryptor::new(key);
+        let mut out = Vec::new();
+        for data in stream.chunks(piece) {
+            out.extend(decryptor.update(data)?);
+        }
+        out.extend(decryptor.finish()?);
+        Ok(out)
+    }
+
+    #[test]
+    fn round_trips_in_any_pieces() {
+        let key = new_key();
+        let plaintext: Vec<u8> = (0..=255).collect();
+        for len in [0, 1, CHUNK - 1, CHUNK, CHUNK + 1, 3 * CHUNK, 256] {
+            for piece in [1, 7, CHUNK, 1000] {
+                let stream = encrypt(&key, &plaintext[..len], piece);
+                let chunks = len.div_ceil(CHUNK).max(1);
+                assert_eq!(stream.len(), HEADER_LEN + len + chunks * TAG_LEN);
+                for decrypt_piece in [1, 13, 1000] {
+                    assert_eq!(
+                        decrypt(&key, &stream, decrypt_piece).unwrap(),
+                        &plaintext[..len]
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_tampered_streams() {
+        let key = new_key();
+        let stream = encrypt(&key, &[7; 3 * CHUNK + 5], 100);
+        let chunk = CHUNK + TAG_LEN;
+        let body = &stream[HEADER_LEN..];
+        let header = &stream[..HEADER_LEN];
+
+        let mut flipped = stream.clone();
+        flipped[HEADER_LEN + 3] ^= 1;
+        let truncated = stream[..HEADER_LEN + 2 * chunk].to_vec();
+        let dropped = [header, &body[..chunk], &body[2 * chunk..]].concat();
+        let reordered = [
+            header,
+            &body[chunk..2 * chunk],
+            &body[..chunk],
+            &body[2 * chunk..],
+        ]
+        .concat();
+        let mut chunk_size = stream.clone();
+        chunk_size[8] += 1;
+        for stream in [
+            flipped,
+            truncated,
+            dropped,
+            reordered,
+            chunk_size,
+            Vec::new(),
+        ] {
+            assert!(decrypt(&key, &stream, 1000).is_err());
+        }
+        assert!(decrypt(&new_key(), &stream, 1000).is_err());
+    }
+
+    #[test]
+    fn stream_link_round_trips() {
+        let key = new_key();
+        let link = StreamLink::new("https://cipherly.example/", "abc".into(), &key);
+        let text = link.to_string();
+        assert!(text.starts_with("https://cipherly.example/api/streams/abc#"));
+        let parsed = StreamLink::parse(&text).unwrap();
+        assert_eq!(parsed, link);
+        assert_eq!(*parsed.key().unwrap(), *key);
+        assert_eq!(
+            StreamLink::parse("https://cipherly.example/s/abc#key"),
+            None
+        );
+    }
+}
@@ -0,0 +1,226 @@
+use anyhow::{Context as _, Result, anyhow};
+use axum::serve::Listener;
+use rustls::{
+    ServerConfig,
+    crypto::{CryptoProvider, aws_lc_rs},
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use std::{
+    env,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task::JoinSet,
+};
+use tokio_rustls::{TlsAcceptor, server::TlsStream};
+use tokio_util::sync::CancellationToken;
+
+const DEFAULT_RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct TlsConfig {
+    /// PEM file containing the certificate chain, leaf first.
+    pub cert_path: PathBuf,
+    /// PEM file containing the private key for the leaf certificate.
+    pub key_path: PathBuf,
+    /// How often to check the certificate and key files for changes.
+    pub reload_interval: Duration,
+    /// When set, responses include a `Strict-Transport-Security` header with this max-age.
+    pub hsts_max_age: Option<Duration>,
+}
+
+impl TlsConfig {
+    /// Reads TLS settings from `TLS_CERT_PATH`, `TLS_KEY_PATH`, `TLS_RELOAD_INTERVAL_SECS`
+    /// and `HSTS_MAX_AGE_SECS`. Returns `None` when no certificate is configured.
+    pub fn from_env() -> Result<Option<TlsConfig>> {
+        let (cert_path, key_path) = match (env::var("TLS_CERT_PATH"), env::var("TLS_KEY_PATH")) {
+            (Ok(cert_path), Ok(key_path)) => (cert_path, key_path),
+            (Err(_), Err(_)) => return Ok(None),
+            _ => {
+                return Err(anyhow!(
+                    "TLS_CERT_PATH and TLS_KEY_PATH must be set together"
+                ));
+            }
+        };
+        Ok(Some(TlsConfig {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            reload_interval: secs_from_env("TLS_RELOAD_INTERVAL_SECS")?
+                .unwrap_or(DEFAULT_RELOAD_INTERVAL),
+            hsts_max_age: secs_from_env("HSTS_MAX_AGE_SECS")?,
+        }))
+    }
+
+    pub fn hsts_header(&self) -> Option<String> {
+        self.hsts_max_age
+            .map(|max_age| format!("max-age={}; includeSubDomains", max_age.as_secs()))
+    }
+}
+
+fn secs_from_env(name: &str) -> Result<Option<Duration>> {
+    match env::var(name) {
+        Ok(secs) => Ok(Some(Duration::from_secs(
+            secs.parse()
+                .with_context(|| format!("{name} should be a number of seconds"))?,
+        ))),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Serves the most recently loaded certificate and key for every handshake.
+#[derive(Debug)]
+struct CertResolver {
+    provider: Arc<CryptoProvider>,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    fn new(provider: Arc<CryptoProvider>, config: &TlsConfig) -> Result<CertResolver> {
+        let current = load_certified_key(&provider, &config.cert_path, &config.key_path)?;
+        Ok(CertResolver {
+            provider,
+            cert_path: config.cert_path.clone(),
+            key_path: config.key_path.clone(),
+            current: RwLock::new(Arc::new(current)),
+        })
+    }
+
+    fn reload(&self) -> Result<()> {
+        let certified_key = load_certified_key(&self.provider, &self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = Arc::new(certified_key);
+        Ok(())
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path: &Path| path.metadata().and_then(|m| m.modified()).ok();
+        Some((modified(&self.cert_path)?, modified(&self.key_path)?))
+    }
+
+    /// Polls the certificate and key files and reloads them whenever either changes.
+    /// A failed reload is logged and the previous certificate stays in use.
+    async fn watch(self: Arc<Self>, interval: Duration, shutdown_signal: CancellationToken) {
+        let mut last_modified = self.modified();
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {},
+                _ = shutdown_signal.cancelled() => return,
+            }
+            let modified = self.modified();
+            if modified.is_none() || modified == last_modified {
+                continue;
+            }
+            match self.reload() {
+                Ok(()) => {
+                    tracing::info!("Reloaded TLS certificate from {}", self.cert_path.display());
+                    last_modified = modified;
+                }
+                Err(err) => tracing::warn!("Failed to reload TLS certificate: {err:#}"),
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn load_certified_key(
+    provider: &CryptoProvider,
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<CertifiedKey> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read certificates from {}", cert_path.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificates found in {}", cert_path.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .with_context(|| format!("Failed to read private key from {}", key_path.display()))?;
+    CertifiedKey::from_der(certs, key, provider).context("Certificate does not match private key")
+}
+
+/// Accepts TCP connections and completes TLS handshakes off the accept loop, so a slow
+/// client can't stall other connections.
+pub struct TlsListener {
+    tcp: TcpListener,
+    acceptor: TlsAcceptor,
+    handshakes: JoinSet<Option<(TlsStream<TcpStream>, SocketAddr)>>,
+}
+
+impl TlsListener {
+    pub fn new(
+        tcp: TcpListener,
+        config: &TlsConfig,
+        shutdown_signal: CancellationToken,
+    ) -> Result<TlsListener> {
+        let provider = Arc::new(aws_lc_rs::default_provider());
+        let resolver = Arc::new(CertResolver::new(provider.clone(), config)?);
+        tokio::spawn(
+            resolver
+                .clone()
+                .watch(config.reload_interval, shutdown_signal),
+        );
+
+        let mut server_config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(resolver);
+        server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(TlsListener {
+            tcp,
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            handshakes: JoinSet::new(),
+        })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            tokio::select! {
+                Some(handshake) = self.handshakes.join_next() => {
+                    if let Ok(Some(conn)) = handshake {
+                        return conn;
+                    }
+                }
+                (stream, addr) = Listener::accept(&mut self.tcp) => {
+                    let acceptor = self.acceptor.clone();
+                    self.handshakes.spawn(async move {
+                        match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                            Ok(Ok(stream)) => Some((stream, addr)),
+                            Ok(Err(err)) => {
+                                tracing::debug!("TLS handshake with {addr} failed: {err}");
+                                None
+                            }
+                            Err(_) => {
+                                tracing::debug!("TLS handshake with {addr} timed out");
+                                None
+                            }
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        self.tcp.local_addr()
+    }
+}
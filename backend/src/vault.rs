@@ -0,0 +1,301 @@
+//! Holds the server's KEKs. When `KEKS_FILE` is protected by a split master key, the server
+//! starts sealed and serves no envelopes until operators submit enough shares to unseal it. Only
+//! signed-in [`Admins`] may submit shares, one each.
+
+use crate::{
+    google,
+    kek::{self, Keks, KeyConfigError},
+    listener,
+    shamir::Share,
+};
+use anyhow::{Context as _, Result};
+use axum::{
+    Extension,
+    extract::{ConnectInfo, Request},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+use std::{
+    env, fmt, fs,
+    sync::{Arc, RwLock},
+};
+
+pub struct Vault {
+    state: RwLock<State>,
+}
+
+enum State {
+    Unsealed(Arc<Keks>),
+    Sealed {
+        file: String,
+        threshold: u8,
+        /// Each share with the email of the operator who submitted it.
+        shares: Vec<(String, Share)>,
+    },
+}
+
+/// Whether the vault is sealed, and if so how many of the shares it needs it has.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct VaultStatus {
+    pub sealed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shares: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub threshold: Option<u8>,
+}
+
+#[derive(Debug)]
+pub enum UnsealError {
+    /// The share couldn't be decoded.
+    InvalidShare,
+    /// A share with the same `x` was already submitted.
+    DuplicateShare,
+    /// The operator already submitted a share.
+    AlreadySubmitted,
+    /// The share is a different length from the ones already submitted.
+    MismatchedShare,
+    /// The threshold was reached, but the shares didn't open the key ring. They are discarded so
+    /// operators can start again.
+    WrongShares(KeyConfigError),
+}
+
+impl fmt::Display for UnsealError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnsealError::InvalidShare => write!(f, "share is malformed"),
+            UnsealError::DuplicateShare => write!(f, "share was already submitted"),
+            UnsealError::AlreadySubmitted => write!(f, "operator already submitted a share"),
+            UnsealError::MismatchedShare => write!(f, "share is not of the same master key"),
+            UnsealError::WrongShares(err) => write!(f, "shares did not unseal the KEKs: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for UnsealError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            UnsealError::WrongShares(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Vault {
+    pub fn unsealed(keks: Keks) -> Vault {
+        Vault {
+            state: RwLock::new(State::Unsealed(Arc::new(keks))),
+        }
+    }
+
+    /// A vault for a key ring file written by [`kek::split_key_ring`].
+    pub fn sealed(file: String) -> Result<Vault, KeyConfigError> {
+        let threshold = kek::split_threshold(&file)?.ok_or(KeyConfigError::MalformedFile(
+            "key ring is not protected by a split master key",
+        ))?;
+        Ok(Vault {
+            state: RwLock::new(State::Sealed {
+                file,
+                threshold,
+                shares: Vec::new(),
+            }),
+        })
+    }
+
+    /// Loads a sealed vault if `KEKS_FILE` is protected by a split master key, and otherwise
+    /// unsealed KEKs as described in [`kek::from_env`].
+    pub fn from_env() -> Result<Vault> {
+        if let Ok(path) = env::var("KEKS_FILE") {
+            let file =
+                fs::read_to_string(&path).with_context(|| format!("Failed to read {path}"))?;
+            if kek::split_threshold(&file).is_ok_and(|threshold| threshold.is_some()) {
+                tracing::info!("KEKs are sealed until enough shares are submitted");
+                return Ok(Vault::sealed(file)?);
+            }
+        }
+        Ok(Vault::unsealed(kek::from_env()?))
+    }
+
+    /// The KEKs, unless the vault is still sealed.
+    pub fn keks(&self) -> Option<Arc<Keks>> {
+        match &*self.state.read().unwrap_or_else(|err| err.into_inner()) {
+            State::Unsealed(keks) => Some(keks.clone()),
+            State::Sealed { .. } => None,
+        }
+    }
+
+    pub fn status(&self) -> VaultStatus {
+        self.state
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .status()
+    }
+
+    /// Adds a share of the master key from `operator`, and unseals the KEKs once there are
+    /// enough. Shares sent after the vault is unsealed are ignored.
+    pub fn add_share(&self, share: &str, operator: &str) -> Result<VaultStatus, UnsealError> {
+        let share = Share::decode(share).map_err(|_| UnsealError::InvalidShare)?;
+        let mut state = self.state.write().unwrap_or_else(|err| err.into_inner());
+        let State::Sealed {
+            file,
+            threshold,
+            shares,
+        } = &mut *state
+        else {
+            return Ok(state.status());
+        };
+        if shares
+            .iter()
+            .any(|(other, _)| other.eq_ignore_ascii_case(operator))
+        {
+            return Err(UnsealError::AlreadySubmitted);
+        }
+        if shares.iter().any(|(_, other)| other.x == share.x) {
+            return Err(UnsealError::DuplicateShare);
+        }
+        if shares
+            .first()
+            .is_some_and(|(_, other)| other.y.len() != share.y.len())
+        {
+            return Err(UnsealError::MismatchedShare);
+        }
+        shares.push((operator.into(), share));
+        tracing::info!(operator, "Received share {} of {threshold}", shares.len());
+        if shares.len() >= usize::from(*threshold) {
+            let submitted: Vec<_> = shares.iter().map(|(_, share)| share.clone()).collect();
+            let keks = kek::combine_key_ring(file, &submitted)
+                .and_then(|json| kek::parse(&json))
+                .and_then(|keks| keks.self_test().map(|_| keks));
+            match keks {
+                Ok(keks) => {
+                    tracing::info!("Unsealed {} KEKs", keks.len());
+                    *state = State::Unsealed(Arc::new(keks));
+                }
+                Err(err) => {
+                    let operators: Vec<_> =
+                        shares.drain(..).map(|(operator, _)| operator).collect();
+                    tracing::warn!(?operators, "Shares did not unseal the KEKs; discarded them");
+                    return Err(UnsealError::WrongShares(err));
+                }
+            }
+        }
+        Ok(state.status())
+    }
+}
+
+impl State {
+    fn status(&self) -> VaultStatus {
+        match self {
+            State::Unsealed(_) => VaultStatus {
+                sealed: false,
+                shares: None,
+                threshold: None,
+            },
+            State::Sealed {
+                threshold, shares, ..
+            } => VaultStatus {
+                sealed: true,
+                shares: Some(shares.len()),
+                threshold: Some(*threshold),
+            },
+        }
+    }
+}
+
+/// Makes the KEKs available to the handler as an `Arc<Keks>` extension, or responds with 503
+/// while the vault is sealed. Needs an `Arc<Vault>` extension.
+pub async fn require_unsealed(
+    Extension(vault): Extension<Arc<Vault>>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let keks = vault.keks().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    request.extensions_mut().insert(keks);
+    Ok(next.run(request).await)
+}
+
+/// The operators who may submit shares to `/api/admin/unseal`.
+#[derive(Debug, Clone, Default)]
+pub struct Admins {
+    /// Google accounts of the operators, compared ignoring case.
+    pub emails: Vec<String>,
+    /// Also require shares to come over a Unix socket or from a loopback address. Behind a
+    /// reverse proxy on the same host every request looks local, so this only helps when the
+    /// server is reached directly.
+    pub local_only: bool,
+}
+
+impl Admins {
+    /// Reads `ADMIN_EMAILS`, a comma-separated list, and `ADMIN_LOCAL_ONLY`.
+    pub fn from_env() -> Admins {
+        Admins {
+            emails: env::var("ADMIN_EMAILS")
+                .map(|emails| {
+                    emails
+                        .split(',')
+                        .map(|email| email.trim().to_string())
+                        .filter(|email| !email.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            local_only: env::var("ADMIN_LOCAL_ONLY").is_ok_and(|value| value == "true"),
+        }
+    }
+
+    fn allows(&self, email: &str, peer: Option<&listener::Addr>) -> bool {
+        let local = match peer {
+            Some(listener::Addr::Tcp(addr)) => addr.ip().is_loopback(),
+            Some(listener::Addr::Unix(_)) => true,
+            None => false,
+        };
+        (local || !self.local_only)
+            && self
+                .emails
+                .iter()
+                .any(|admin| admin.eq_ignore_ascii_case(email))
+    }
+}
+
+/// Lets only [`Admins`] through, responding with 403 to anyone else. Needs an `Arc<Admins>`
+/// extension, and [`google::authenticate`] in front of it to provide the caller's claims.
+pub async fn require_admin(
+    Extension(admins): Extension<Arc<Admins>>,
+    Extension(claims): Extension<google::Claims>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let peer = request.extensions().get::<ConnectInfo<listener::Addr>>();
+    if !admins.allows(&claims.email, peer.map(|ConnectInfo(addr)| addr)) {
+        tracing::warn!(
+            email = claims.email,
+            "Refused a non-admin at an admin endpoint"
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Admins;
+    use crate::listener::Addr;
+
+    #[test]
+    fn admins_can_be_limited_to_local_peers() {
+        let mut admins = Admins {
+            emails: vec!["Alice@email.com".into()],
+            local_only: false,
+        };
+        let remote = Addr::Tcp("203.0.113.1:443".parse().unwrap());
+        let local = Addr::Tcp("127.0.0.1:443".parse().unwrap());
+        assert!(admins.allows("alice@email.com", Some(&remote)));
+        assert!(!admins.allows("bob@email.com", Some(&local)));
+
+        admins.local_only = true;
+        assert!(!admins.allows("alice@email.com", Some(&remote)));
+        assert!(!admins.allows("alice@email.com", None));
+        assert!(admins.allows("alice@email.com", Some(&local)));
+        assert!(admins.allows("alice@email.com", Some(&Addr::Unix(None))));
+    }
+}
@@ -0,0 +1,492 @@
+//! Notifies configured endpoints when an envelope is sealed, unsealed, or a non-recipient tries
+//! to unseal it, e.g. to post to chat or feed a SIEM.
+//!
+//! Each endpoint gets a `POST` of a JSON [`WebhookEvent`], with the unix time in
+//! `X-Cipherly-Timestamp` and an HMAC-SHA256 of `<timestamp>.<body>`, base64url encoded, in
+//! `X-Cipherly-Signature`; see [`verify`]. A fixed pool of workers makes the deliveries from a
+//! bounded queue. Failed deliveries are retried with exponential backoff, and then written to a
+//! dead-letter file if one is configured, as are deliveries that find the queue full.
+//!
+//! Endpoint URLs often carry a secret, so logs and dead letters name only their host.
+
+use crate::audit::{Action, AuditEvent, Outcome};
+use anyhow::{Context as _, Result, anyhow};
+use base64::prelude::*;
+use hmac::{Hmac, Mac};
+use reqwest::{StatusCode, header};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    env,
+    fs::OpenOptions,
+    io::Write as _,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+use tokio::sync::{Mutex, mpsc};
+use zeroize::Zeroizing;
+
+pub const TIMESTAMP_HEADER: &str = "x-cipherly-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-cipherly-signature";
+
+/// How long an endpoint has to respond to each attempt.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many deliveries can wait for a worker by default.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// How many deliveries are made at once by default.
+const WORKERS: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebhookEvent {
+    #[serde(rename = "type")]
+    pub kind: EventType,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventType {
+    Sealed,
+    Unsealed,
+    /// A signed-in user who isn't a recipient tried to unseal the envelope.
+    NotRecipient,
+}
+
+impl EventType {
+    /// The type of webhook `event` fires, if any.
+    pub fn of(event: &AuditEvent) -> Option<EventType> {
+        match (event.action, event.outcome) {
+            (Action::Seal, Outcome::Sealed) => Some(EventType::Sealed),
+            (Action::Unseal, Outcome::Unsealed) => Some(EventType::Unsealed),
+            (Action::Unseal, Outcome::NotRecipient) => Some(EventType::NotRecipient),
+            _ => None,
+        }
+    }
+}
+
+/// A delivery that failed every attempt, as written to the dead-letter file.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub time: String,
+    /// The host of the endpoint, from [`redact`].
+    pub url: String,
+    pub error: String,
+    pub event: WebhookEvent,
+}
+
+impl DeadLetter {
+    fn new(delivery: Delivery, error: String) -> DeadLetter {
+        DeadLetter {
+            time: chrono::Utc::now().to_rfc3339(),
+            url: redact(&delivery.url),
+            error,
+            event: delivery.event,
+        }
+    }
+}
+
+/// Delivers events to webhook endpoints in the background.
+pub struct Webhooks {
+    http: reqwest::Client,
+    urls: Vec<String>,
+    secret: Zeroizing<Vec<u8>>,
+    dead_letter: Option<PathBuf>,
+    max_retries: u32,
+    retry_backoff: Duration,
+    queue_capacity: usize,
+    workers: usize,
+    /// Started with the first event, once the builder methods have been applied.
+    queue: OnceLock<mpsc::Sender<Delivery>>,
+}
+
+/// An event waiting for a worker to deliver it to `url`.
+struct Delivery {
+    url: String,
+    event: WebhookEvent,
+}
+
+impl Webhooks {
+    pub fn new(urls: Vec<String>, secret: &[u8]) -> Webhooks {
+        Webhooks {
+            http: reqwest::Client::new(),
+            urls,
+            secret: Zeroizing::new(secret.into()),
+            dead_letter: None,
+            max_retries: 5,
+            retry_backoff: Duration::from_secs(1),
+            queue_capacity: QUEUE_CAPACITY,
+            workers: WORKERS,
+            queue: OnceLock::new(),
+        }
+    }
+
+    /// Appends deliveries that failed every attempt to `path` as JSON lines of [`DeadLetter`].
+    pub fn with_dead_letter(mut self, path: impl Into<PathBuf>) -> Webhooks {
+        self.dead_letter = Some(path.into());
+        self
+    }
+
+    /// Retries a failed delivery up to `max_retries` times, waiting `backoff` before the first
+    /// retry and doubling it after each.
+    pub fn with_retries(mut self, max_retries: u32, backoff: Duration) -> Webhooks {
+        self.max_retries = max_retries;
+        self.retry_backoff = backoff;
+        self
+    }
+
+    /// Queues up to `capacity` deliveries for `workers` to make, at most that many at once.
+    pub fn with_queue(mut self, capacity: usize, workers: usize) -> Webhooks {
+        self.queue_capacity = capacity;
+        self.workers = workers;
+        self
+    }
+
+    /// Reads `WEBHOOK_URLS`, comma separated, the signing secret `WEBHOOK_SECRET` and, optionally,
+    /// the path of `WEBHOOK_DEAD_LETTER`. Webhooks are disabled when `WEBHOOK_URLS` is unset.
+    pub fn from_env() -> Result<Option<Webhooks>> {
+        let Ok(urls) = env::var("WEBHOOK_URLS") else {
+            return Ok(None);
+        };
+        let urls: Vec<String> = urls
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(Into::into)
+            .collect();
+        let secret =
+            Zeroizing::new(env::var("WEBHOOK_SECRET").context("WEBHOOK_SECRET is not set")?);
+        if secret.is_empty() {
+            return Err(anyhow!("WEBHOOK_SECRET must not be empty"));
+        }
+        let hosts: Vec<String> = urls.iter().map(|url| redact(url)).collect();
+        tracing::info!("Sending webhooks to {}", hosts.join(", "));
+        let webhooks = Webhooks::new(urls, secret.as_bytes());
+        Ok(Some(match env::var_os("WEBHOOK_DEAD_LETTER") {
+            Some(path) => webhooks.with_dead_letter(path),
+            None => webhooks,
+        }))
+    }
+
+    /// Queues `event` for every endpoint, if it's one that fires webhooks. A delivery that finds
+    /// the queue full goes straight to the dead-letter file, written on the blocking thread pool.
+    pub fn notify(self: &Arc<Self>, event: &AuditEvent) {
+        let Some(kind) = EventType::of(event) else {
+            return;
+        };
+        let event = WebhookEvent {
+            kind,
+            event: event.clone(),
+        };
+        let queue = self.queue.get_or_init(|| self.start());
+        for url in &self.urls {
+            let delivery = Delivery {
+                url: url.clone(),
+                event: event.clone(),
+            };
+            if let Err(err) = queue.try_send(delivery) {
+                let delivery = err.into_inner();
+                tracing::warn!(url = redact(url), "Webhook queue is full");
+                if let Some(path) = self.dead_letter.clone() {
+                    let error = "the webhook queue is full".into();
+                    let dead_letter = DeadLetter::new(delivery, error);
+                    tokio::task::spawn_blocking(move || write_dead_letter(&path, &dead_letter));
+                }
+            }
+        }
+    }
+
+    /// Spawns the workers, which stop once the last `Arc` of `self`, and so the queue, is dropped.
+    fn start(self: &Arc<Self>) -> mpsc::Sender<Delivery> {
+        let (tx, rx) = mpsc::channel(self.queue_capacity);
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..self.workers {
+            let (webhooks, rx) = (Arc::downgrade(self), rx.clone());
+            tokio::spawn(async move {
+                loop {
+                    let Some(delivery) = rx.lock().await.recv().await else {
+                        return;
+                    };
+                    let Some(webhooks) = webhooks.upgrade() else {
+                        return;
+                    };
+                    webhooks.deliver(delivery).await;
+                }
+            });
+        }
+        tx
+    }
+
+    async fn deliver(&self, delivery: Delivery) {
+        let Err(err) = self.post(&delivery.url, &delivery.event).await else {
+            return;
+        };
+        tracing::warn!(
+            url = redact(&delivery.url),
+            "Failed to deliver webhook: {err:#}"
+        );
+        let Some(path) = self.dead_letter.clone() else {
+            return;
+        };
+        let dead_letter = DeadLetter::new(delivery, format!("{err:#}"));
+        let _ = tokio::task::spawn_blocking(move || write_dead_letter(&path, &dead_letter)).await;
+    }
+
+    async fn post(&self, url: &str, event: &WebhookEvent) -> Result<()> {
+        let body = serde_json::to_vec(event)?;
+        let mut backoff = self.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            let timestamp = chrono::Utc::now().timestamp().to_string();
+            let result = self
+                .http
+                .post(url)
+                .timeout(TIMEOUT)
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(TIMESTAMP_HEADER, &timestamp)
+                .header(SIGNATURE_HEADER, sign(&self.secret, &timestamp, &body))
+                .body(body.clone())
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status);
+            match result {
+                Ok(_) => return Ok(()),
+                Err(err) if attempt < self.max_retries && is_retryable(&err) => {
+                    tracing::debug!(
+                        url = redact(url),
+                        "Retrying webhook after {}",
+                        err.without_url()
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err(err) => return Err(err.without_url().into()),
+            }
+        }
+    }
+}
+
+/// The host, and port if any, of endpoint `url`, to name it by without giving away a secret in
+/// its path or query.
+pub fn redact(url: &str) -> String {
+    let Ok(url) = reqwest::Url::parse(url) else {
+        return "<invalid url>".into();
+    };
+    match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{host}:{port}"),
+        (Some(host), None) => host.into(),
+        (None, _) => "<invalid url>".into(),
+    }
+}
+
+/// Appends `dead_letter` to the file at `path`. Blocks.
+fn write_dead_letter(path: &Path, dead_letter: &DeadLetter) {
+    let result = serde_json::to_vec(dead_letter)
+        .map_err(anyhow::Error::from)
+        .and_then(|mut line| {
+            line.push(b'\n');
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            file.write_all(&line)?;
+            file.sync_all()?;
+            Ok(())
+        });
+    if let Err(err) = result {
+        tracing::error!(
+            url = dead_letter.url,
+            "Failed to write webhook to {}: {err:#}",
+            path.display()
+        );
+    }
+}
+
+/// Other client errors won't go away by trying again.
+fn is_retryable(err: &reqwest::Error) -> bool {
+    err.status().is_none_or(|status| {
+        status.is_server_error()
+            || status == StatusCode::REQUEST_TIMEOUT
+            || status == StatusCode::TOO_MANY_REQUESTS
+    })
+}
+
+fn mac(secret: &[u8], timestamp: &str, body: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    mac
+}
+
+/// The `X-Cipherly-Signature` of a delivery.
+pub fn sign(secret: &[u8], timestamp: &str, body: &[u8]) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(mac(secret, timestamp, body).finalize().into_bytes())
+}
+
+/// Whether `signature` is the `X-Cipherly-Signature` of a delivery, for receivers. They should
+/// also reject old timestamps, so a delivery can't be replayed.
+pub fn verify(secret: &[u8], timestamp: &str, body: &[u8], signature: &str) -> bool {
+    BASE64_URL_SAFE_NO_PAD
+        .decode(signature)
+        .is_ok_and(|signature| {
+            mac(secret, timestamp, body)
+                .verify_slice(&signature)
+                .is_ok()
+        })
+}
+
+#[cfg(test)]
+pub mod testing {
+    use axum::{
+        Router,
+        body::Bytes,
+        http::{HeaderMap, StatusCode},
+        routing::post,
+    };
+    use tokio::{net::TcpListener, sync::mpsc};
+
+    /// Starts an HTTP server that responds to every `POST /` with `status` and passes on what it
+    /// got. Returns the URL to post to.
+    pub async fn receiver(
+        status: StatusCode,
+    ) -> (String, mpsc::UnboundedReceiver<(HeaderMap, Bytes)>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let app = Router::new().route(
+            "/",
+            post(move |headers: HeaderMap, body: Bytes| async move {
+                let _ = tx.send((headers, body));
+                status
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (format!("http://{addr}/"), rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        DeadLetter, Delivery, EventType, SIGNATURE_HEADER, TIMESTAMP_HEADER, WebhookEvent,
+        Webhooks, redact, testing::receiver, verify,
+    };
+    use crate::audit::{Action, AuditEvent, Outcome, Requester};
+    use axum::http::StatusCode;
+    use std::{fs, sync::Arc, time::Duration};
+
+    const SECRET: &[u8] = b"webhook secret";
+
+    fn event(action: Action, outcome: Outcome) -> AuditEvent {
+        let mut event = AuditEvent::now(action, Requester::default(), outcome);
+        event.time = "2026-10-19T12:00:00+00:00".into();
+        event.envelope = Some("AAAAAAAAAAAAAAAA".into());
+        event.email = Some("alice@email.com".into());
+        event
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn delivers_signed_events() {
+        let (url, mut received) = receiver(StatusCode::OK).await;
+        let webhooks = Arc::new(Webhooks::new(vec![url], SECRET));
+        // Only seals, unseals and attempts by non-recipients fire webhooks.
+        webhooks.notify(&event(Action::Unseal, Outcome::Revoked));
+        webhooks.notify(&event(Action::Unseal, Outcome::NotRecipient));
+
+        let (headers, body) = received.recv().await.unwrap();
+        let timestamp = headers[TIMESTAMP_HEADER].to_str().unwrap();
+        let signature = headers[SIGNATURE_HEADER].to_str().unwrap();
+        assert!(verify(SECRET, timestamp, &body, signature));
+        assert!(!verify(b"another secret", timestamp, &body, signature));
+        let delivered: WebhookEvent = serde_json::from_slice(&body).unwrap();
+        assert_eq!(delivered.kind, EventType::NotRecipient);
+        assert_eq!(
+            delivered.event,
+            event(Action::Unseal, Outcome::NotRecipient)
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn dead_letters_after_retries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dead-letter.jsonl");
+        let (url, mut received) = receiver(StatusCode::SERVICE_UNAVAILABLE).await;
+        let webhooks = Webhooks::new(vec![url.clone()], SECRET)
+            .with_retries(2, Duration::from_millis(1))
+            .with_dead_letter(&path);
+        let sealed = WebhookEvent {
+            kind: EventType::Sealed,
+            event: event(Action::Seal, Outcome::Sealed),
+        };
+        webhooks
+            .deliver(Delivery {
+                url: url.clone(),
+                event: sealed.clone(),
+            })
+            .await;
+
+        let mut attempts = 0;
+        while received.try_recv().is_ok() {
+            attempts += 1;
+        }
+        assert_eq!(attempts, 3);
+        let dead_letters = fs::read_to_string(&path).unwrap();
+        let dead_letters: Vec<DeadLetter> = dead_letters
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].url, redact(&url));
+        assert!(!dead_letters[0].error.contains(&url));
+        assert_eq!(dead_letters[0].event, sealed);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn dead_letters_what_the_queue_cant_hold() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dead-letter.jsonl");
+        // Accepts connections but never responds, so the one worker stays busy.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let webhooks = Arc::new(
+            Webhooks::new(vec![url.clone()], SECRET)
+                .with_queue(1, 1)
+                .with_dead_letter(&path),
+        );
+        for _ in 0..3 {
+            webhooks.notify(&event(Action::Seal, Outcome::Sealed));
+        }
+
+        // At most one delivery is in flight and one queued. The rest are written in the
+        // background.
+        let mut dead_letters = String::new();
+        for _ in 0..100 {
+            dead_letters = fs::read_to_string(&path).unwrap_or_default();
+            if !dead_letters.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let dead_letters: Vec<DeadLetter> = dead_letters
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert!(!dead_letters.is_empty());
+        for dead_letter in dead_letters {
+            assert_eq!(dead_letter.url, redact(&url));
+            assert_eq!(dead_letter.error, "the webhook queue is full");
+        }
+    }
+
+    #[test]
+    fn redacts_urls_to_their_host() {
+        assert_eq!(
+            redact("https://hooks.example.com/services/T0/B0/secret?token=secret"),
+            "hooks.example.com"
+        );
+        assert_eq!(redact("http://127.0.0.1:8080/secret"), "127.0.0.1:8080");
+        assert_eq!(redact("not a url"), "<invalid url>");
+    }
+}
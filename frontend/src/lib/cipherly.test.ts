@@ -1,10 +1,14 @@
-import { describe, expect, it, vi } from "vitest";
+import { Base64 } from "js-base64";
+import { beforeEach, describe, expect, it, vi } from "vitest";
 import {
   EncryptionScheme,
+  decodePayload,
   decodeUtf8,
+  encodePayload,
   encodeUtf8,
   exportedForTesting,
 } from "./cipherly";
+import vectors from "./testdata/payload-vectors.json";
 const {
   decodeAuthPayload,
   decodePasswordPayload,
@@ -16,6 +20,7 @@ const {
   generateSalt,
   generateKey,
   generateIv,
+  solveChallenge,
 } = exportedForTesting;
 
 describe("cipherly", () => {
@@ -94,5 +99,71 @@ describe("cipherly", () => {
     expect(decodeAuthPayload(encoded)).toEqual(expected);
   });
 
+  it("solveChallenge matches the backend's solver", async () => {
+    const solution = await solveChallenge({ token: "test", difficulty: 12 });
+    expect(solution).toBe(1963);
+  });
+
   // TODO: Unit tests for seal / unseal with a mock of the backend
 });
+
+// Shared with the Rust codec in backend/src/payload.rs.
+type Vector = {
+  name: string;
+  file: boolean;
+  payload?: Record<string, unknown>;
+  encoded: string;
+};
+const { valid, invalid } = vectors as { valid: Vector[]; invalid: Vector[] };
+
+function vectorPayload(vector: Vector): Record<string, unknown> {
+  return Object.fromEntries(
+    Object.entries(vector.payload ?? {}).map(([key, value]) => [
+      key,
+      ["s", "n", "se", "iv", "ct"].includes(key)
+        ? Base64.toUint8Array(value as string)
+        : value,
+    ]),
+  );
+}
+
+function encodeVector(vector: Vector): Uint8Array {
+  const { es, fn, ...body } = vectorPayload(vector);
+  const filename = (fn as string | null | undefined) ?? undefined;
+  const data =
+    es === EncryptionScheme.Password
+      ? encodePasswordPayload(
+          body as Parameters<typeof encodePasswordPayload>[0],
+          filename,
+        )
+      : encodeAuthPayload(
+          body as Parameters<typeof encodeAuthPayload>[0],
+          filename,
+        );
+  return new Uint8Array(
+    encodePayload(data, vector.file).flatMap((part) => [...part]),
+  );
+}
+
+describe("payload test vectors", () => {
+  beforeEach(() => {
+    vi.stubGlobal("location", {
+      protocol: "https:",
+      host: "cipherly.example",
+    });
+  });
+
+  it.each(valid)("decodes $name", (vector) => {
+    const encoded = Base64.toUint8Array(vector.encoded);
+    expect(decodePayload(encoded, vector.file)).toEqual(vectorPayload(vector));
+  });
+
+  it.each(valid)("encodes $name", (vector) => {
+    expect(encodeVector(vector)).toEqual(Base64.toUint8Array(vector.encoded));
+  });
+
+  it.each(invalid)("rejects $name", (vector) => {
+    const encoded = Base64.toUint8Array(vector.encoded);
+    expect(() => decodePayload(encoded, vector.file)).toThrow();
+  });
+});
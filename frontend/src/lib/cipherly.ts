@@ -221,9 +221,82 @@ export function decodePayload(data: Uint8Array, file: boolean): Payload {
   return Payload.parse(decodeMessagePack(payloadData));
 }
 
+function shortLinkUrl() {
+  return `${location.protocol}//${location.host}/s/`;
+}
+
+function concat(parts: Uint8Array[]): Uint8Array<ArrayBuffer> {
+  const result = new Uint8Array(
+    parts.reduce((length, part) => length + part.length, 0),
+  );
+  let offset = 0;
+  for (const part of parts) {
+    result.set(part, offset);
+    offset += part.length;
+  }
+  return result;
+}
+
+// Stores the payload on the server as a blob encrypted under a new key, which
+// only the returned `/s/<id>#<key>` link carries. With `maxViews`, the server
+// deletes the blob after that many fetches.
+export async function uploadPayload(
+  data: Uint8Array<ArrayBuffer>,
+  maxViews?: number,
+): Promise<Uint8Array<ArrayBuffer>[]> {
+  const key = await generateKey();
+  const iv = generateIv();
+  const ciphertext = await encrypt(concat(encodePayload(data, true)), key, iv);
+  const query = maxViews ? `?max_views=${maxViews}` : "";
+  const response = await fetch(`/api/blobs${query}`, {
+    method: "POST",
+    headers: {
+      "Content-Type": "application/octet-stream",
+    },
+    body: concat([iv, ciphertext]),
+  });
+  if (!response.ok) {
+    throw { code: response.status, message: response.statusText };
+  }
+  const { id } = await response.json();
+  const encodedKey = await crypto.subtle.exportKey("raw", key);
+  return [
+    encodeUtf8(
+      `${shortLinkUrl()}${id}#${encodeBase64(new Uint8Array(encodedKey))}`,
+    ),
+  ];
+}
+
+export function isShortLink(text: string): boolean {
+  return text.trim().startsWith(shortLinkUrl());
+}
+
+// Fetches and decrypts the payload of a link made by `uploadPayload`.
+export async function fetchPayload(link: string): Promise<Payload> {
+  const [id, encodedKey] = link.trim().slice(shortLinkUrl().length).split("#");
+  if (!id || !encodedKey) {
+    throw new Error("Short link is malformed");
+  }
+  const response = await fetch(`/api/blobs/${encodeURIComponent(id)}`);
+  if (!response.ok) {
+    throw { code: response.status, message: response.statusText };
+  }
+  const blob = new Uint8Array(await response.arrayBuffer());
+  const key = await crypto.subtle.importKey(
+    "raw",
+    decodeBase64(encodedKey),
+    { name: "AES-GCM" },
+    false,
+    ["decrypt"],
+  );
+  const data = await decrypt(blob.slice(12), key, blob.slice(0, 12));
+  return decodePayload(data, true);
+}
+
 type Envelope = {
   dek: CryptoKey;
   emails: string[];
+  maxUnseals?: number;
 };
 
 type SealedEnvelope = {
@@ -232,20 +305,89 @@ type SealedEnvelope = {
   data: Uint8Array<ArrayBuffer>;
 };
 
+type Challenge = {
+  token: string;
+  difficulty: number;
+};
+
+function leadingZeroBits(hash: Uint8Array): number {
+  let zeros = 0;
+  for (const byte of hash) {
+    zeros += Math.clz32(byte) - 24;
+    if (byte !== 0) {
+      break;
+    }
+  }
+  return zeros;
+}
+
+// Finds a number such that the SHA-256 of "<token>:<number>" starts with
+// `difficulty` zero bits, like `challenge::solve` in the backend.
+async function solveChallenge(challenge: Challenge): Promise<number> {
+  for (let solution = 0; ; solution++) {
+    const hash = await crypto.subtle.digest(
+      "SHA-256",
+      encodeUtf8(`${challenge.token}:${solution}`),
+    );
+    if (leadingZeroBits(new Uint8Array(hash)) >= challenge.difficulty) {
+      return solution;
+    }
+  }
+}
+
+type Violation = {
+  field: string;
+  code: string;
+  message: string;
+};
+
+// The server lists every problem with an envelope it refuses to seal.
+async function sealError(response: Response): Promise<string> {
+  if (response.status === 400) {
+    try {
+      const body: { violations: Violation[] } = await response.json();
+      return body.violations.map((violation) => violation.message).join("; ");
+    } catch {
+      // Not a list of violations.
+    }
+  }
+  return response.statusText;
+}
+
 async function seal(envelope: Envelope): Promise<SealedEnvelope> {
   const encodedDek = await crypto.subtle.exportKey("raw", envelope.dek);
-  const response = await fetch("/api/seal", {
-    method: "POST",
-    headers: {
-      "Content-Type": "application/json",
-    },
-    body: JSON.stringify({
-      dek: encodeBase64(new Uint8Array(encodedDek)),
-      emails: envelope.emails,
-    }),
+  const body = JSON.stringify({
+    dek: encodeBase64(new Uint8Array(encodedDek)),
+    emails: envelope.emails,
+    max_unseals: envelope.maxUnseals,
   });
+  const post = (headers: Record<string, string> = {}) =>
+    fetch("/api/seal", {
+      method: "POST",
+      headers: {
+        "Content-Type": "application/json",
+        ...headers,
+      },
+      body,
+    });
+  let response = await post();
+  // The server may ask anonymous senders to solve a challenge first.
+  if (response.status === 428) {
+    const challengeResponse = await fetch("/api/challenge");
+    if (!challengeResponse.ok) {
+      throw {
+        code: challengeResponse.status,
+        message: challengeResponse.statusText,
+      };
+    }
+    const challenge: Challenge = await challengeResponse.json();
+    const solution = await solveChallenge(challenge);
+    response = await post({
+      "X-Cipherly-Challenge": `${challenge.token}:${solution}`,
+    });
+  }
   if (!response.ok) {
-    throw { code: response.status, message: response.statusText };
+    throw { code: response.status, message: await sealError(response) };
   }
   const result = await response.json();
   return {
@@ -290,11 +432,12 @@ export async function authEncrypt(
   plaintext: Uint8Array<ArrayBuffer>,
   emails: string[],
   filename?: string,
+  maxUnseals?: number,
 ): Promise<Uint8Array<ArrayBuffer>> {
   const dek = await generateKey();
   const iv = generateIv();
   const ciphertext = await encrypt(plaintext, dek, iv);
-  const { kid, nonce, data } = await seal({ dek, emails });
+  const { kid, nonce, data } = await seal({ dek, emails, maxUnseals });
   return encodeAuthPayload(
     {
       k: kid,
@@ -334,4 +477,5 @@ export const exportedForTesting = {
   generateIv,
   generateKey,
   generateSalt,
+  solveChallenge,
 };
@@ -10,6 +10,33 @@ const config = {
       "$lib/*": "src/lib/*",
     },
     adapter: adapter(),
+    // Every page is prerendered, so SvelteKit puts this policy in a <meta> tag
+    // with the hashes of its inline bootstrap scripts. The server's
+    // Content-Security-Policy header adds what a <meta> tag can't carry, like
+    // frame-ancestors; see backend/src/headers.rs.
+    csp: {
+      mode: "hash",
+      directives: {
+        "default-src": ["self"],
+        "script-src": ["self", "https://accounts.google.com/gsi/client"],
+        "style-src": [
+          "self",
+          "unsafe-inline",
+          "https://accounts.google.com/gsi/style",
+        ],
+        "img-src": [
+          "self",
+          "data:",
+          "https://*.googleusercontent.com",
+          "https://i3.ytimg.com",
+        ],
+        "connect-src": ["self", "https://accounts.google.com/gsi/"],
+        "frame-src": ["https://accounts.google.com/gsi/"],
+        "object-src": ["none"],
+        "base-uri": ["none"],
+        "form-action": ["self"],
+      },
+    },
   },
   compilerOptions: {
     runes: true,